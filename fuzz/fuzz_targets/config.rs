@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use yarch8::config::{Config, Profile};
+
+// Anything the config parser accepts has to be usable without panicking later
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(config) = Config::parse(text) else {
+        return;
+    };
+    let profiles = std::iter::once(&config.defaults).chain(config.roms.values());
+    for profile in profiles {
+        if let Some(palette) = &profile.palette {
+            palette.background_rgb().unwrap();
+            palette.foreground_rgb().unwrap();
+        }
+    }
+    config.profile_for("", Profile::default(), Profile::default());
+});
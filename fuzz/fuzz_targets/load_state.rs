@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use yarch8::cpu::{Snapshot, YARCH8};
+
+// Save states come from files and RPC clients, any that load must also run without panicking
+fuzz_target!(|data: &[u8]| {
+    let Ok(snapshot) = serde_json::from_slice::<Snapshot>(data) else {
+        return;
+    };
+    let mut yarch8 = YARCH8::new(60, 600);
+    yarch8.seed(0);
+    if yarch8.load_state(&snapshot).is_ok() {
+        for _ in 0..1024 {
+            yarch8.step();
+        }
+    }
+});
@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use yarch8::patch;
+
+/* IPS and BPS patches on ROMs of any size. BPS patches get the source and patch
+    checksums they need, so the fuzzer gets past them into the copy actions. The target
+    checksum is only checked once every action ran.
+*/
+fuzz_target!(|data: &[u8]| {
+    let Some((&split, data)) = data.split_first() else {
+        return;
+    };
+    let (source, body) = data.split_at(usize::from(split).min(data.len()));
+
+    let mut ips = b"PATCH".to_vec();
+    ips.extend_from_slice(body);
+    let _ = patch::apply(source, &ips);
+
+    let mut bps = b"BPS1".to_vec();
+    bps.extend_from_slice(body);
+    bps.extend_from_slice(&patch::crc32(source).to_le_bytes());
+    bps.extend_from_slice(&0u32.to_le_bytes());
+    let patch_crc = patch::crc32(&bps);
+    bps.extend_from_slice(&patch_crc.to_le_bytes());
+    let _ = patch::apply(source, &bps);
+});
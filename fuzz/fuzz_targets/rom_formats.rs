@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use yarch8::{loader, patch};
+
+// ROM files in any format, and the same bytes as a patch on a fixed ROM
+fuzz_target!(|data: &[u8]| {
+    let _ = loader::decode(data);
+    let _ = patch::apply(&[0x00, 0xE0, 0x12, 0x00], data);
+});
@@ -0,0 +1,67 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use yarch8::cpu::jit::Jit;
+use yarch8::cpu::{Quirks, Snapshot, YARCH8};
+
+/* Arbitrary ROM, quirks and key presses, run twice through the interpreter and once
+    through the JIT. Nothing may panic and all three runs have to end in the same state.
+
+    Input: quirk bits, number of key events, the key events (bit 7 set = pressed, low
+    nibble = key, one applied every KEY_INTERVAL cycles), then the ROM itself.
+*/
+
+const CYCLES: u32 = 4096;
+const KEY_INTERVAL: u32 = 64;
+
+fn quirks(bits: u8) -> Quirks {
+    Quirks {
+        shift_vy: bits & 0x01 != 0,
+        load_store_inc_i: bits & 0x02 != 0,
+        jump_vx: bits & 0x04 != 0,
+        vf_reset: bits & 0x08 != 0,
+        wrap_sprites: bits & 0x10 != 0,
+        wait_key_release: bits & 0x20 != 0,
+    }
+}
+
+fn run(rom: &[u8], quirks: Quirks, key_events: &[u8], mut jit: Option<&mut Jit>) -> Snapshot {
+    let mut yarch8 = YARCH8::new(60, 600);
+    yarch8.load_bytes(rom);
+    yarch8.set_quirks(quirks);
+    yarch8.seed(0);
+    yarch8.start();
+
+    for chunk in 0..(CYCLES / KEY_INTERVAL) as usize {
+        if let Some(&event) = key_events.get(chunk) {
+            if event & 0x80 != 0 {
+                yarch8.key_press(event & 0xF);
+            } else {
+                yarch8.key_released(event & 0xF);
+            }
+        }
+        match &mut jit {
+            Some(jit) => jit.run(&mut yarch8, KEY_INTERVAL),
+            None => {
+                for _ in 0..KEY_INTERVAL {
+                    yarch8.step();
+                }
+            }
+        }
+    }
+    yarch8.save_state()
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&[quirk_bits, key_count], rest)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let (key_events, rom) = rest.split_at((key_count as usize).min(rest.len()));
+    let quirks = quirks(quirk_bits);
+
+    let first = run(rom, quirks, key_events, None);
+    let second = run(rom, quirks, key_events, None);
+    assert_eq!(first, second, "Interpreter runs differ");
+    let compiled = run(rom, quirks, key_events, Some(&mut Jit::new()));
+    assert_eq!(first, compiled, "JIT differs from interpreter");
+});
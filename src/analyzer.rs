@@ -0,0 +1,284 @@
+use crate::cpu::Quirks;
+use crate::platform::Platform;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/* Static analysis of a ROM image to guess which platform it was written for.
+
+    Code is found by following the control flow from 0x200, so sprite data is not
+    mistaken for instructions. Opcodes that only exist on SUPER-CHIP or XO-CHIP then
+    give the platform away. A plain scan of the whole image is only used as weak evidence
+    when the control flow can not be fully followed (computed jumps).
+*/
+
+const START_ADDRESS: usize = 0x200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    Low,
+    Medium,
+    High,
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            Confidence::Low => "low",
+            Confidence::Medium => "medium",
+            Confidence::High => "high",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Analysis {
+    pub platform: Platform,
+    pub confidence: Confidence,
+    pub quirks: Quirks,
+    pub code_addresses: BTreeSet<u16>, // addresses of instructions reached from entry point
+    pub schip_opcodes: Vec<(u16, u16)>, // (address, opcode) of SUPER-CHIP only instructions
+    pub xochip_opcodes: Vec<(u16, u16)>, // same for XO-CHIP only instructions
+    pub machine_code_calls: Vec<(u16, u16)>, // 0NNN calls into COSMAC VIP machine code
+    pub computed_jumps: usize,         // BNNN, control flow is lost after those
+    pub self_modifying: bool,          // code writes into itself with FX33/FX55
+    pub shifts_vy: bool,               // 8XY6/8XYE with X != Y, result depends on shift quirk
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Extension {
+    None,
+    SuperChip,
+    XoChip,
+    MachineCode,
+}
+
+// Which platform extension an opcode belongs to
+fn classify(op: u16) -> Extension {
+    let x = (op & 0x0F00) >> 8;
+    match op & 0xF000 {
+        0x0000 => match op {
+            // 0000 is more likely zero padding than a call to address 0
+            0x0000 | 0x00E0 | 0x00EE => Extension::None,
+            0x00FB..=0x00FF => Extension::SuperChip,
+            _ if op & 0xFFF0 == 0x00C0 => Extension::SuperChip,
+            _ if op & 0xFFF0 == 0x00D0 => Extension::XoChip,
+            _ => Extension::MachineCode,
+        },
+        0x5000 => match op & 0x000F {
+            0x2 | 0x3 => Extension::XoChip,
+            _ => Extension::None,
+        },
+        0xD000 if op & 0x000F == 0 => Extension::SuperChip,
+        0xF000 => match op & 0x00FF {
+            0x00 if x == 0 => Extension::XoChip,
+            0x01 | 0x3A => Extension::XoChip,
+            0x02 if x == 0 => Extension::XoChip,
+            0x30 | 0x75 | 0x85 => Extension::SuperChip,
+            _ => Extension::None,
+        },
+        _ => Extension::None,
+    }
+}
+
+fn read_op(rom: &[u8], address: usize) -> Option<u16> {
+    let offset = address.checked_sub(START_ADDRESS)?;
+    let bytes = rom.get(offset..offset + 2)?;
+    Some(((bytes[0] as u16) << 8) | bytes[1] as u16)
+}
+
+// Follow control flow from the entry point, returns instruction addresses found
+fn trace(rom: &[u8]) -> (BTreeSet<u16>, usize) {
+    let mut code = BTreeSet::new();
+    let mut computed_jumps = 0;
+    let mut to_visit = vec![START_ADDRESS];
+
+    while let Some(address) = to_visit.pop() {
+        if code.contains(&(address as u16)) {
+            continue;
+        }
+        let op = match read_op(rom, address) {
+            Some(op) => op,
+            None => continue,
+        };
+        code.insert(address as u16);
+        let nnn = (op & 0x0FFF) as usize;
+
+        // Skips jump over the next instruction, which is 4 bytes long if it is F000 NNNN
+        let skip_target = if read_op(rom, address + 2) == Some(0xF000) {
+            address + 6
+        } else {
+            address + 4
+        };
+
+        match op & 0xF000 {
+            0x0000 if op == 0x00EE || op == 0x00FD => {}
+            0x1000 => to_visit.push(nnn),
+            0x2000 => {
+                to_visit.push(nnn);
+                to_visit.push(address + 2);
+            }
+            0x3000 | 0x4000 | 0x9000 => {
+                to_visit.push(address + 2);
+                to_visit.push(skip_target);
+            }
+            0x5000 if op & 0x000F == 0 => {
+                to_visit.push(address + 2);
+                to_visit.push(skip_target);
+            }
+            0xB000 => computed_jumps += 1,
+            0xE000 if op & 0x00FF == 0x9E || op & 0x00FF == 0xA1 => {
+                to_visit.push(address + 2);
+                to_visit.push(skip_target);
+            }
+            0xF000 if op == 0xF000 => to_visit.push(address + 4),
+            _ => to_visit.push(address + 2),
+        }
+    }
+    (code, computed_jumps)
+}
+
+pub fn analyze(rom: &[u8]) -> Analysis {
+    let (code, computed_jumps) = trace(rom);
+
+    let mut schip_opcodes = Vec::new();
+    let mut xochip_opcodes = Vec::new();
+    let mut machine_code_calls = Vec::new();
+    let mut shifts_vy = false;
+    let mut index_targets = Vec::new();
+    let mut writes_memory = false;
+
+    for &address in code.iter() {
+        let op = read_op(rom, address as usize).unwrap();
+        match classify(op) {
+            Extension::SuperChip => schip_opcodes.push((address, op)),
+            Extension::XoChip => xochip_opcodes.push((address, op)),
+            Extension::MachineCode => machine_code_calls.push((address, op)),
+            Extension::None => {}
+        }
+        let (x, y) = ((op & 0x0F00) >> 8, (op & 0x00F0) >> 4);
+        match op & 0xF00F {
+            0x8006 | 0x800E if x != y => shifts_vy = true,
+            _ => {}
+        }
+        match op & 0xF0FF {
+            0xF033 | 0xF055 => writes_memory = true,
+            _ => {}
+        }
+        if op & 0xF000 == 0xA000 {
+            index_targets.push(op & 0x0FFF);
+        }
+    }
+
+    // I pointed into code plus a memory write is a good hint code patches itself
+    let self_modifying = writes_memory
+        && index_targets
+            .iter()
+            .any(|target| code.contains(target) || code.contains(&(target & !1)));
+
+    let (platform, confidence) = if !xochip_opcodes.is_empty() {
+        (Platform::XoChip, Confidence::High)
+    } else if !schip_opcodes.is_empty() {
+        (Platform::SuperChip, Confidence::High)
+    } else if !machine_code_calls.is_empty() {
+        (Platform::HybridVip, Confidence::High)
+    } else if rom.len() > 0x1000 - START_ADDRESS {
+        // Only XO-CHIP can address past the 4K of RAM
+        (Platform::XoChip, Confidence::Medium)
+    } else if computed_jumps > 0 {
+        // Some code was not traced, scanning the whole image may hit data so trust it less
+        match scan(rom) {
+            Extension::XoChip => (Platform::XoChip, Confidence::Low),
+            Extension::SuperChip => (Platform::SuperChip, Confidence::Low),
+            _ => (Platform::ModernChip8, Confidence::Low),
+        }
+    } else {
+        (Platform::ModernChip8, Confidence::Medium)
+    };
+
+    let mut quirks = platform.quirks();
+    // VIP era code that shifts VY into VX needs the original shift behaviour
+    if shifts_vy && platform != Platform::SuperChip {
+        quirks.shift_vy = true;
+    }
+
+    Analysis {
+        platform,
+        confidence,
+        quirks,
+        code_addresses: code,
+        schip_opcodes,
+        xochip_opcodes,
+        machine_code_calls,
+        computed_jumps,
+        self_modifying,
+        shifts_vy,
+    }
+}
+
+// Look at every aligned word, data included, returns strongest extension found
+fn scan(rom: &[u8]) -> Extension {
+    let mut found = Extension::None;
+    for op in rom
+        .chunks_exact(2)
+        .map(|bytes| ((bytes[0] as u16) << 8) | bytes[1] as u16)
+    {
+        match classify(op) {
+            Extension::XoChip => return Extension::XoChip,
+            Extension::SuperChip => found = Extension::SuperChip,
+            _ => {}
+        }
+    }
+    found
+}
+
+impl Analysis {
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        report.push_str(&format!(
+            "Platform: {} ({} confidence)\n",
+            self.platform.name(),
+            self.confidence
+        ));
+        report.push_str(&format!(
+            "Reachable instructions: {}\n",
+            self.code_addresses.len()
+        ));
+        list_opcodes(&mut report, "SUPER-CHIP instructions", &self.schip_opcodes);
+        list_opcodes(&mut report, "XO-CHIP instructions", &self.xochip_opcodes);
+        list_opcodes(&mut report, "Machine code calls", &self.machine_code_calls);
+        if self.computed_jumps > 0 {
+            report.push_str(&format!(
+                "Computed jumps: {} (code after them is not traced)\n",
+                self.computed_jumps
+            ));
+        }
+        if self.self_modifying {
+            report.push_str("Self-modifying code: likely\n");
+        }
+        if self.shifts_vy {
+            report.push_str("Shifts with VY: yes, depends on shift quirk\n");
+        }
+        report.push_str(&format!("Quirks: {:?}\n", self.quirks));
+        report
+    }
+}
+
+fn list_opcodes(report: &mut String, label: &str, opcodes: &[(u16, u16)]) {
+    if opcodes.is_empty() {
+        return;
+    }
+    let listed: Vec<String> = opcodes
+        .iter()
+        .take(8)
+        .map(|(address, op)| format!("{:04X}@{:03X}", op, address))
+        .collect();
+    let more = if opcodes.len() > 8 { ", ..." } else { "" };
+    report.push_str(&format!(
+        "{}: {} ({}{})\n",
+        label,
+        opcodes.len(),
+        listed.join(", "),
+        more
+    ));
+}
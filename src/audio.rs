@@ -0,0 +1,68 @@
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+use yarch8::cpu::megachip::Sound;
+
+/* Plays MEGA-CHIP samples through an SDL queue.
+
+    The machine only keeps the sample that is playing, so once per frame we compare it
+    with the one we queued last and start over when its serial changed. Looping samples
+    are queued again before the queue runs dry.
+*/
+
+pub struct SamplePlayer {
+    audio: AudioSubsystem,
+    queue: Option<AudioQueue<u8>>,
+    serial: u64,
+}
+
+impl SamplePlayer {
+    pub fn new(audio: AudioSubsystem) -> Self {
+        SamplePlayer {
+            audio,
+            queue: None,
+            serial: 0,
+        }
+    }
+
+    pub fn update(&mut self, sound: Option<&Sound>) {
+        let sound = match sound {
+            Some(sound) if !sound.data.is_empty() && sound.rate > 0 => sound,
+            _ => {
+                self.queue = None;
+                return;
+            }
+        };
+
+        if sound.serial != self.serial {
+            self.serial = sound.serial;
+            self.queue = self.open(sound);
+            return;
+        }
+
+        // A frame or less left, keep a looping sample going
+        if let Some(queue) = &self.queue {
+            if sound.looping && (queue.size() as usize) < usize::from(sound.rate / 60) + 1 {
+                let _ = queue.queue_audio(&sound.data);
+            }
+        }
+    }
+
+    // Missing audio devices just mean silence
+    fn open(&self, sound: &Sound) -> Option<AudioQueue<u8>> {
+        let desired = AudioSpecDesired {
+            freq: Some(i32::from(sound.rate)),
+            channels: Some(1),
+            samples: None,
+        };
+        let queue: AudioQueue<u8> = match self.audio.open_queue(None, &desired) {
+            Ok(queue) => queue,
+            Err(e) => {
+                println!("Could not open audio: {}", e);
+                return None;
+            }
+        };
+        queue.queue_audio(&sound.data).ok()?;
+        queue.resume();
+        Some(queue)
+    }
+}
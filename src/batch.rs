@@ -0,0 +1,87 @@
+use crate::cpu::{Snapshot, YARCH8};
+use crate::env::{Env, NoReward, Observation};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/* Run many independent machines across threads, e.g. for test matrices or rollouts.
+    Each job gets its own ROM, seed and key inputs, results come back in job order. A job
+    that can not run (e.g. a start state that does not load) fails on its own, the others
+    still run.
+*/
+
+// Machines are handed to worker threads, make sure that stays possible
+const _: fn() = || {
+    fn assert_send_clone<T: Send + Clone>() {}
+    assert_send_clone::<YARCH8>();
+};
+
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub rom: Arc<Vec<u8>>, // shared by jobs running the same ROM
+    pub seed: u64,
+    pub inputs: Vec<u16>, // keys held for each frame, bit N set = key N held
+    pub start: Option<Snapshot>, // resume from this state instead of power-on
+}
+
+#[derive(Clone, Debug)]
+pub struct Outcome {
+    pub framebuffer: Observation,
+    pub state: Snapshot,
+    pub frames: usize,
+}
+
+// Use threads = 0 for one thread per CPU core
+pub fn run_batch(jobs: &[Job], threads: usize) -> Vec<Result<Outcome, String>> {
+    let threads = if threads == 0 {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        threads
+    };
+
+    let next_job = AtomicUsize::new(0);
+    let outcomes: Mutex<Vec<Option<Result<Outcome, String>>>> = Mutex::new(vec![None; jobs.len()]);
+
+    thread::scope(|scope| {
+        for _ in 0..threads.min(jobs.len()) {
+            scope.spawn(|| loop {
+                // Workers pull jobs until none are left, so slow jobs do not hold others back
+                let idx = next_job.fetch_add(1, Ordering::Relaxed);
+                let job = match jobs.get(idx) {
+                    Some(job) => job,
+                    None => break,
+                };
+                let outcome = run_job(job);
+                outcomes.lock().unwrap()[idx] = Some(outcome);
+            });
+        }
+    });
+
+    outcomes
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|outcome| outcome.expect("Batch job did not finish!"))
+        .collect()
+}
+
+pub fn run_job(job: &Job) -> Result<Outcome, String> {
+    let mut env = Env::new(Box::new(NoReward), 1);
+    env.reset(&job.rom, job.seed);
+    if let Some(start) = &job.start {
+        env.machine_mut()
+            .load_state(start)
+            .map_err(|e| format!("Invalid start state: {}", e))?;
+    }
+
+    for &mask in job.inputs.iter() {
+        let keys: Vec<u8> = (0..16u8).filter(|key| mask & (1 << key) != 0).collect();
+        env.step(&keys);
+    }
+
+    Ok(Outcome {
+        framebuffer: env.observation(),
+        state: env.machine().save_state(),
+        frames: job.inputs.len(),
+    })
+}
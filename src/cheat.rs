@@ -0,0 +1,191 @@
+use crate::cpu::YARCH8;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/* Cheats: finding interesting bytes in RAM and forcing their values.
+
+    A Search starts with every address as a candidate and a copy of RAM. Each filter()
+    compares RAM against that copy (or a fixed value) and drops the addresses that do
+    not match, e.g. lose a life, filter Decreased, repeat until the lives counter is
+    the only candidate left.
+
+    Cheat files hold one code per line, addresses and values in hex:
+
+        # Infinite lives
+        2F0:03      frozen, written back every frame
+        0x3A2=12    patched once after the ROM is loaded
+
+    Anything after # is a comment. By default the file for a ROM sits next to it with
+    the extension .cht.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Decreased,
+    Increased,
+}
+
+impl Comparison {
+    // Parse names used by the RPC interface, Equal takes its value separately
+    pub fn from_name(name: &str, value: Option<u8>) -> Option<Comparison> {
+        match name {
+            "equal" => value.map(Comparison::Equal),
+            "changed" => Some(Comparison::Changed),
+            "unchanged" => Some(Comparison::Unchanged),
+            "decreased" => Some(Comparison::Decreased),
+            "increased" => Some(Comparison::Increased),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, previous: u8, current: u8) -> bool {
+        match *self {
+            Comparison::Equal(value) => current == value,
+            Comparison::Changed => current != previous,
+            Comparison::Unchanged => current == previous,
+            Comparison::Decreased => current < previous,
+            Comparison::Increased => current > previous,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Search {
+    candidates: Vec<u16>,
+    previous: Vec<u8>, // RAM at the last filter
+}
+
+impl Search {
+    pub fn new(yarch8: &YARCH8) -> Self {
+        let ram = yarch8.get_ram();
+        Search {
+            candidates: (0..ram.len() as u16).collect(),
+            previous: ram.to_vec(),
+        }
+    }
+
+    // Keep the candidates matching the comparison, returns how many are left
+    pub fn filter(&mut self, yarch8: &YARCH8, comparison: Comparison) -> usize {
+        let ram = yarch8.get_ram();
+        let previous = &self.previous;
+        self.candidates.retain(|&address| {
+            comparison.matches(previous[address as usize], ram[address as usize])
+        });
+        self.previous = ram.to_vec();
+        self.candidates.len()
+    }
+
+    pub fn get_candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Cheats {
+    frozen: BTreeMap<u16, u8>,
+    patches: Vec<(u16, u8)>,
+}
+
+impl Cheats {
+    pub fn parse(text: &str) -> Result<Cheats, String> {
+        let mut cheats = Cheats::default();
+        for (idx, line) in text.lines().enumerate() {
+            let code = line.split('#').next().unwrap().trim();
+            if code.is_empty() {
+                continue;
+            }
+            let error = |message: &str| format!("Line {}: {} in \"{}\"", idx + 1, message, code);
+            let (separator, (address, value)) = match (code.split_once(':'), code.split_once('=')) {
+                (Some(parts), None) => (':', parts),
+                (None, Some(parts)) => ('=', parts),
+                _ => return Err(error("expected ADDRESS:VALUE or ADDRESS=VALUE")),
+            };
+            let address = parse_hex(address)
+                .filter(|&address| address < 0x1000)
+                .ok_or_else(|| error("address must be hex from 0 to FFF"))?;
+            // Values may be followed by a description
+            let value = value.split_whitespace().next().unwrap_or("");
+            let value = parse_hex(value)
+                .and_then(|value| u8::try_from(value).ok())
+                .ok_or_else(|| error("value must be hex from 0 to FF"))?;
+            if separator == ':' {
+                cheats.frozen.insert(address, value);
+            } else {
+                cheats.patches.push((address, value));
+            }
+        }
+        Ok(cheats)
+    }
+
+    pub fn load(path: &str) -> Result<Cheats, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Cheats::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // For the file next to the ROM, no file means no cheats
+    pub fn load_if_present(path: &str) -> Result<Cheats, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Cheats::parse(&text).map_err(|e| format!("{}: {}", path, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Cheats::default()),
+            Err(e) => Err(format!("{}: {}", path, e)),
+        }
+    }
+
+    // game.ch8 -> game.cht
+    pub fn path_for(rom_path: &str) -> String {
+        Path::new(rom_path)
+            .with_extension("cht")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub fn freeze(&mut self, address: u16, value: u8) {
+        self.frozen.insert(address & 0xFFF, value);
+    }
+
+    pub fn unfreeze(&mut self, address: u16) -> bool {
+        self.frozen.remove(&address).is_some()
+    }
+
+    pub fn get_frozen(&self) -> &BTreeMap<u16, u8> {
+        &self.frozen
+    }
+
+    pub fn get_patches(&self) -> &[(u16, u8)] {
+        &self.patches
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frozen.is_empty() && self.patches.is_empty()
+    }
+
+    // Call once after loading the ROM
+    pub fn apply_patches(&self, yarch8: &mut YARCH8) {
+        for &(address, value) in self.patches.iter() {
+            yarch8.write_ram(address as usize, value);
+        }
+    }
+
+    // Call every frame, only bytes the game changed get written back
+    pub fn apply(&self, yarch8: &mut YARCH8) {
+        for (&address, &value) in self.frozen.iter() {
+            if yarch8.get_ram()[address as usize] != value {
+                yarch8.write_ram(address as usize, value);
+            }
+        }
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    let text = text.trim();
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
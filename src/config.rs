@@ -0,0 +1,169 @@
+use crate::cpu::timing::Timing;
+use crate::cpu::variant::Variant;
+use crate::cpu::Quirks;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+/* Config file layout (TOML):
+
+    [defaults]
+    cpu_freq = 500
+
+    [roms.<sha1 of ROM file>]
+    cpu_freq = 1000
+    quirks = { shift_vy = true, vf_reset = true }
+    timing = "cosmac_vip"
+    variant = "chip8x"
+    palette = { background = "#000000", foreground = "#33ff66" }
+    keymap = { "5" = "Up", "8" = "Down" }
+*/
+
+// Settings that can be given globally or per ROM, unset ones fall back to next level
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub scale: Option<u32>,
+    pub fps: Option<u32>,
+    pub cpu_freq: Option<u32>,
+    pub timer_freq: Option<u32>,
+    pub quirks: Option<Quirks>,
+    pub timing: Option<Timing>,   // "flat" or "cosmac_vip"
+    pub variant: Option<Variant>, // "chip8", "chip8x", "chip8e", "hires", "chip10" or "megachip"
+    pub palette: Option<Palette>,
+    pub keymap: Option<HashMap<String, String>>, // CHIP-8 key in hex -> SDL key name
+}
+
+impl Profile {
+    fn validate(&self) -> Result<(), String> {
+        let non_zero = [
+            ("scale", self.scale),
+            ("fps", self.fps),
+            ("cpu_freq", self.cpu_freq),
+            ("timer_freq", self.timer_freq),
+        ];
+        for (name, value) in non_zero {
+            if value == Some(0) {
+                return Err(format!("{} must be greater than 0", name));
+            }
+        }
+        if let Some(palette) = &self.palette {
+            palette.background_rgb()?;
+            palette.foreground_rgb()?;
+        }
+        // Key names belong to the window, it checks them when it builds its keymap
+        for (key, key_name) in self.keymap.iter().flatten() {
+            parse_keymap_key(key)?;
+            if key_name.trim().is_empty() {
+                return Err(format!("Empty key name for CHIP-8 key {} in keymap", key));
+            }
+        }
+        Ok(())
+    }
+
+    // Settings of other profile win over ours
+    pub fn overlay(self, other: Profile) -> Profile {
+        Profile {
+            scale: other.scale.or(self.scale),
+            fps: other.fps.or(self.fps),
+            cpu_freq: other.cpu_freq.or(self.cpu_freq),
+            timer_freq: other.timer_freq.or(self.timer_freq),
+            quirks: other.quirks.or(self.quirks),
+            timing: other.timing.or(self.timing),
+            variant: other.variant.or(self.variant),
+            palette: other.palette.or(self.palette),
+            keymap: other.keymap.or(self.keymap),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Palette {
+    pub background: String, // "#rrggbb"
+    pub foreground: String,
+}
+
+impl Palette {
+    pub fn background_rgb(&self) -> Result<(u8, u8, u8), String> {
+        parse_color(&self.background)
+    }
+
+    pub fn foreground_rgb(&self) -> Result<(u8, u8, u8), String> {
+        parse_color(&self.foreground)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub defaults: Profile,
+    pub roms: HashMap<String, Profile>, // keyed by SHA-1 of ROM in lowercase hex
+}
+
+impl Config {
+    // Missing config file is not an error, everything is just left to defaults. A file
+    // that can not be read or parsed is.
+    pub fn load(config_path: &str) -> Result<Self, String> {
+        match fs::read_to_string(config_path) {
+            Ok(text) => Config::parse(&text).map_err(|e| format!("{}: {}", config_path, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("{}: {}", config_path, e)),
+        }
+    }
+
+    // Values that would only blow up later (zero frequencies, bad colours) are rejected here
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        config
+            .defaults
+            .validate()
+            .map_err(|e| format!("[defaults]: {}", e))?;
+        for (rom_hash, profile) in config.roms.iter() {
+            profile
+                .validate()
+                .map_err(|e| format!("[roms.{}]: {}", rom_hash, e))?;
+        }
+        Ok(config)
+    }
+
+    // Database settings sit between config defaults and the ROM's own section, guesses
+    // below the defaults
+    pub fn profile_for(&self, rom_hash: &str, detected: Profile, guessed: Profile) -> Profile {
+        let rom_profile = self.roms.get(rom_hash).cloned().unwrap_or_default();
+        guessed
+            .overlay(self.defaults.clone())
+            .overlay(detected)
+            .overlay(rom_profile)
+    }
+}
+
+// Keymap entries are keyed by CHIP-8 key, one hex digit
+pub fn parse_keymap_key(key: &str) -> Result<u8, String> {
+    let mut digits = key.chars();
+    match (
+        digits.next().and_then(|digit| digit.to_digit(16)),
+        digits.next(),
+    ) {
+        (Some(index), None) => Ok(index as u8),
+        _ => Err(format!(
+            "Invalid CHIP-8 key {} in keymap, expected 0 to F",
+            key
+        )),
+    }
+}
+
+fn parse_color(color: &str) -> Result<(u8, u8, u8), String> {
+    try_parse_color(color).ok_or_else(|| format!("Invalid colour {}, expected #rrggbb", color))
+}
+
+fn try_parse_color(color: &str) -> Option<(u8, u8, u8)> {
+    let hex = color.trim_start_matches('#');
+    // from_str_radix alone would take a sign, so check the digits first
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let rgb = u32::from_str_radix(hex, 16).ok()?;
+    Some(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+}
@@ -0,0 +1,281 @@
+use crate::cpu::variant::Variant;
+use crate::cpu::YARCH8;
+use crate::instruction::Instruction;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+
+/* Code coverage, which bytes of RAM a run actually used.
+
+    Every address gets flags for being fetched as an instruction, read as data (DXYN
+    sprite rows, FX65) and written (FX33, FX55). record() looks at the instruction
+    about to run, so call it right before each YARCH8::step(). Sprite rows that are
+    clipped off screen are not read and do not count, MEGA-CHIP sprites are read whole.
+    Only the first 4K are tracked.
+
+    Reports cover the loaded ROM image, one line per instruction or data byte with
+    disassembly in the ROM's variant, as plain text or as an HTML page with the same content colour coded.
+*/
+
+pub const FETCHED: u8 = 0x1;
+pub const READ: u8 = 0x2;
+pub const WRITTEN: u8 = 0x4;
+
+const START_ADDRESS: usize = 0x200;
+
+#[derive(Clone)]
+pub struct Coverage {
+    marks: Vec<u8>, // FETCHED | READ | WRITTEN per address
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// One line of a report
+struct Line {
+    address: usize,
+    bytes: Vec<u8>,
+    marks: u8,
+    text: String, // disassembly, or the bit pattern for data
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage {
+            marks: vec![0; 4096],
+        }
+    }
+
+    // Mark what the instruction at PC is about to fetch, read and write
+    pub fn record(&mut self, yarch8: &YARCH8) {
+        let ram = yarch8.get_ram();
+        let pc = yarch8.get_pc() as usize;
+        let bytes = match ram.get(pc..pc + 2) {
+            Some(bytes) => bytes,
+            None => return,
+        };
+
+        let i = yarch8.get_i() as usize;
+        let opcode = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let instruction = yarch8.get_variant().decode(opcode);
+        // 01NN NNNN carries the rest of I in the next 2 bytes
+        let length = match instruction {
+            Instruction::LongIndex { .. } => 4,
+            _ => 2,
+        };
+        self.mark(pc, length, FETCHED);
+
+        match instruction {
+            Instruction::Draw { .. } if yarch8.get_mega_sprite_size().is_some() => {
+                let (width, height) = yarch8.get_mega_sprite_size().unwrap();
+                self.mark(i, width * height, READ);
+            }
+            Instruction::Draw { y, n, .. } => {
+                let (_, height) = yarch8.get_resolution();
+                let top = yarch8.get_v_reg(y as usize) as usize % height;
+                let rows = if yarch8.get_quirks().wrap_sprites {
+                    n as usize
+                } else {
+                    (n as usize).min(height - top)
+                };
+                self.mark(i, rows, READ);
+            }
+            Instruction::Load { x } => self.mark(i, x as usize + 1, READ),
+            Instruction::Store { x } => self.mark(i, x as usize + 1, WRITTEN),
+            Instruction::Bcd { .. } => self.mark(i, 3, WRITTEN),
+            // CHIP-8E register ranges, empty when they run backwards
+            Instruction::LoadRange { x, y } => {
+                self.mark(i, (y + 1).saturating_sub(x) as usize, READ)
+            }
+            Instruction::StoreRange { x, y } => {
+                self.mark(i, (y + 1).saturating_sub(x) as usize, WRITTEN)
+            }
+            _ => {}
+        }
+    }
+
+    // Anything past the first 4K is not tracked
+    fn mark(&mut self, address: usize, len: usize, flag: u8) {
+        let end = address.saturating_add(len).min(self.marks.len());
+        if let Some(marks) = self.marks.get_mut(address..end) {
+            marks.iter_mut().for_each(|marks| *marks |= flag);
+        }
+    }
+
+    pub fn get(&self, address: usize) -> u8 {
+        self.marks.get(address).copied().unwrap_or(0)
+    }
+
+    pub fn clear(&mut self) {
+        self.marks.fill(0);
+    }
+
+    // Totals over the ROM image, plus writes outside it
+    pub fn summary(&self, rom: &[u8]) -> String {
+        let image = &self.marks[START_ADDRESS..self.image_end(rom)];
+        let count = |flag: u8| image.iter().filter(|&&marks| marks & flag != 0).count();
+        let percent = |bytes: usize| 100.0 * bytes as f64 / image.len().max(1) as f64;
+        let executed = count(FETCHED);
+        let read = count(READ);
+        let written = count(WRITTEN);
+        let untouched = image.iter().filter(|&&marks| marks == 0).count();
+        let written_outside = self
+            .marks
+            .iter()
+            .enumerate()
+            .filter(|&(address, &marks)| {
+                marks & WRITTEN != 0 && !(START_ADDRESS..self.image_end(rom)).contains(&address)
+            })
+            .count();
+        format!(
+            "Image {:#05X}-{:#05X} ({} bytes): {} executed ({:.1}%), {} read as data ({:.1}%), \
+            {} written, {} untouched ({:.1}%), {} bytes written outside the image",
+            START_ADDRESS,
+            self.image_end(rom) - 1,
+            image.len(),
+            executed,
+            percent(executed),
+            read,
+            percent(read),
+            written,
+            untouched,
+            percent(untouched),
+            written_outside
+        )
+    }
+
+    pub fn text_report(&self, rom: &[u8], variant: Variant) -> String {
+        let mut report = self.summary(rom);
+        report.push_str("\nFlags: X executed, R read as data, W written\n\n");
+        for line in self.lines(rom, variant) {
+            let bytes: Vec<String> = line
+                .bytes
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            let _ = writeln!(
+                report,
+                "{:#05X}  {}  {:<4}  {}",
+                line.address,
+                flags(line.marks),
+                bytes.join(""),
+                line.text
+            );
+        }
+        report
+    }
+
+    pub fn html_report(&self, rom: &[u8], variant: Variant) -> String {
+        let mut report = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+            <title>YARCH8 coverage</title>\n<style>\n\
+            body { font-family: monospace; }\n\
+            td { padding: 0 1em 0 0; white-space: pre; }\n\
+            .executed { background: #c8f0c8; }\n\
+            .data { background: #c8d8f8; }\n\
+            .written { color: #b00000; }\n\
+            .untouched { background: #f8d0d0; }\n\
+            </style>\n</head>\n<body>\n",
+        );
+        let _ = writeln!(report, "<p>{}</p>", self.summary(rom));
+        report.push_str("<p>Flags: X executed, R read as data, W written</p>\n<table>\n");
+        for line in self.lines(rom, variant) {
+            let mut classes = Vec::new();
+            if line.marks & FETCHED != 0 {
+                classes.push("executed");
+            } else if line.marks & READ != 0 {
+                classes.push("data");
+            } else if line.marks & WRITTEN == 0 {
+                classes.push("untouched");
+            }
+            if line.marks & WRITTEN != 0 {
+                classes.push("written");
+            }
+            let bytes: Vec<String> = line
+                .bytes
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            let _ = writeln!(
+                report,
+                "<tr class=\"{}\"><td>{:#05X}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                classes.join(" "),
+                line.address,
+                flags(line.marks),
+                bytes.join(""),
+                line.text
+            );
+        }
+        report.push_str("</table>\n</body>\n</html>\n");
+        report
+    }
+
+    // HTML for paths ending in .html or .htm, text otherwise
+    pub fn write_report(&self, rom: &[u8], variant: Variant, path: &str) -> io::Result<()> {
+        let lower = path.to_lowercase();
+        let report = if lower.ends_with(".html") || lower.ends_with(".htm") {
+            self.html_report(rom, variant)
+        } else {
+            self.text_report(rom, variant)
+        };
+        fs::write(path, report)
+    }
+
+    fn image_end(&self, rom: &[u8]) -> usize {
+        (START_ADDRESS + rom.len()).min(self.marks.len())
+    }
+
+    /* Split the image into lines. Executed addresses and bytes nothing touched are shown
+        as instructions, so unreached routines can still be read. Data is shown one byte
+        at a time with its bit pattern, the way it would be drawn as a sprite row.
+    */
+    fn lines(&self, rom: &[u8], variant: Variant) -> Vec<Line> {
+        let end = self.image_end(rom);
+        let byte = |address: usize| rom[address - START_ADDRESS];
+        let mut lines = Vec::new();
+        let mut address = START_ADDRESS;
+        while address < end {
+            let marks = self.marks[address];
+            let is_code = marks & FETCHED != 0
+                || (marks == 0 && address + 1 < end && self.marks[address + 1] == 0);
+            if is_code && address + 1 < end {
+                let opcode = u16::from_be_bytes([byte(address), byte(address + 1)]);
+                lines.push(Line {
+                    address,
+                    bytes: vec![byte(address), byte(address + 1)],
+                    marks: marks | self.marks[address + 1],
+                    text: variant.decode(opcode).to_string(),
+                });
+                address += 2;
+            } else {
+                let pattern: String = (0..8)
+                    .map(|bit| {
+                        if byte(address) & (0x80 >> bit) != 0 {
+                            '#'
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect();
+                lines.push(Line {
+                    address,
+                    bytes: vec![byte(address)],
+                    marks,
+                    text: pattern,
+                });
+                address += 1;
+            }
+        }
+        lines
+    }
+}
+
+fn flags(marks: u8) -> String {
+    [(FETCHED, 'X'), (READ, 'R'), (WRITTEN, 'W')]
+        .iter()
+        .map(|&(flag, letter)| if marks & flag != 0 { letter } else { '-' })
+        .collect()
+}
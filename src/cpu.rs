@@ -1,23 +1,225 @@
-use rand;
-use std::fs::File;
-use std::io::prelude::*;
-use std::time::{Duration, Instant};
+use crate::database::{self, RomInfo};
+use crate::instruction::Instruction;
+use crate::{loader, patch};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::time::Duration;
 
+pub mod jit;
+pub mod megachip;
+pub mod timing;
+pub mod variant;
+pub mod vip;
+
+use megachip::{Blend, MegaChip};
+use timing::Timing;
+use variant::Variant;
+use vip::{Vip, VipState};
+
+// Queued key events a machine holds at most, more than any frame of input needs
+pub const MAX_KEY_EVENTS: usize = 256;
+
+// Behaviours that differ between CHIP-8 interpreters. All off matches no platform in
+// particular, Platform::quirks has the sets each of them uses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quirks {
+    pub shift_vy: bool,         // 8XY6/8XYE shift VY into VX (COSMAC VIP)
+    pub load_store_inc_i: bool, // FX55/FX65 leave I pointing after last register
+    pub jump_vx: bool,          // BNNN jumps to XNN + VX (SUPER-CHIP)
+    pub vf_reset: bool,         // 8XY1/8XY2/8XY3 reset VF to 0
+    pub wrap_sprites: bool,     // DXYN wraps pixels around the edges instead of clipping
+    pub wait_key_release: bool, // FX0A waits for a key to be pressed and released (COSMAC VIP)
+}
+
+// Where FX0A is in waiting for a key, driven by key presses in between its executions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyWait {
+    #[default]
+    None, // not in FX0A
+    Waiting,
+    Pressed(u8), // pressed while waiting, FX0A finishes once it is released
+}
+
+/* Key going down or up once the clock reaches cycle, instructions executed so far.
+    Events are applied right before the next instruction, so a run given the same events
+    behaves the same however fast the host feeds them.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyEvent {
+    pub cycle: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+// Why the machine stopped, programs doing something undefined halt it instead of crashing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Fault {
+    UnknownOpcode(u16),
+    MachineCode(u16), // 0NNN, would run COSMAC VIP machine code at NNN
+    StackOverflow,
+    StackUnderflow,
+    MemoryOutOfRange(u32), // I based access runs past end of RAM, holds I
+    PcOutOfRange(u16),
+    InvalidKey(u8),
+    Halted, // 00ED on CHIP-8E, the program is done
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::UnknownOpcode(op) => write!(f, "unknown opcode {:04X}", op),
+            Fault::MachineCode(nnn) => write!(f, "machine code call to {:#05X}", nnn),
+            Fault::StackOverflow => write!(f, "stack overflow"),
+            Fault::StackUnderflow => write!(f, "return with empty stack"),
+            Fault::MemoryOutOfRange(i) => write!(f, "memory access past end of RAM, I = {:#X}", i),
+            Fault::PcOutOfRange(pc) => write!(f, "PC {:#X} past end of RAM", pc),
+            Fault::InvalidKey(key) => write!(f, "no key {:#X}", key),
+            Fault::Halted => write!(f, "program halted"),
+        }
+    }
+}
+
+// Full machine state, for save states
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pc: u16,
+    i: u32,
+    ram: Vec<u8>,
+    v_regs: [u8; 16],
+    delay_timer: u8,
+    sound_timer: u8,
+    stack: [u16; 16],
+    sp: usize,
+    disp_buff: Vec<Vec<bool>>,
+    keys: [bool; 16],
+    cycles: u64,
+    delay_time_start: u64,
+    sound_time_start: u64,
+    #[serde(default)]
+    fault: Option<Fault>,
+    #[serde(default)]
+    rpl_flags: [u8; 16],
+    #[serde(default)]
+    machine_cycles: u64,
+    #[serde(default)]
+    vip: Option<VipState>,
+    #[serde(default)]
+    color_zones: [[u8; 8]; 32],
+    #[serde(default)]
+    background: u8,
+    #[serde(default)]
+    delay_wait: bool,
+    #[serde(default)]
+    mega: Option<MegaChip>,
+    #[serde(default)]
+    key_wait: KeyWait,
+    #[serde(default)]
+    key_events: VecDeque<KeyEvent>,
+}
+
+impl Snapshot {
+    // Describe every field that differs from other, for reporting divergences
+    pub fn diff(&self, other: &Snapshot) -> Vec<String> {
+        let mut diffs = Vec::new();
+        let mut field = |name: &str, ours: String, theirs: String| {
+            if ours != theirs {
+                diffs.push(format!("{}: {} vs {}", name, ours, theirs));
+            }
+        };
+        field("pc", format!("{:#05X}", self.pc), format!("{:#05X}", other.pc));
+        field("i", format!("{:#05X}", self.i), format!("{:#05X}", other.i));
+        field("v", format!("{:02X?}", self.v_regs), format!("{:02X?}", other.v_regs));
+        field("delay_timer", self.delay_timer.to_string(), other.delay_timer.to_string());
+        field("sound_timer", self.sound_timer.to_string(), other.sound_timer.to_string());
+        field("stack", format!("{:03X?}", self.stack), format!("{:03X?}", other.stack));
+        field("sp", self.sp.to_string(), other.sp.to_string());
+        field("keys", format!("{:?}", self.keys), format!("{:?}", other.keys));
+        field("cycles", self.cycles.to_string(), other.cycles.to_string());
+        field(
+            "delay_time_start",
+            self.delay_time_start.to_string(),
+            other.delay_time_start.to_string(),
+        );
+        field(
+            "sound_time_start",
+            self.sound_time_start.to_string(),
+            other.sound_time_start.to_string(),
+        );
+        if let Some(address) = (0..self.ram.len()).find(|&a| self.ram.get(a) != other.ram.get(a)) {
+            field(
+                &format!("ram[{:#05X}]", address),
+                format!("{:#04X}", self.ram[address]),
+                format!("{:#04X}", other.ram.get(address).copied().unwrap_or(0)),
+            );
+        }
+        field("fault", format!("{:?}", self.fault), format!("{:?}", other.fault));
+        field("rpl_flags", format!("{:02X?}", self.rpl_flags), format!("{:02X?}", other.rpl_flags));
+        field(
+            "machine_cycles",
+            self.machine_cycles.to_string(),
+            other.machine_cycles.to_string(),
+        );
+        field("background", self.background.to_string(), other.background.to_string());
+        field("delay_wait", self.delay_wait.to_string(), other.delay_wait.to_string());
+        field("key_wait", format!("{:?}", self.key_wait), format!("{:?}", other.key_wait));
+        field("key_events", format!("{:?}", self.key_events), format!("{:?}", other.key_events));
+        if self.color_zones != other.color_zones {
+            diffs.push("colour zones differ".to_string());
+        }
+        if self.mega != other.mega {
+            diffs.push("MEGA-CHIP state differs".to_string());
+        }
+        if self.vip != other.vip {
+            diffs.push("COSMAC VIP state differs".to_string());
+        }
+        if self.disp_buff != other.disp_buff {
+            diffs.push("display buffer differs".to_string());
+        }
+        diffs
+    }
+}
+
+// Plain data only, so instances are Send and clone with a few small copies
+#[derive(Clone)]
 pub struct YARCH8 {
     pc: u16, // only 12 bit = 4096 address possible
-    i: u16,  // same
+    i: u32,  // 16 bit, 24 bit with MEGA-CHIP
     ram: [u8; 4096],
     v_regs: [u8; 16],
     delay_timer: u8,
     sound_timer: u8,
     stack: [u16; 16],
     sp: usize,
-    disp_buff: [[bool; 64]; 32],
+    disp_buff: Vec<Vec<bool>>, // rows of pixels, size changes with display mode
     keys: [bool; 16], // 16 keys pressed or not pressed
-    delay_time_start: Instant,
-    sound_time_start: Instant,
+    cycles: u64, // instructions executed so far, used as emulated clock
+    delay_time_start: u64,
+    sound_time_start: u64,
     timer_req_duration: Duration,
     cycle_req_duration: Duration,
+    quirks: Quirks,
+    rng: StdRng, // for CXNN, seed it for reproducible runs
+    decode_cache: Vec<Option<Instruction>>, // decoded instruction per address, None until decoded
+    code_version: u64, // bumped whenever RAM or quirks change, so compiled code knows to recheck
+    fault: Option<Fault>, // set once the machine stopped, nothing runs until reset
+    rpl_flags: [u8; 16], // HP-48 RPL user flags for FX75/FX85, survive reset like on the calculator
+    timing: Timing,
+    machine_cycles: u64, // COSMAC VIP clock, only runs with its timing
+    last_step_cycles: u64, // machine cycles the last step took, for stall()
+    vip: Option<Box<Vip>>, // runs the original interpreter instead of execute()
+    variant: Variant,
+    color_zones: [[u8; 8]; 32], // CHIP-8X foreground colour per pixel row and 8 pixel column
+    background: u8,             // CHIP-8X background colour
+    delay_wait: bool,           // CHIP-8E FX4F set the delay timer and waits for it
+    mega: Option<Box<MegaChip>>, // colour screen, sound and memory above 4K of MEGA-CHIP
+    key_wait: KeyWait,
+    key_events: VecDeque<KeyEvent>, // queued input, oldest first, not yet due
 }
 
 impl YARCH8 {
@@ -31,30 +233,291 @@ impl YARCH8 {
             sound_timer: 0x0,
             stack: [0x0; 16],
             sp: 0x0,
-            disp_buff: [[false; 64]; 32],
+            disp_buff: vec![vec![false; 64]; 32],
             keys: [false; 16],
-            delay_time_start: Instant::now(),
-            sound_time_start: Instant::now(),
+            cycles: 0,
+            delay_time_start: 0,
+            sound_time_start: 0,
             timer_req_duration: Duration::new(0, 1_000_000_000u32/timer_freq),
             cycle_req_duration: Duration::new(0, 1_000_000_000u32/cycle_freq),
+            quirks: Quirks::default(),
+            rng: StdRng::from_entropy(),
+            decode_cache: vec![None; 4096],
+            code_version: 0,
+            fault: None,
+            rpl_flags: [0x0; 16],
+            timing: Timing::Flat,
+            machine_cycles: 0,
+            last_step_cycles: 0,
+            vip: None,
+            variant: Variant::Chip8,
+            color_zones: [[variant::CHIP8X_DEFAULT_COLOR; 8]; 32],
+            background: 0,
+            delay_wait: false,
+            mega: None,
+            key_wait: KeyWait::None,
+            key_events: VecDeque::new(),
         }
     }
 
-    pub fn load(&mut self, rom_path: &str) {
-        // Read from rom file and write into memory, from 0x200 onwards
-        let rom_file = File::open(rom_path).expect("Loading ROM error!");
-        for (idx, byte) in rom_file.bytes().enumerate() {
-            self.ram[0x200 + idx] = byte.expect("Byte error in loading ROM!");
+    pub fn load(&mut self, rom_path: &str) -> RomInfo {
+        // Read from rom file (any format loader knows) and write into memory, from 0x200 onwards
+        // (0x300 for CHIP-8X)
+        let rom = loader::read_rom(rom_path)
+            .unwrap_or_else(|e| panic!("Loading ROM error in {}: {}", rom_path, e));
+        self.load_bytes(&rom)
+    }
+
+    // Apply an IPS or BPS patch to the ROM image before loading it
+    pub fn load_patched(&mut self, rom_path: &str, patch_path: &str) -> Result<RomInfo, String> {
+        let rom = loader::read_rom(rom_path).map_err(|e| format!("Loading ROM error: {}", e))?;
+        let patch = fs::read(patch_path).map_err(|e| format!("Loading patch error: {}", e))?;
+        let rom = patch::apply(&rom, &patch)?;
+        Ok(self.load_bytes(&rom))
+    }
+
+    pub fn load_bytes(&mut self, rom: &[u8]) -> RomInfo {
+        // Whatever does not fit in RAM can not be loaded, MEGA-CHIP has more of it
+        let start = usize::from(self.variant.start_address());
+        let (low, high) = rom.split_at(rom.len().min(self.ram.len() - start));
+        self.ram[start..start + low.len()].copy_from_slice(low);
+        if let Some(mega) = self.mega.as_mut() {
+            mega.load(high);
         }
+        self.decode_cache.fill(None);
+        self.code_version += 1;
+        // Look up what the ROM expects from us
+        database::lookup(rom)
     }
 
     pub fn start(&mut self) {
-        self.store_font();
-        self.pc = 0x200;
+        match self.vip.as_mut() {
+            // The interpreter brings its own font
+            Some(vip) => {
+                vip.load_interpreter(&mut self.ram);
+                vip.reset();
+                self.code_version += 1;
+            }
+            None => self.store_font(),
+        }
+        self.pc = self.variant.start_address();
+    }
+
+    // Put machine back to power-on state, ROM has to be loaded again
+    pub fn reset(&mut self) {
+        self.pc = 0x0;
+        self.i = 0x0;
+        self.ram = [0x0; 4096];
+        self.v_regs = [0x0; 16];
+        self.delay_timer = 0x0;
+        self.sound_timer = 0x0;
+        self.stack = [0x0; 16];
+        self.sp = 0x0;
+        let (width, height) = self.variant.resolution();
+        self.disp_buff = vec![vec![false; width]; height];
+        self.keys = [false; 16];
+        self.key_wait = KeyWait::None;
+        self.key_events.clear();
+        self.cycles = 0;
+        self.delay_time_start = 0;
+        self.sound_time_start = 0;
+        self.machine_cycles = 0;
+        self.color_zones = [[variant::CHIP8X_DEFAULT_COLOR; 8]; 32];
+        self.background = 0;
+        self.delay_wait = false;
+        if let Some(mega) = self.mega.as_mut() {
+            **mega = MegaChip::new();
+        }
+        if let Some(vip) = self.vip.as_mut() {
+            vip.reset();
+        }
+        self.fault = None;
+        self.decode_cache.fill(None);
+        self.code_version += 1;
+    }
+
+    pub fn save_state(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            i: self.i,
+            ram: self.ram.to_vec(),
+            v_regs: self.v_regs,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            stack: self.stack,
+            sp: self.sp,
+            disp_buff: self.disp_buff.clone(),
+            keys: self.keys,
+            cycles: self.cycles,
+            delay_time_start: self.delay_time_start,
+            sound_time_start: self.sound_time_start,
+            fault: self.fault,
+            rpl_flags: self.rpl_flags,
+            machine_cycles: self.machine_cycles,
+            vip: self.vip.as_ref().map(|vip| vip.get_state().clone()),
+            color_zones: self.color_zones,
+            background: self.background,
+            delay_wait: self.delay_wait,
+            key_wait: self.key_wait,
+            key_events: self.key_events.clone(),
+            mega: self.mega.as_ref().map(|mega| (**mega).clone()),
+        }
+    }
+
+    // Snapshots may come from outside, so check them before touching anything
+    pub fn load_state(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        if snapshot.ram.len() != self.ram.len() {
+            return Err(format!("RAM must be {} bytes", self.ram.len()));
+        }
+        if snapshot.sp > self.stack.len() {
+            return Err("Stack pointer out of range".to_string());
+        }
+        let width = snapshot.disp_buff.first().map_or(0, |row| row.len());
+        if width == 0 || snapshot.disp_buff.iter().any(|row| row.len() != width) {
+            return Err("Display buffer must be a non-empty rectangle".to_string());
+        }
+        // Leave the clock plenty of room to count up
+        if snapshot.cycles > u64::MAX / 2 || snapshot.machine_cycles > u64::MAX / 2 {
+            return Err("Cycle count out of range".to_string());
+        }
+        if snapshot.mega.is_some() != self.mega.is_some() {
+            return Err("Snapshot and machine must both be MEGA-CHIP or neither".to_string());
+        }
+        if let Some(mega) = &snapshot.mega {
+            mega.validate()?;
+            if mega.is_enabled()
+                && (width != megachip::WIDTH || snapshot.disp_buff.len() != megachip::HEIGHT)
+            {
+                return Err("MEGA-CHIP mode needs a 256x192 display buffer".to_string());
+            }
+        }
+        if snapshot.i > self.wrap_i(u32::MAX) {
+            return Err("I out of range".to_string());
+        }
+        if snapshot.vip.is_some() != self.vip.is_some() {
+            return Err("Snapshot and machine must both be COSMAC VIPs or neither".to_string());
+        }
+        if usize::from(snapshot.background) >= variant::CHIP8X_BACKGROUNDS.len()
+            || snapshot.color_zones.iter().flatten().any(|&color| color > 7)
+        {
+            return Err("CHIP-8X colours out of range".to_string());
+        }
+        if snapshot.key_events.iter().any(|event| event.key > 0xF) {
+            return Err("Key events must be for keys 0 to F".to_string());
+        }
+        if snapshot.key_events.len() > MAX_KEY_EVENTS {
+            return Err(format!("More than {} queued key events", MAX_KEY_EVENTS));
+        }
+        let latest_start = snapshot.delay_time_start.max(snapshot.sound_time_start);
+        if latest_start > snapshot.cycles {
+            return Err("Timer start cycles must not be ahead of the cycle count".to_string());
+        }
+        self.pc = snapshot.pc;
+        self.i = snapshot.i;
+        self.ram.copy_from_slice(&snapshot.ram);
+        self.v_regs = snapshot.v_regs;
+        self.delay_timer = snapshot.delay_timer;
+        self.sound_timer = snapshot.sound_timer;
+        self.stack = snapshot.stack;
+        self.sp = snapshot.sp;
+        self.disp_buff = snapshot.disp_buff.clone();
+        self.keys = snapshot.keys;
+        self.cycles = snapshot.cycles;
+        self.delay_time_start = snapshot.delay_time_start;
+        self.sound_time_start = snapshot.sound_time_start;
+        self.fault = snapshot.fault;
+        self.rpl_flags = snapshot.rpl_flags;
+        self.machine_cycles = snapshot.machine_cycles;
+        if let (Some(vip), Some(state)) = (self.vip.as_mut(), &snapshot.vip) {
+            vip.set_state(state.clone());
+        }
+        self.color_zones = snapshot.color_zones;
+        self.background = snapshot.background;
+        self.delay_wait = snapshot.delay_wait;
+        self.key_wait = snapshot.key_wait;
+        self.key_events = snapshot.key_events.clone();
+        if let (Some(mega), Some(state)) = (self.mega.as_mut(), &snapshot.mega) {
+            **mega = state.clone();
+        }
+        self.decode_cache.fill(None);
+        self.code_version += 1;
+        Ok(())
+    }
+
+    pub fn set_timer_freq(&mut self, timer_freq: u32) {
+        self.timer_req_duration = Duration::new(0, 1_000_000_000u32 / timer_freq);
+    }
+
+    pub fn set_cpu_freq(&mut self, cycle_freq: u32) {
+        self.cycle_req_duration = Duration::new(0, 1_000_000_000u32 / cycle_freq);
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+        self.code_version += 1;
+    }
+
+    pub fn get_quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /* Instruction set and display size of a historical dialect. Resets the display, and
+        changes where the ROM goes, so it has to be set before loading.
+     */
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+        self.mega = (variant == Variant::MegaChip).then(|| Box::new(MegaChip::new()));
+        let (width, height) = variant.resolution();
+        self.set_resolution(width, height);
+        self.decode_cache.fill(None);
+        self.code_version += 1;
+    }
+
+    pub fn get_variant(&self) -> Variant {
+        self.variant
+    }
+
+    // With COSMAC VIP timing the CPU frequency is fixed and the timer frequency ignored
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    pub fn get_timing(&self) -> Timing {
+        self.timing
+    }
+
+    pub fn get_machine_cycles(&self) -> u64 {
+        self.machine_cycles
+    }
+
+    /* Turn the machine into a COSMAC VIP running the original interpreter, or back.
+        Takes effect on start(), timing is always the VIP's. CHIP-8 registers then live
+        in the interpreter's RAM and the 1802, get_pc() and friends do not follow them.
+     */
+    pub fn set_vip(&mut self, vip: Option<Vip>) {
+        if vip.is_some() {
+            self.timing = Timing::CosmacVip;
+        }
+        self.vip = vip.map(Box::new);
+        self.decode_cache.fill(None);
+        self.code_version += 1;
     }
 
-    pub fn stall(&self) {
-        ::std::thread::sleep(self.cycle_req_duration);
+    pub fn get_vip(&self) -> Option<&Vip> {
+        self.vip.as_deref()
+    }
+
+    // Sleep for one cycle (the last step with COSMAC VIP timing), slowed down by given factor
+    pub fn stall(&self, slowdown: u32) {
+        let duration = match self.timing {
+            Timing::Flat if self.vip.is_none() => self.cycle_req_duration,
+            _ => Duration::from_nanos(self.last_step_cycles * timing::MACHINE_CYCLE_NANOS),
+        };
+        ::std::thread::sleep(duration * slowdown);
     }
 
     pub fn fetch(&mut self) -> u16 {
@@ -64,169 +527,264 @@ impl YARCH8 {
         ((self.ram[fetch_address] as u16) << 8) + (self.ram[fetch_address + 1] as u16)
     }
 
+    // Run one full cycle: fetch, decode, execute and update timers. Returns instruction run.
+    // A faulted machine does nothing and returns 0.
+    pub fn step(&mut self) -> u16 {
+        if self.fault.is_some() {
+            // Nothing would ever drain them
+            self.key_events.clear();
+            return 0x0000;
+        }
+        self.apply_key_events();
+        if self.vip.is_some() {
+            return self.step_vip();
+        }
+        let address = self.pc as usize;
+        if address + 1 >= self.ram.len() {
+            self.fault = Some(Fault::PcOutOfRange(self.pc));
+            return 0x0000;
+        }
+        let instruction = self.fetch();
+        // Decode once per address, writes to RAM throw stale entries away
+        let decoded = match self.decode_cache[address] {
+            Some(decoded) => decoded,
+            None => {
+                let decoded = self.variant.decode(instruction);
+                self.decode_cache[address] = Some(decoded);
+                decoded
+            }
+        };
+        match self.timing {
+            Timing::Flat => {
+                self.execute(decoded);
+                self.tick_timers();
+            }
+            Timing::CosmacVip => self.execute_vip(decoded),
+        }
+        instruction
+    }
+
+    // Costs are worked out before running, the instruction changes what they depend on
+    fn execute_vip(&mut self, instruction: Instruction) {
+        let start = self.machine_cycles;
+        let mut cycles = timing::vip_cycles(self, instruction);
+        // Sprites are drawn right after the frame interrupt
+        if let Instruction::Draw { .. } = instruction {
+            cycles += timing::until_frame(start);
+        }
+        self.machine_cycles = timing::advance(start, cycles);
+        self.last_step_cycles = self.machine_cycles - start;
+        self.execute(instruction);
+        // The frame interrupt counts both timers down
+        let frames = timing::frames_between(start, self.machine_cycles);
+        let frames = frames.min(u8::MAX as u64) as u8;
+        self.delay_timer = self.delay_timer.saturating_sub(frames);
+        self.sound_timer = self.sound_timer.saturating_sub(frames);
+    }
+
+    /* Run the VIP until its interpreter is about to fetch the next CHIP-8 instruction, or
+        for a frame at most so key waits do not block. Returns the instruction fetched.
+     */
+    fn step_vip(&mut self) -> u16 {
+        let vip = self.vip.as_mut().unwrap();
+        let start = self.machine_cycles;
+        let mut instruction = 0x0000;
+        let mut fetched = 0;
+        while self.machine_cycles - start < timing::FRAME_CYCLES {
+            if vip.is_fetching(&self.ram) {
+                if fetched == 2 {
+                    break;
+                }
+                instruction = instruction << 8 | u16::from(vip.peek_r5(&self.ram));
+                fetched += 1;
+            }
+            vip.step(&mut self.ram, &self.keys, &mut self.disp_buff, &mut self.machine_cycles);
+        }
+        self.last_step_cycles = self.machine_cycles - start;
+        self.cycles += 1;
+        // RAM may have changed under any compiled code
+        self.code_version += 1;
+        instruction
+    }
+
     pub fn decode_execute(&mut self, instruction: u16) {
-        // Extract
-        let vx: usize = self.get_vx(instruction);
-        let vy: usize = self.get_vy(instruction);
-        let nnn: u16 = self.get_nnn(instruction);
-        let nn: u8 = self.get_nn(instruction);
-        let n: u8 = self.get_n(instruction);
-
-        // Decode
-        match instruction & 0xF000 {
-            0x0000 => {
-                if nn == 0xE0 {
-                    // Clear screen
-                    self.disp_buff = [[false; 64]; 32];
-                } else if nn == 0xEE {
-                    // Return from routine
-                    if self.sp == 0 {
-                        panic!("Return failed. No return address in stack!");
-                    }
-                    self.pc = self.stack[self.sp - 1];
-                    // Better clear stack
-                    self.stack[self.sp - 1] = 0x0000;
-                    self.sp -= 1;
-                } else {
-                    // Throw error
-                    panic!()
+        self.execute(self.variant.decode(instruction));
+    }
+
+    pub fn execute(&mut self, instruction: Instruction) {
+        self.cycles += 1;
+
+        match instruction {
+            // Clear screen
+            Instruction::Cls => {
+                self.clear_disp_buff();
+                if let Some(mega) = self.mega.as_mut() {
+                    mega.clear();
+                }
+            }
+            // Switch to low resolution (64x32)
+            Instruction::Lores => self.switch_resolution(64, 32),
+            // Switch to high resolution (128x64)
+            Instruction::Hires => self.switch_resolution(128, 64),
+            Instruction::Ret => {
+                // Return from routine
+                if self.sp == 0 {
+                    return self.raise(Fault::StackUnderflow);
                 }
+                self.pc = self.stack[self.sp - 1];
+                // Better clear stack
+                self.stack[self.sp - 1] = 0x0000;
+                self.sp -= 1;
             }
+            Instruction::Sys { nnn } => self.raise(Fault::MachineCode(nnn)),
             // Jump
-            0x1000 => self.pc = nnn,
+            Instruction::Jump { nnn } => self.pc = nnn,
             // Call routine
-            0x2000 => {
+            Instruction::Call { nnn } => {
                 if self.sp >= 16 {
-                    // Stack overflow
-                    panic!("Call routine failed as stack overflow...");
+                    return self.raise(Fault::StackOverflow);
                 }
                 self.stack[self.sp] = self.pc;
                 self.sp += 1;
                 self.pc = nnn;
             }
             // Skips or Nops
-            0x3000 => {
-                if self.v_regs[vx] == nn {
+            Instruction::SkipEqImm { x, nn } => {
+                if self.v_regs[x as usize] == nn {
                     self.pc += 2;
                 }
             }
-            0x4000 => {
-                if self.v_regs[vx] != nn {
+            Instruction::SkipNeImm { x, nn } => {
+                if self.v_regs[x as usize] != nn {
                     self.pc += 2;
                 }
             }
-            0x5000 => {
-                if self.v_regs[vx] == self.v_regs[vy] {
+            Instruction::SkipEqReg { x, y } => {
+                if self.v_regs[x as usize] == self.v_regs[y as usize] {
                     self.pc += 2;
                 }
             }
             // Set VXNN
-            0x6000 => {
-                self.v_regs[vx] = nn;
-            }
+            Instruction::SetImm { x, nn } => self.v_regs[x as usize] = nn,
             // Add to Vx NN
-            0x7000 => {
-                let (wrapped_sum, _) = self.v_regs[vx].overflowing_add(nn);
-                self.v_regs[vx] = wrapped_sum;
+            Instruction::AddImm { x, nn } => {
+                let (wrapped_sum, _) = self.v_regs[x as usize].overflowing_add(nn);
+                self.v_regs[x as usize] = wrapped_sum;
             }
             // Arithmetics...
-            0x8000 => match n {
-                0 => self.v_regs[vx] = self.v_regs[vy],
-                1 => self.v_regs[vx] |= self.v_regs[vy],
-                2 => self.v_regs[vx] &= self.v_regs[vy],
-                3 => self.v_regs[vx] ^= self.v_regs[vy],
-                4 => {
-                    let (wrapped_sum, is_overflow) =
-                        self.v_regs[vx].overflowing_add(self.v_regs[vy]);
-                    self.v_regs[vx] = wrapped_sum;
-                    if is_overflow {
-                        self.v_regs[15] = 0x1;
-                    } else {
-                        self.v_regs[15] = 0x0;
-                    }
+            Instruction::Mov { x, y } => self.v_regs[x as usize] = self.v_regs[y as usize],
+            Instruction::Or { x, y } => {
+                self.v_regs[x as usize] |= self.v_regs[y as usize];
+                if self.quirks.vf_reset {
+                    self.v_regs[15] = 0x0;
                 }
-                5 => {
-                    // VX = VX - VY
-                    let (result, is_overflow) = self.v_regs[vx].overflowing_sub(self.v_regs[vy]);
-                    self.v_regs[vx] = result;
-                    if is_overflow {
-                        self.v_regs[15] = 0x0;
-                    } else {
-                        self.v_regs[15] = 0x1;
-                    }
+            }
+            Instruction::And { x, y } => {
+                self.v_regs[x as usize] &= self.v_regs[y as usize];
+                if self.quirks.vf_reset {
+                    self.v_regs[15] = 0x0;
                 }
-                6 => {
-                    // TODO: Config to handle ambiguity
-                    // Default to modern
-                    // Right shift
-                    //self.v_regs[vx] = self.v_regs[vy];
-                    self.v_regs[15] = self.v_regs[vx] & 0x01;
-                    self.v_regs[vx] = self.v_regs[vx] >> 1;
-                }
-                7 => {
-                    // VX = VY - VX
-                    let (result, is_overflow) = self.v_regs[vy].overflowing_sub(self.v_regs[vx]);
-                    self.v_regs[vx] = result;
-                    if is_overflow {
-                        self.v_regs[15] = 0x0;
-                    } else {
-                        self.v_regs[15] = 0x1;
-                    }
+            }
+            Instruction::Xor { x, y } => {
+                self.v_regs[x as usize] ^= self.v_regs[y as usize];
+                if self.quirks.vf_reset {
+                    self.v_regs[15] = 0x0;
+                }
+            }
+            Instruction::AddReg { x, y } => {
+                let (wrapped_sum, is_overflow) =
+                    self.v_regs[x as usize].overflowing_add(self.v_regs[y as usize]);
+                self.v_regs[x as usize] = wrapped_sum;
+                self.v_regs[15] = is_overflow as u8;
+            }
+            Instruction::Sub { x, y } => {
+                // VX = VX - VY
+                let (result, is_overflow) =
+                    self.v_regs[x as usize].overflowing_sub(self.v_regs[y as usize]);
+                self.v_regs[x as usize] = result;
+                self.v_regs[15] = !is_overflow as u8;
+            }
+            Instruction::Shr { x, y } => {
+                // Right shift
+                if self.quirks.shift_vy {
+                    self.v_regs[x as usize] = self.v_regs[y as usize];
                 }
-                0xE => {
-                    // TODO: Config to handle ambiguity
-                    // Default to modern
-                    // Left shift
-                    //self.v_regs[vx] = self.v_regs[vy];
-                    self.v_regs[15] = (self.v_regs[vx] & 0x80) >> 7;
-                    self.v_regs[vx] = self.v_regs[vx] << 1;
-                }
-                _ => unimplemented!(),
-            },
-            0x9000 => {
-                if self.v_regs[vx] != self.v_regs[vy] {
+                let flag = self.v_regs[x as usize] & 0x01;
+                self.v_regs[x as usize] >>= 1;
+                self.v_regs[15] = flag;
+            }
+            Instruction::SubN { x, y } => {
+                // VX = VY - VX
+                let (result, is_overflow) =
+                    self.v_regs[y as usize].overflowing_sub(self.v_regs[x as usize]);
+                self.v_regs[x as usize] = result;
+                self.v_regs[15] = !is_overflow as u8;
+            }
+            Instruction::Shl { x, y } => {
+                // Left shift
+                if self.quirks.shift_vy {
+                    self.v_regs[x as usize] = self.v_regs[y as usize];
+                }
+                let flag = (self.v_regs[x as usize] & 0x80) >> 7;
+                self.v_regs[x as usize] <<= 1;
+                self.v_regs[15] = flag;
+            }
+            Instruction::SkipNeReg { x, y } => {
+                if self.v_regs[x as usize] != self.v_regs[y as usize] {
                     self.pc += 2;
                 }
             }
             // Set I NN
-            0xA000 => self.i = nnn,
-            0xB000 => {
-                // TODO: Adding a check if we are using OG chip-8 or not to allow
-                // multiple behaviour support
-
-                // Assume that CHIP-8 OG is used for now
-                // Jump to NNN + V0 content
-                self.pc = nnn + u16::from(self.v_regs[0]);
+            Instruction::SetI { nnn } => self.i = u32::from(nnn),
+            Instruction::JumpOffset { x, nnn } => {
+                if self.quirks.jump_vx {
+                    // Jump to XNN + VX content
+                    self.pc = nnn + u16::from(self.v_regs[x as usize]);
+                } else {
+                    // Jump to NNN + V0 content
+                    self.pc = nnn + u16::from(self.v_regs[0]);
+                }
             }
-            0xC000 => {
+            Instruction::Rand { x, nn } => {
                 // Gen random number, AND with NN and store in VX
-                let nonce: u8 = rand::random();
-                self.v_regs[vx] = nonce & nn;
+                let nonce: u8 = self.rng.gen();
+                self.v_regs[x as usize] = nonce & nn;
             }
             // Draw
-            0xD000 => {
+            Instruction::Draw { x, y, .. } if self.is_mega_mode() => self.draw_mega(x, y),
+            Instruction::Draw { x, y, n } => {
                 // Set an init value and restart from here every new line of sprite
                 // If we increment by 1 for every sprite, the image is skewed and hit edge...
-                let x_init = usize::try_from(self.v_regs[vx]).unwrap() % 64;
-                let y_init = usize::try_from(self.v_regs[vy]).unwrap() % 32;
+                let (width, height) = self.get_resolution();
+                let x_init = usize::from(self.v_regs[x as usize]) % width;
+                let y_init = usize::from(self.v_regs[y as usize]) % height;
 
                 // Clear flag register
                 self.v_regs[15] = 0;
 
                 for layer in 0..(n as usize) {
-                    let y = y_init + layer;
-                    if y >= 32 {
-                        // hit bottom so break!
-                        break;
+                    let mut y = y_init + layer;
+                    if y >= height {
+                        if !self.quirks.wrap_sprites {
+                            // hit bottom so break!
+                            break;
+                        }
+                        y %= height;
                     }
 
                     // Otw
-                    let sprite: u8 = self.ram[usize::from(self.i) + layer];
+                    if !self.i_range_fits(layer + 1) {
+                        return self.raise(Fault::MemoryOutOfRange(self.i));
+                    }
+                    let sprite = self.read_memory(self.i as usize + layer);
 
                     for bit_pos in 0..8 {
-                        let x = x_init + bit_pos;
-                        if x >= 64 {
-                            break;
+                        let mut x = x_init + bit_pos;
+                        if x >= width {
+                            if !self.quirks.wrap_sprites {
+                                break;
+                            }
+                            x %= width;
                         }
                         // If apply bit mask = 0 => the pixel is off, no need shift
                         let b = (sprite & (1 << (7 - bit_pos))) != 0;
@@ -239,103 +797,401 @@ impl YARCH8 {
                     }
                 }
             }
-            0xE000 => match nn {
-                0x9E => {
-                    // if VX 's value key is pressed, skip (PC +2)
-                    if self.keys[self.v_regs[vx] as usize] {
-                        self.pc += 2;
-                    }
+            Instruction::SkipKey { x } => {
+                // if VX 's value key is pressed, skip (PC +2)
+                match self.keys.get(self.v_regs[x as usize] as usize) {
+                    Some(true) => self.pc += 2,
+                    Some(false) => {}
+                    None => self.raise(Fault::InvalidKey(self.v_regs[x as usize])),
                 }
-                0xA1 => {
-                    // if VX 's value key is not pressed, skip (PC +2)
-                    if !self.keys[self.v_regs[vx] as usize] {
-                        self.pc += 2;
-                    }
+            }
+            Instruction::SkipNotKey { x } => {
+                // if VX 's value key is not pressed, skip (PC +2)
+                match self.keys.get(self.v_regs[x as usize] as usize) {
+                    Some(true) => {}
+                    Some(false) => self.pc += 2,
+                    None => self.raise(Fault::InvalidKey(self.v_regs[x as usize])),
                 }
-                _ => unimplemented!(),
-            },
-            0xF000 => match nn {
-                0x07 => {
-                    // Set VX to delay_timer value
-                    self.v_regs[vx] = self.delay_timer;
-                }
-                0x0A => {
-                    // Get key pressed, otherwise skip
-                    // Deviate from original behaviour
-                    // Just get the first one pressed from the list
-                    if self.keys.iter().any(|&k| k) {
-                        let (keypressed_idx, _) = *self
-                            .keys
-                            .iter()
-                            .enumerate()
-                            .filter(|(_, &k)| k)
-                            .collect::<Vec<_>>()
-                            .first()
-                            .unwrap();
-                        self.v_regs[vx] = keypressed_idx as u8;
-                    } else {
-                        // Revert value of PC to basically blocking...
+            }
+            Instruction::GetDelay { x } => {
+                // Set VX to delay_timer value
+                self.v_regs[x as usize] = self.delay_timer;
+            }
+            Instruction::WaitKey { x } if self.quirks.wait_key_release => self.wait_key_release(x),
+            Instruction::WaitKey { x } => {
+                // Get key pressed, otherwise skip
+                // Deviate from original behaviour
+                // Just get the first one pressed from the list
+                match self.keys.iter().position(|&k| k) {
+                    Some(keypressed_idx) => {
+                        self.v_regs[x as usize] = keypressed_idx as u8;
+                        self.key_wait = KeyWait::None;
+                    }
+                    // Revert value of PC to basically blocking...
+                    None => {
+                        self.key_wait = KeyWait::Waiting;
                         self.pc -= 2;
                     }
                 }
-                0x15 => {
-                    // Set delay timer to vx
-                    self.delay_timer = self.v_regs[vx];
-                    self.delay_time_start = Instant::now();
-                }
-                0x18 => {
-                    // Set sound timer to vx
-                    self.sound_timer = self.v_regs[vx];
-                    self.sound_time_start = Instant::now();
-                }
-                0x1E => {
-                    //add to idx
-                    // Here we do not care about setting VF.
-                    // TODO: handle VF if overflow...
-                    self.i += u16::from(self.v_regs[vx]);
-                }
-                0x29 => {
-                    // Font char
-                    // Take lower of vx reg as char
-                    let font_base = 0x50;
-                    let offset = self.v_regs[vx] * 5;
-                    // Set index reg to the address = font_base + offset
-                    self.i = u16::from(font_base + offset);
-                }
-                0x33 => {
-                    // Take digits in VX and write in I, I + 1, ...
-                    let mut num_digit = self.v_regs[vx].to_string().len();
-                    let mut num = self.v_regs[vx];
-                    while num != 0 {
-                        let digit = num.rem_euclid(10);
-                        self.ram[usize::from(self.i) + num_digit - 1] = digit;
-                        num_digit -= 1;
-                        num = num.div_euclid(10);
-                    }
+            }
+            Instruction::SetDelay { x } => {
+                // Set delay timer to vx
+                self.delay_timer = self.v_regs[x as usize];
+                self.delay_time_start = self.cycles;
+            }
+            Instruction::SetSound { x } => {
+                // Set sound timer to vx
+                self.sound_timer = self.v_regs[x as usize];
+                self.sound_time_start = self.cycles;
+            }
+            Instruction::AddI { x } => {
+                //add to idx
+                // Here we do not care about setting VF.
+                // TODO: handle VF if overflow...
+                self.i = self.wrap_i(self.i + u32::from(self.v_regs[x as usize]));
+            }
+            Instruction::Font { x } => {
+                // Font char
+                // Take lower of vx reg as char
+                let font_base = 0x50;
+                let offset = (self.v_regs[x as usize] & 0xF) * 5;
+                // Set index reg to the address = font_base + offset
+                self.i = font_base + u32::from(offset);
+            }
+            Instruction::Bcd { x } => {
+                // Hundreds, tens and ones of VX go to I, I + 1 and I + 2, zeros included
+                if !self.i_range_fits(3) {
+                    return self.raise(Fault::MemoryOutOfRange(self.i));
                 }
-                0x55 => {
-                    // TODO: Ambiguous instruction
-                    // Load
-                    for idx in 0..=vx {
-                        self.ram[self.i as usize + idx] = self.v_regs[idx];
-                    }
+                let num = self.v_regs[x as usize];
+                let idx = self.i as usize;
+                self.write_memory(idx, num / 100);
+                self.write_memory(idx + 1, num / 10 % 10);
+                self.write_memory(idx + 2, num % 10);
+            }
+            Instruction::Store { x } => {
+                // Load
+                if !self.i_range_fits(x as usize + 1) {
+                    return self.raise(Fault::MemoryOutOfRange(self.i));
                 }
-                0x65 => {
-                    // TODO: Ambiguous instruction
-                    // Store
-                    for idx in 0..=vx {
-                        self.v_regs[idx] = self.ram[self.i as usize + idx];
-                    }
+                for idx in 0..=(x as usize) {
+                    self.write_memory(self.i as usize + idx, self.v_regs[idx]);
+                }
+                if self.quirks.load_store_inc_i {
+                    self.i = self.wrap_i(self.i + u32::from(x) + 1);
+                }
+            }
+            Instruction::Load { x } => {
+                // Store
+                if !self.i_range_fits(x as usize + 1) {
+                    return self.raise(Fault::MemoryOutOfRange(self.i));
+                }
+                for idx in 0..=(x as usize) {
+                    self.v_regs[idx] = self.read_memory(self.i as usize + idx);
+                }
+                if self.quirks.load_store_inc_i {
+                    self.i = self.wrap_i(self.i + u32::from(x) + 1);
+                }
+            }
+            Instruction::SaveFlags { x } => {
+                // 16 flags so any X fits, SUPER-CHIP programs only use the first 8
+                let len = x as usize + 1;
+                self.rpl_flags[..len].copy_from_slice(&self.v_regs[..len]);
+            }
+            Instruction::LoadFlags { x } => {
+                let len = x as usize + 1;
+                self.v_regs[..len].copy_from_slice(&self.rpl_flags[..len]);
+            }
+            // CHIP-8E
+            Instruction::Halt => self.raise(Fault::Halted),
+            Instruction::Nop => {}
+            Instruction::WaitDelay => {
+                if self.delay_timer > 0 {
+                    self.pc -= 2;
+                }
+            }
+            Instruction::SkipAlways => self.pc += 2,
+            Instruction::SkipGtReg { x, y } => {
+                if self.v_regs[x as usize] > self.v_regs[y as usize] {
+                    self.pc += 2;
+                }
+            }
+            Instruction::StoreRange { x, y } => {
+                // I ends up after the last register, ranges running backwards are empty
+                let (first, len) = (x as usize, (y as usize + 1).saturating_sub(x as usize));
+                if !self.i_range_fits(len) {
+                    return self.raise(Fault::MemoryOutOfRange(self.i));
                 }
-                _ => unimplemented!(),
-            },
-            _ => unimplemented!(),
+                for idx in 0..len {
+                    self.write_memory(self.i as usize + idx, self.v_regs[first + idx]);
+                }
+                self.i = self.wrap_i(self.i + len as u32);
+            }
+            Instruction::LoadRange { x, y } => {
+                let (first, len) = (x as usize, (y as usize + 1).saturating_sub(x as usize));
+                if !self.i_range_fits(len) {
+                    return self.raise(Fault::MemoryOutOfRange(self.i));
+                }
+                for idx in 0..len {
+                    self.v_regs[first + idx] = self.read_memory(self.i as usize + idx);
+                }
+                self.i = self.wrap_i(self.i + len as u32);
+            }
+            // Branches count from the branch instruction itself
+            Instruction::JumpBack { nn } => {
+                self.pc = self.pc.wrapping_sub(2).wrapping_sub(u16::from(nn));
+            }
+            Instruction::JumpForward { nn } => {
+                self.pc = self.pc.wrapping_sub(2).wrapping_add(u16::from(nn));
+            }
+            Instruction::SkipBytes { x } => {
+                self.pc = self.pc.wrapping_add(u16::from(self.v_regs[x as usize]));
+            }
+            Instruction::DelayWait { x } => {
+                // Set the timer on the first run only, then block until it ran out
+                if !self.delay_wait {
+                    self.delay_timer = self.v_regs[x as usize];
+                    self.delay_time_start = self.cycles;
+                    self.delay_wait = true;
+                }
+                if self.delay_timer > 0 {
+                    self.pc -= 2;
+                } else {
+                    self.delay_wait = false;
+                }
+            }
+            // Nothing is connected to the ports
+            Instruction::Output { .. } | Instruction::Tone { .. } => {}
+            Instruction::InputStrobe { x }
+            | Instruction::Input { x }
+            | Instruction::PortInput { x } => self.v_regs[x as usize] = 0,
+            // CHIP-8X
+            Instruction::NextBackground => {
+                self.background = (self.background + 1) % variant::CHIP8X_BACKGROUNDS.len() as u8;
+            }
+            Instruction::AddNibbles { x, y } => {
+                // Both nibbles are added on their own, modulo 8 like the colour numbers
+                let (vx, vy) = (self.v_regs[x as usize], self.v_regs[y as usize]);
+                let high = ((vx >> 4) + (vy >> 4)) % 8;
+                let low = ((vx & 0xF) + (vy & 0xF)) % 8;
+                self.v_regs[x as usize] = high << 4 | low;
+            }
+            Instruction::Color { x, y, n } => self.set_color_zones(x, y, n),
+            // There is no second keypad, its keys are never down
+            Instruction::SkipKey2 { .. } => {}
+            Instruction::SkipNotKey2 { .. } => self.pc += 2,
+            // MEGA-CHIP
+            Instruction::MegaOff => {
+                self.mega_chip().set_enabled(false);
+                self.set_resolution(64, 32);
+            }
+            Instruction::MegaOn => {
+                self.mega_chip().set_enabled(true);
+                self.set_resolution(megachip::WIDTH, megachip::HEIGHT);
+            }
+            Instruction::LongIndex { nn } => {
+                // Low 16 bits are the next 2 bytes, skipped over like an instruction
+                let address = self.pc as usize;
+                if address + 1 >= self.ram.len() {
+                    return self.raise(Fault::PcOutOfRange(self.pc));
+                }
+                let low = u16::from_be_bytes([self.ram[address], self.ram[address + 1]]);
+                self.i = u32::from(nn) << 16 | u32::from(low);
+                self.pc += 2;
+            }
+            Instruction::LoadPalette { nn } => {
+                let len = usize::from(nn) * 4;
+                if !self.i_range_fits(len) {
+                    return self.raise(Fault::MemoryOutOfRange(self.i));
+                }
+                let colors = self.read_memory_at_i(len);
+                self.mega_chip().load_palette(&colors);
+            }
+            Instruction::SpriteWidth { nn } => self.mega_chip().set_sprite_width(nn),
+            Instruction::SpriteHeight { nn } => self.mega_chip().set_sprite_height(nn),
+            Instruction::PlaySound { n } => {
+                if !self.i_range_fits(megachip::SOUND_HEADER) {
+                    return self.raise(Fault::MemoryOutOfRange(self.i));
+                }
+                let len = megachip::sound_len(&self.read_memory_at_i(megachip::SOUND_HEADER));
+                if !self.i_range_fits(len) {
+                    return self.raise(Fault::MemoryOutOfRange(self.i));
+                }
+                let sound = self.read_memory_at_i(len);
+                // N = 0 loops the sample
+                self.mega_chip().play(&sound, n == 0);
+            }
+            Instruction::StopSound => self.mega_chip().stop(),
+            Instruction::BlendMode { n } => self.mega_chip().set_blend(Blend::from_mode(n)),
+            Instruction::CollisionColor { nn } => self.mega_chip().set_collision_color(nn),
+            Instruction::Unknown(op) => self.raise(Fault::UnknownOpcode(op)),
+        }
+    }
+
+    /* FX0A like the COSMAC VIP: only a key pressed after the wait started counts, and it
+        is stored once released. The tone sounds while the key is down.
+     */
+    fn wait_key_release(&mut self, x: u8) {
+        match self.key_wait {
+            KeyWait::Pressed(key) if !self.keys[usize::from(key)] => {
+                self.v_regs[x as usize] = key;
+                self.key_wait = KeyWait::None;
+                return;
+            }
+            KeyWait::Pressed(_) => {
+                if self.sound_timer == 0 {
+                    self.sound_timer = 1;
+                    self.sound_time_start = self.cycles;
+                }
+            }
+            KeyWait::None | KeyWait::Waiting => self.key_wait = KeyWait::Waiting,
+        }
+        self.pc -= 2;
+    }
+
+    // FX0A would run again without anything changing, until a key comes along
+    fn is_key_wait_idle(&self) -> bool {
+        match self.key_wait {
+            KeyWait::Waiting => self.quirks.wait_key_release || !self.keys.contains(&true),
+            _ => false,
+        }
+    }
+
+    // MEGA-CHIP sprites are palette indexes, VF tells whether one hit the collision colour
+    fn draw_mega(&mut self, x: u8, y: u8) {
+        let (width, height) = self.mega_chip().get_sprite_size();
+        if !self.i_range_fits(width * height) {
+            return self.raise(Fault::MemoryOutOfRange(self.i));
         }
+        let sprite = self.read_memory_at_i(width * height);
+        let (x, y) = (self.v_regs[x as usize], self.v_regs[y as usize]);
+        let mega = self.mega.as_mut().unwrap();
+        let collision = mega.draw(x.into(), y.into(), &sprite, &mut self.disp_buff);
+        self.v_regs[15] = collision as u8;
+    }
+
+    // Switched to MEGA-CHIP mode with 0011, the colour screen is in use
+    fn is_mega_mode(&self) -> bool {
+        self.mega.as_ref().is_some_and(|mega| mega.is_enabled())
+    }
+
+    // Only MEGA-CHIP decodes its instructions, so the state is always there for them
+    fn mega_chip(&mut self) -> &mut MegaChip {
+        self.mega.as_mut().expect("MEGA-CHIP instruction without MEGA-CHIP state")
+    }
+
+    /* CHIP-8X BXYN: VX picks the 8 pixel columns, VX+1 the rows and VY the colour.
+        Positions are in the low nibbles, the high nibbles add that many more zones.
+        N = 0 colours blocks of 4 rows, otherwise N rows from the pixel row in VX+1.
+     */
+    fn set_color_zones(&mut self, x: u8, y: u8, n: u8) {
+        let horizontal = usize::from(self.v_regs[x as usize]);
+        let vertical = usize::from(self.v_regs[(x as usize + 1) % 16]);
+        let color = self.v_regs[y as usize] & 0x7;
+        let left = horizontal & 0xF;
+        let columns = left..=left + (horizontal >> 4);
+        let rows = if n == 0 {
+            let top = (vertical & 0xF) * variant::COLOR_ZONE_HEIGHT;
+            top..top + ((vertical >> 4) + 1) * variant::COLOR_ZONE_HEIGHT
+        } else {
+            vertical..vertical + usize::from(n)
+        };
+        let (zone_columns, zone_rows) = (self.color_zones[0].len(), self.color_zones.len());
+        for row in rows {
+            for column in columns.clone() {
+                self.color_zones[row % zone_rows][column % zone_columns] = color;
+            }
+        }
+    }
+
+    // Stop the machine with PC left on the faulting instruction
+    fn raise(&mut self, fault: Fault) {
+        self.fault = Some(fault);
+        self.pc -= 2;
+    }
+
+    // Whether len bytes starting at I are all inside RAM
+    fn i_range_fits(&self, len: usize) -> bool {
+        self.i as usize + len <= self.memory_len()
+    }
+
+    // I wraps around at 16 bits, at 24 with MEGA-CHIP
+    fn wrap_i(&self, i: u32) -> u32 {
+        match self.mega {
+            Some(_) => i & 0xFF_FFFF,
+            None => i & 0xFFFF,
+        }
+    }
+
+    /* Memory as seen through I. MEGA-CHIP has up to 16 MB, code still lives in the first
+        4K so everything above is kept apart from RAM.
+     */
+    fn memory_len(&self) -> usize {
+        match self.mega {
+            Some(_) => megachip::MEMORY_SIZE,
+            None => self.ram.len(),
+        }
+    }
+
+    fn read_memory(&self, address: usize) -> u8 {
+        match (self.ram.get(address), &self.mega) {
+            (Some(&value), _) => value,
+            (None, Some(mega)) => mega.read(address - self.ram.len()),
+            (None, None) => 0,
+        }
+    }
+
+    fn write_memory(&mut self, address: usize, value: u8) {
+        if address < self.ram.len() {
+            self.write_ram(address, value);
+        } else if let Some(mega) = self.mega.as_mut() {
+            mega.write(address - self.ram.len(), value);
+        }
+    }
+
+    fn read_memory_at_i(&self, len: usize) -> Vec<u8> {
+        (0..len).map(|idx| self.read_memory(self.i as usize + idx)).collect()
+    }
+
+    pub fn get_fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    /* RPL flags, games keep high scores in them. The calculator never forgets them, so
+        they can be saved to a file per ROM and restored next time.
+     */
+    pub fn get_rpl_flags(&self) -> &[u8; 16] {
+        &self.rpl_flags
+    }
+
+    pub fn set_rpl_flags(&mut self, flags: [u8; 16]) {
+        self.rpl_flags = flags;
+    }
+
+    // Missing file means no flags saved yet, shorter files only set the first flags
+    pub fn load_rpl_flags(&mut self, path: &str) -> io::Result<()> {
+        let flags = match fs::read(path) {
+            Ok(flags) => flags,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let len = flags.len().min(self.rpl_flags.len());
+        self.rpl_flags = [0x0; 16];
+        self.rpl_flags[..len].copy_from_slice(&flags[..len]);
+        Ok(())
+    }
+
+    pub fn save_rpl_flags(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.rpl_flags)
     }
 
     /* Keys Related
      */
     pub fn key_press(&mut self, key: u8) {
+        // Held keys repeat, only going down counts as a press
+        if self.key_wait == KeyWait::Waiting && !self.keys[usize::from(key)] {
+            self.key_wait = KeyWait::Pressed(key);
+        }
         self.keys[usize::from(key)] = true;
     }
 
@@ -343,6 +1199,68 @@ impl YARCH8 {
         self.keys[usize::from(key)] = false;
     }
 
+    /* Queue a key change for the given cycle. Events stamped before one already queued
+        are moved up to it, the queue stays in order. Nothing is lost between instructions:
+        EX9E and EXA1 see every press at least once and FX0A sees every press and release.
+        A faulted machine drops its events, a full queue (the machine is not running)
+        refuses new ones.
+     */
+    pub fn queue_key_event(&mut self, mut event: KeyEvent) -> Result<(), String> {
+        if event.key > 0xF {
+            return Err(format!("No key {:#X}", event.key));
+        }
+        if self.fault.is_some() {
+            return Ok(());
+        }
+        if self.key_events.len() >= MAX_KEY_EVENTS {
+            return Err("Key event queue is full".to_string());
+        }
+        if let Some(last) = self.key_events.back() {
+            event.cycle = event.cycle.max(last.cycle);
+        }
+        self.key_events.push_back(event);
+        Ok(())
+    }
+
+    pub fn get_key_events(&self) -> &VecDeque<KeyEvent> {
+        &self.key_events
+    }
+
+    // Instructions to run before the next queued event is due
+    pub fn cycles_until_key_event(&self) -> Option<u64> {
+        self.key_events
+            .front()
+            .map(|event| event.cycle.saturating_sub(self.cycles))
+    }
+
+    /* Apply events that are due. A key changes once per instruction at most, so a press
+        and release stamped alike still leave the key down for one instruction.
+     */
+    fn apply_key_events(&mut self) {
+        let mut changed = [false; 16];
+        while let Some(&event) = self.key_events.front() {
+            if event.cycle > self.cycles || changed[usize::from(event.key)] {
+                break;
+            }
+            self.key_events.pop_front();
+            changed[usize::from(event.key)] = true;
+            if event.pressed {
+                self.key_press(event.key);
+            } else {
+                self.key_released(event.key);
+            }
+        }
+    }
+
+    pub fn get_keys(&self) -> &[bool; 16] {
+        &self.keys
+    }
+
+    // Stuck in FX0A until a key is pressed (and released, with that quirk)
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.key_wait != KeyWait::None
+    }
+
     /* Fonts
      */
     fn store_font(&mut self) {
@@ -367,60 +1285,189 @@ impl YARCH8 {
         ];
 
         for (idx, byte) in fonts.iter().enumerate() {
-            self.ram[font_base + idx] = *byte;
+            self.write_ram(font_base + idx, *byte);
         }
     }
 
     /* UTIL FUNCTIONS:
-    - Get some register from instructions, etc
+    - Timers, etc
     */
-    fn get_vx(&self, instruction: u16) -> usize {
-        (instruction & 0x0F00) as usize >> 8u8
-    }
-
-    fn get_vy(&self, instruction: u16) -> usize {
-        (instruction & 0x00F0) as usize >> 4u8
-    }
-
-    fn get_n(&self, instruction: u16) -> u8 {
-        (instruction & 0x000F) as u8
-    }
+    fn tick_timers(&mut self) {
+        if self.to_decrease_delay_timer() {
+            self.decrease_delay_timer();
+        }
 
-    fn get_nn(&self, instruction: u16) -> u8 {
-        (instruction & 0x00FF) as u8
+        if self.to_decrease_sound_timer() {
+            self.decrease_sound_timer();
+        }
     }
 
-    fn get_nnn(&self, instruction: u16) -> u16 {
-        instruction & 0x0FFF
+    // Emulated time passed since given cycle count
+    fn elapsed_since(&self, start: u64) -> Duration {
+        self.cycle_req_duration * (self.cycles - start) as u32
     }
 
+    // Timers are usually off, check that first to keep the hot loop cheap
     pub fn to_decrease_delay_timer(&self) -> bool {
-        let elapsed_time = self.delay_time_start.elapsed().as_nanos();
-        self.delay_timer > 0 && elapsed_time > self.timer_req_duration.as_nanos()
+        self.delay_timer > 0
+            && self.elapsed_since(self.delay_time_start) > self.timer_req_duration
     }
 
     pub fn to_decrease_sound_timer(&self) -> bool {
-        let elapsed_time = self.delay_time_start.elapsed().as_nanos();
-        self.sound_timer > 0 && elapsed_time > self.timer_req_duration.as_nanos()
+        self.sound_timer > 0
+            && self.elapsed_since(self.sound_time_start) > self.timer_req_duration
     }
 
     pub fn decrease_delay_timer(&mut self){
         self.delay_timer -= 1;
-        self.delay_time_start = Instant::now();
+        self.delay_time_start = self.cycles;
     }
 
     pub fn decrease_sound_timer(&mut self){
         self.sound_timer -= 1;
-        self.sound_time_start = Instant::now();
+        self.sound_time_start = self.cycles;
+    }
+
+    /* Display Related
+     */
+    fn clear_disp_buff(&mut self) {
+        for row in self.disp_buff.iter_mut() {
+            row.fill(false);
+        }
+    }
+
+    // 00FE and 00FF, MEGA-CHIP mode only comes in 256x192 so they leave it
+    fn switch_resolution(&mut self, width: usize, height: usize) {
+        if self.is_mega_mode() {
+            self.mega_chip().set_enabled(false);
+        }
+        self.set_resolution(width, height);
+    }
+
+    // Changing display mode also clears the screen
+    fn set_resolution(&mut self, width: usize, height: usize) {
+        self.disp_buff = vec![vec![false; width]; height];
+    }
+
+    pub fn get_resolution(&self) -> (usize, usize) {
+        (self.disp_buff[0].len(), self.disp_buff.len())
+    }
+
+    // CHIP-8X foreground colour of a pixel, index into variant::CHIP8X_FOREGROUNDS
+    pub fn get_pixel_color(&self, x: usize, y: usize) -> u8 {
+        let row = &self.color_zones[y % self.color_zones.len()];
+        row[x / variant::COLOR_ZONE_WIDTH % row.len()]
+    }
+
+    // CHIP-8X background colour, index into variant::CHIP8X_BACKGROUNDS
+    pub fn get_background_color(&self) -> u8 {
+        self.background
+    }
+
+    /* Debugger access
+     */
+    pub fn get_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn get_pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc & 0xFFF;
+    }
+
+    pub fn get_i(&self) -> u32 {
+        self.i
+    }
+
+    // Same wrapping as FX1E, addresses past 4K fault when used
+    pub fn set_i(&mut self, i: u16) {
+        self.i = self.wrap_i(u32::from(i));
+    }
+
+    pub fn get_v_reg(&self, idx: usize) -> u8 {
+        self.v_regs[idx]
+    }
+
+    pub fn set_v_reg(&mut self, idx: usize, value: u8) {
+        self.v_regs[idx] = value;
+    }
+
+    pub fn get_sp(&self) -> usize {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: usize) {
+        self.sp = sp.min(self.stack.len());
+    }
+
+    pub fn get_stack(&self) -> &[u16; 16] {
+        &self.stack
+    }
+
+    pub fn get_delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+        self.delay_time_start = self.cycles;
+    }
+
+    pub fn get_sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+        self.sound_time_start = self.cycles;
+    }
+
+    pub fn get_ram(&self) -> &[u8; 4096] {
+        &self.ram
+    }
+
+    // Every RAM write goes through here so cached instructions never go stale
+    pub fn write_ram(&mut self, address: usize, value: u8) {
+        self.ram[address] = value;
+        // Instructions are 2B, so the one starting a byte earlier changed as well
+        self.decode_cache[address] = None;
+        if address > 0 {
+            self.decode_cache[address - 1] = None;
+        }
+        self.code_version += 1;
     }
 
     /* DEBUG FUNCTIONS:
         Print out stuffs for debugging
     */
-    pub fn get_disp_buff(&self) -> &[[bool; 64]; 32] {
+    pub fn get_disp_buff(&self) -> &[Vec<bool>] {
         &self.disp_buff
     }
 
+    // ARGB pixels in MEGA-CHIP mode, rows as wide as the resolution says
+    pub fn get_frame(&self) -> Option<&[u32]> {
+        match &self.mega {
+            Some(mega) if mega.is_enabled() => Some(mega.get_frame()),
+            _ => None,
+        }
+    }
+
+    // Width and height DXYN sprites have in MEGA-CHIP mode
+    pub fn get_mega_sprite_size(&self) -> Option<(usize, usize)> {
+        match &self.mega {
+            Some(mega) if mega.is_enabled() => Some(mega.get_sprite_size()),
+            _ => None,
+        }
+    }
+
+    // Sample MEGA-CHIP is playing
+    pub fn get_sound(&self) -> Option<&megachip::Sound> {
+        self.mega.as_ref().and_then(|mega| mega.get_sound())
+    }
+
     pub fn ram_peek(&self) {
         println!("{:?}", self.ram);
     }
@@ -0,0 +1,337 @@
+use super::{Quirks, Timing, Variant, YARCH8};
+use crate::instruction::Instruction;
+use rand::Rng;
+
+/* Basic block recompiler, translates straight-line CHIP-8 code into closures.
+
+    A block is a run of instructions that only touch registers, I and the random
+    generator, ended by the first instruction that branches, draws, waits for a key,
+    reads or sets a timer or accesses memory. Those exits are run by the interpreter
+    itself, so the block only has to get the simple instructions right, none of which
+    can fault.
+
+    Every block remembers the bytes, quirks and variant it was compiled from and is thrown
+    away when they no longer match, which covers self-modifying code as well as writes from
+    the debugger or loading a state. That check only runs after something changed RAM
+    or quirks, so a Jit must only ever be used with one machine (or clones of it).
+    Timers can only start inside a block at its exit, so when both are off on entry
+    the per-instruction timer checks are skipped. Blocks count instructions, not
+    machine cycles, so with COSMAC VIP timing everything is interpreted.
+*/
+
+const MAX_BLOCK_LEN: usize = 64;
+
+type Op = Box<dyn Fn(&mut YARCH8) + Send + Sync>;
+
+// Which backend runs the instructions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Interpreter,
+    Jit,
+    Lockstep, // JIT checked against the interpreter after every block
+}
+
+struct Block {
+    bytes: Vec<u8>, // RAM the block was compiled from
+    quirks: Quirks,
+    variant: Variant,
+    checked_version: u64, // machine's code version when bytes and quirks last matched
+    body: Vec<Op>,
+    exit: Option<Op>,    // None falls through to the next address
+    idle: bool,          // jumps to itself and does nothing else, ROMs often end this way
+    waits_for_key: bool, // just FX0A, which is idle as well until a key comes along
+}
+
+impl Block {
+    fn len(&self) -> usize {
+        self.body.len() + self.exit.is_some() as usize
+    }
+
+    fn is_valid(&mut self, yarch8: &YARCH8, start: usize) -> bool {
+        if self.checked_version == yarch8.code_version {
+            return true;
+        }
+        let valid = self.quirks == yarch8.quirks
+            && self.variant == yarch8.variant
+            && yarch8.ram[start..start + self.bytes.len()] == self.bytes;
+        if valid {
+            self.checked_version = yarch8.code_version;
+        }
+        valid
+    }
+}
+
+pub struct Jit {
+    blocks: Vec<Option<Box<Block>>>, // indexed by start address
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        Jit {
+            blocks: (0..4096).map(|_| None).collect(),
+        }
+    }
+
+    // Run exactly this many instructions, same results as calling step() as often
+    pub fn run(&mut self, yarch8: &mut YARCH8, cycles: u32) {
+        let mut done = 0;
+        while done < cycles && yarch8.fault.is_none() {
+            done += self.run_block(yarch8, cycles - done);
+        }
+    }
+
+    // Run the JIT and an interpreter copy side by side, error describes the first difference
+    pub fn run_lockstep(&mut self, yarch8: &mut YARCH8, cycles: u32) -> Result<(), String> {
+        let mut reference = yarch8.clone();
+        let mut done = 0;
+        while done < cycles && yarch8.fault.is_none() {
+            let start = yarch8.pc;
+            let ran = self.run_block(yarch8, cycles - done);
+            for _ in 0..ran {
+                reference.step();
+            }
+            let (ours, theirs) = (yarch8.save_state(), reference.save_state());
+            if ours != theirs {
+                return Err(format!(
+                    "JIT diverged from interpreter in block at {:#05X}: {}",
+                    start,
+                    ours.diff(&theirs).join(", ")
+                ));
+            }
+            done += ran;
+        }
+        Ok(())
+    }
+
+    // Run the block at PC if it fits in max_cycles, otherwise one interpreted step
+    fn run_block(&mut self, yarch8: &mut YARCH8, max_cycles: u32) -> u32 {
+        let start = yarch8.pc as usize;
+        // Let the interpreter deal with fetching past the end of RAM, with timing models
+        // other than one cycle per instruction and with the emulated VIP, which runs the
+        // CHIP-8 interpreter from its own memory
+        if start + 1 >= yarch8.ram.len() || yarch8.timing != Timing::Flat || yarch8.vip.is_some() {
+            yarch8.step();
+            return 1;
+        }
+        // Queued key events are applied by step(), blocks stop short of them
+        let max_cycles = match yarch8.cycles_until_key_event() {
+            Some(0) => {
+                yarch8.step();
+                return 1;
+            }
+            Some(until) => max_cycles.min(until.min(u64::from(u32::MAX)) as u32),
+            None => max_cycles,
+        };
+
+        if !self.blocks[start]
+            .as_mut()
+            .is_some_and(|block| block.is_valid(yarch8, start))
+        {
+            self.blocks[start] = Some(Box::new(compile(yarch8, start)));
+        }
+        let block = self.blocks[start].as_ref().unwrap();
+        let len = block.len();
+        if len == 0 || len > max_cycles as usize {
+            yarch8.step();
+            return 1;
+        }
+
+        // Only the clock and timers move while idling, so run all the cycles at once
+        if block.idle || (block.waits_for_key && yarch8.is_key_wait_idle()) {
+            if yarch8.delay_timer == 0 && yarch8.sound_timer == 0 {
+                yarch8.cycles += max_cycles as u64;
+            } else {
+                for _ in 0..max_cycles {
+                    yarch8.cycles += 1;
+                    yarch8.tick_timers();
+                }
+            }
+            return max_cycles;
+        }
+
+        if yarch8.delay_timer == 0 && yarch8.sound_timer == 0 {
+            for op in block.body.iter() {
+                op(yarch8);
+            }
+            yarch8.cycles += block.body.len() as u64;
+        } else {
+            for op in block.body.iter() {
+                yarch8.cycles += 1;
+                op(yarch8);
+                yarch8.tick_timers();
+            }
+        }
+
+        // Exits see PC already pointing past themselves, as after a fetch
+        let exit_address = (start + 2 * block.body.len()) as u16;
+        match &block.exit {
+            Some(exit) => {
+                yarch8.pc = exit_address + 2;
+                exit(yarch8);
+                yarch8.tick_timers();
+            }
+            None => yarch8.pc = exit_address,
+        }
+        len as u32
+    }
+}
+
+fn compile(yarch8: &YARCH8, start: usize) -> Block {
+    let quirks = yarch8.quirks;
+    let mut body = Vec::new();
+    let mut exit = None;
+    let mut idle = false;
+    let mut waits_for_key = false;
+    let mut address = start;
+
+    while body.len() < MAX_BLOCK_LEN && address + 1 < yarch8.ram.len() {
+        let opcode = ((yarch8.ram[address] as u16) << 8) | yarch8.ram[address + 1] as u16;
+        let instruction = yarch8.variant.decode(opcode);
+        // Unknown instructions are left for the interpreter to fail on
+        if let Instruction::Sys { .. } | Instruction::Unknown(_) = instruction {
+            break;
+        }
+        address += 2;
+        match compile_op(instruction, quirks) {
+            Some(op) => body.push(op),
+            None => {
+                idle = body.is_empty() && instruction == Instruction::Jump { nnn: start as u16 };
+                waits_for_key =
+                    body.is_empty() && matches!(instruction, Instruction::WaitKey { .. });
+                exit = Some(compile_exit(instruction));
+                break;
+            }
+        }
+    }
+
+    Block {
+        bytes: yarch8.ram[start..address].to_vec(),
+        quirks,
+        variant: yarch8.variant,
+        checked_version: yarch8.code_version,
+        body,
+        exit,
+        idle,
+        waits_for_key,
+    }
+}
+
+// Closure doing what execute() does for straight-line instructions, None for block exits
+fn compile_op(instruction: Instruction, quirks: Quirks) -> Option<Op> {
+    let op: Op = match instruction {
+        Instruction::SetImm { x, nn } => Box::new(move |m| m.v_regs[x as usize] = nn),
+        Instruction::AddImm { x, nn } => {
+            Box::new(move |m| m.v_regs[x as usize] = m.v_regs[x as usize].wrapping_add(nn))
+        }
+        Instruction::Mov { x, y } => Box::new(move |m| m.v_regs[x as usize] = m.v_regs[y as usize]),
+        Instruction::Or { x, y } => Box::new(move |m| {
+            m.v_regs[x as usize] |= m.v_regs[y as usize];
+            if quirks.vf_reset {
+                m.v_regs[15] = 0x0;
+            }
+        }),
+        Instruction::And { x, y } => Box::new(move |m| {
+            m.v_regs[x as usize] &= m.v_regs[y as usize];
+            if quirks.vf_reset {
+                m.v_regs[15] = 0x0;
+            }
+        }),
+        Instruction::Xor { x, y } => Box::new(move |m| {
+            m.v_regs[x as usize] ^= m.v_regs[y as usize];
+            if quirks.vf_reset {
+                m.v_regs[15] = 0x0;
+            }
+        }),
+        Instruction::AddReg { x, y } => Box::new(move |m| {
+            let (sum, is_overflow) = m.v_regs[x as usize].overflowing_add(m.v_regs[y as usize]);
+            m.v_regs[x as usize] = sum;
+            m.v_regs[15] = is_overflow as u8;
+        }),
+        Instruction::Sub { x, y } => Box::new(move |m| {
+            let (result, is_overflow) = m.v_regs[x as usize].overflowing_sub(m.v_regs[y as usize]);
+            m.v_regs[x as usize] = result;
+            m.v_regs[15] = !is_overflow as u8;
+        }),
+        Instruction::SubN { x, y } => Box::new(move |m| {
+            let (result, is_overflow) = m.v_regs[y as usize].overflowing_sub(m.v_regs[x as usize]);
+            m.v_regs[x as usize] = result;
+            m.v_regs[15] = !is_overflow as u8;
+        }),
+        Instruction::Shr { x, y } => {
+            let source = if quirks.shift_vy { y } else { x } as usize;
+            Box::new(move |m| {
+                let value = m.v_regs[source];
+                m.v_regs[x as usize] = value >> 1;
+                m.v_regs[15] = value & 0x01;
+            })
+        }
+        Instruction::Shl { x, y } => {
+            let source = if quirks.shift_vy { y } else { x } as usize;
+            Box::new(move |m| {
+                let value = m.v_regs[source];
+                m.v_regs[x as usize] = value << 1;
+                m.v_regs[15] = (value & 0x80) >> 7;
+            })
+        }
+        Instruction::SetI { nnn } => Box::new(move |m| m.i = u32::from(nnn)),
+        Instruction::Rand { x, nn } => Box::new(move |m| {
+            let nonce: u8 = m.rng.gen();
+            m.v_regs[x as usize] = nonce & nn;
+        }),
+        Instruction::AddI { x } => {
+            Box::new(move |m| m.i = m.wrap_i(m.i + u32::from(m.v_regs[x as usize])))
+        }
+        Instruction::Font { x } => Box::new(move |m| {
+            let font_base = 0x50;
+            let offset = (m.v_regs[x as usize] & 0xF) * 5;
+            m.i = font_base + u32::from(offset);
+        }),
+        _ => return None,
+    };
+    Some(op)
+}
+
+// Exits count their own cycle, the common control flow ones skip the interpreter's dispatch
+fn compile_exit(instruction: Instruction) -> Op {
+    match instruction {
+        Instruction::Jump { nnn } => Box::new(move |m| {
+            m.cycles += 1;
+            m.pc = nnn;
+        }),
+        Instruction::SkipEqImm { x, nn } => Box::new(move |m| {
+            m.cycles += 1;
+            if m.v_regs[x as usize] == nn {
+                m.pc += 2;
+            }
+        }),
+        Instruction::SkipNeImm { x, nn } => Box::new(move |m| {
+            m.cycles += 1;
+            if m.v_regs[x as usize] != nn {
+                m.pc += 2;
+            }
+        }),
+        Instruction::SkipEqReg { x, y } => Box::new(move |m| {
+            m.cycles += 1;
+            if m.v_regs[x as usize] == m.v_regs[y as usize] {
+                m.pc += 2;
+            }
+        }),
+        Instruction::SkipNeReg { x, y } => Box::new(move |m| {
+            m.cycles += 1;
+            if m.v_regs[x as usize] != m.v_regs[y as usize] {
+                m.pc += 2;
+            }
+        }),
+        Instruction::GetDelay { x } => Box::new(move |m| {
+            m.cycles += 1;
+            m.v_regs[x as usize] = m.delay_timer;
+        }),
+        _ => Box::new(move |m| m.execute(instruction)),
+    }
+}
@@ -0,0 +1,250 @@
+use serde::{Deserialize, Serialize};
+
+/* MEGA-CHIP 8 state, for the MEGA-CHIP variant.
+
+    Programs start out as plain CHIP-8 and switch to MEGA-CHIP mode with 0011 (0010 goes
+    back). In MEGA-CHIP mode the screen is 256x192 true colour pixels. Sprites are
+    03NN x 04NN bytes, each the index of a colour loaded with 02NN from ARGB quadruples
+    at I, 0 being transparent. Sprites are blended onto the screen as 080N says and
+    collide where they cover a pixel of the 09NN colour. Memory goes up to 16 MB, code
+    still has to be in the first 4K but 01NN NNNN points I anywhere. 060N plays the
+    8 bit unsigned samples at I, after a header of sample rate (2 bytes) and length
+    (3 bytes) and one unused byte, 0700 stops them.
+*/
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 192;
+pub const MEMORY_SIZE: usize = 0x100_0000; // 24 bit addresses
+pub const SOUND_HEADER: usize = 6;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Blend {
+    #[default]
+    Normal,
+    Alpha25, // 25% sprite, 75% screen
+    Alpha50,
+    Add,
+    Multiply,
+}
+
+impl Blend {
+    pub fn from_mode(mode: u8) -> Blend {
+        match mode {
+            1 => Blend::Alpha25,
+            2 => Blend::Alpha50,
+            3 => Blend::Add,
+            4 => Blend::Multiply,
+            _ => Blend::Normal,
+        }
+    }
+
+    // Colour of a screen pixel after a sprite pixel went on top, both ARGB
+    fn apply(&self, screen: u32, sprite: u32) -> u32 {
+        let channel = |color: u32, shift: u32| (color >> shift) & 0xFF;
+        let mix = |shift: u32| {
+            let (s, p) = (channel(screen, shift), channel(sprite, shift));
+            let mixed = match self {
+                Blend::Normal => p,
+                Blend::Alpha25 => (s * 3 + p) / 4,
+                Blend::Alpha50 => (s + p) / 2,
+                Blend::Add => (s + p).min(0xFF),
+                Blend::Multiply => s * p / 0xFF,
+            };
+            mixed << shift
+        };
+        0xFF00_0000 | mix(16) | mix(8) | mix(0)
+    }
+}
+
+// Sample started by 060N, copied out of memory so players do not need the machine
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sound {
+    pub serial: u64, // counts 060N, so a restarted sample can be told apart
+    pub rate: u16,   // samples per second
+    pub looping: bool,
+    pub data: Vec<u8>, // unsigned 8 bit mono
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MegaChip {
+    enabled: bool,
+    memory: Vec<u8>,   // everything above the first 4K, only as much as was used
+    palette: Vec<u32>, // ARGB, 256 entries, 0 is transparent
+    sprite_width: usize,
+    sprite_height: usize,
+    blend: Blend,
+    collision_color: u8,
+    frame: Vec<u32>,  // ARGB per pixel, rows of WIDTH
+    indexes: Vec<u8>, // palette index each pixel was last drawn with, for collisions
+    sound: Option<Sound>,
+    sounds_started: u64,
+}
+
+impl Default for MegaChip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MegaChip {
+    pub fn new() -> Self {
+        MegaChip {
+            enabled: false,
+            memory: Vec::new(),
+            palette: vec![0xFF00_0000; 256],
+            sprite_width: 0,
+            sprite_height: 0,
+            blend: Blend::Normal,
+            collision_color: 0,
+            frame: vec![0xFF00_0000; WIDTH * HEIGHT],
+            indexes: vec![0; WIDTH * HEIGHT],
+            sound: None,
+            sounds_started: 0,
+        }
+    }
+
+    // Save states come from outside, sizes must match what the rest relies on
+    pub fn validate(&self) -> Result<(), String> {
+        if self.memory.len() > MEMORY_SIZE - 0x1000 {
+            return Err("MEGA-CHIP memory larger than 16 MB".to_string());
+        }
+        if self.palette.len() != 256
+            || self.frame.len() != WIDTH * HEIGHT
+            || self.indexes.len() != WIDTH * HEIGHT
+        {
+            return Err("MEGA-CHIP palette or screen has the wrong size".to_string());
+        }
+        if self.sprite_width > 256 || self.sprite_height > 256 {
+            return Err("MEGA-CHIP sprite size out of range".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // 0011 and 0010, both clear the screen
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.frame.fill(0xFF00_0000);
+        self.indexes.fill(0);
+    }
+
+    pub fn get_frame(&self) -> &[u32] {
+        &self.frame
+    }
+
+    pub fn get_sound(&self) -> Option<&Sound> {
+        self.sound.as_ref()
+    }
+
+    /* Memory above the first 4K, addresses count from 0x1000. It grows on demand so
+       a small ROM does not drag 16 MB along with every clone of the machine.
+    */
+    pub fn memory_len(&self) -> usize {
+        self.memory.len()
+    }
+
+    // Never written memory reads as 0
+    pub fn read(&self, address: usize) -> u8 {
+        self.memory.get(address).copied().unwrap_or(0)
+    }
+
+    // ROM bytes that did not fit in the first 4K
+    pub fn load(&mut self, bytes: &[u8]) {
+        self.memory = bytes[..bytes.len().min(MEMORY_SIZE - 0x1000)].to_vec();
+    }
+
+    pub fn write(&mut self, address: usize, value: u8) {
+        if address >= self.memory.len() {
+            self.memory.resize(address + 1, 0);
+        }
+        self.memory[address] = value;
+    }
+
+    // 02NN, colours 1 to NN from the ARGB quadruples given
+    pub fn load_palette(&mut self, colors: &[u8]) {
+        for (idx, argb) in colors.chunks_exact(4).enumerate() {
+            self.palette[idx + 1] = u32::from_be_bytes([argb[0], argb[1], argb[2], argb[3]]);
+        }
+    }
+
+    // 03NN and 04NN, 0 stands for 256
+    pub fn set_sprite_width(&mut self, nn: u8) {
+        self.sprite_width = if nn == 0 { 256 } else { usize::from(nn) };
+    }
+
+    pub fn set_sprite_height(&mut self, nn: u8) {
+        self.sprite_height = if nn == 0 { 256 } else { usize::from(nn) };
+    }
+
+    pub fn get_sprite_size(&self) -> (usize, usize) {
+        (self.sprite_width, self.sprite_height)
+    }
+
+    pub fn set_blend(&mut self, blend: Blend) {
+        self.blend = blend;
+    }
+
+    pub fn set_collision_color(&mut self, index: u8) {
+        self.collision_color = index;
+    }
+
+    /* Draw a sprite of palette indexes, clipped at the edges. Pixels drawn are set in the
+       display buffer as well. Returns whether it covered a pixel last drawn in the
+       collision colour.
+    */
+    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8], disp_buff: &mut [Vec<bool>]) -> bool {
+        let mut collision = false;
+        let width = self.sprite_width.max(1);
+        for (row, line) in sprite.chunks(width).enumerate() {
+            let py = y + row;
+            if py >= HEIGHT {
+                break;
+            }
+            for (col, &index) in line.iter().enumerate() {
+                let px = x + col;
+                if index == 0 || px >= WIDTH {
+                    continue;
+                }
+                let at = py * WIDTH + px;
+                if self.indexes[at] != 0 && self.indexes[at] == self.collision_color {
+                    collision = true;
+                }
+                self.indexes[at] = index;
+                self.frame[at] = self
+                    .blend
+                    .apply(self.frame[at], self.palette[index as usize]);
+                disp_buff[py][px] = true;
+            }
+        }
+        collision
+    }
+
+    // 060N, header and samples as read from I onwards
+    pub fn play(&mut self, header_and_data: &[u8], looping: bool) {
+        let rate = u16::from_be_bytes([header_and_data[0], header_and_data[1]]);
+        self.sounds_started += 1;
+        self.sound = Some(Sound {
+            serial: self.sounds_started,
+            rate,
+            looping,
+            data: header_and_data[SOUND_HEADER..].to_vec(),
+        });
+    }
+
+    pub fn stop(&mut self) {
+        self.sound = None;
+    }
+}
+
+// Bytes 060N reads from I: the header, then as many samples as it gives
+pub fn sound_len(header: &[u8]) -> usize {
+    let len = u32::from_be_bytes([0, header[2], header[3], header[4]]);
+    SOUND_HEADER + len as usize
+}
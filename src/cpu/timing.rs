@@ -0,0 +1,163 @@
+use super::YARCH8;
+use crate::instruction::Instruction;
+use serde::Deserialize;
+
+/* How long instructions take in emulated time.
+
+    Flat is the usual model: every instruction takes one cycle of the configured CPU
+    frequency, timers count down after enough of those have passed.
+
+    CosmacVip charges every instruction what the original interpreter spends on it,
+    counted in CDP1802 machine cycles (8 clocks of the 1.76 MHz crystal, about 4.54us).
+    The CDP1861 draws a frame every 3668 machine cycles (262 lines of 14), its interrupt
+    counts the timers down and the display DMA steals the cycles of the 128 lines shown.
+    DXYN waits for that interrupt before drawing, like the original, so no more than one
+    sprite gets drawn per frame. Costs vary with the operands the way the interpreter's
+    loops do: DXYN with the sprite height and how far the sprite has to be shifted to
+    its byte, FX33 with the digits, FX55/FX65 with the register count.
+*/
+
+pub const MACHINE_CYCLE_NANOS: u64 = 4544;
+pub const FRAME_CYCLES: u64 = 3668;
+const DISPLAY_CYCLES: u64 = 128 * 8; // DMA of 8 bytes per line shown
+const INTERRUPT_CYCLES: u64 = 46; // interrupt routine, counts the timers down
+const FETCH_CYCLES: u64 = 40; // fetch and dispatch, spent on every instruction
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Timing {
+    #[default]
+    Flat,
+    CosmacVip,
+}
+
+impl Timing {
+    pub fn from_name(name: &str) -> Option<Timing> {
+        match name {
+            "flat" => Some(Timing::Flat),
+            "cosmac_vip" | "cosmac-vip" | "vip" => Some(Timing::CosmacVip),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Timing::Flat => "flat",
+            Timing::CosmacVip => "COSMAC VIP",
+        }
+    }
+}
+
+// Machine cycles the VIP interpreter spends on the instruction, fetch included
+pub fn vip_cycles(yarch8: &YARCH8, instruction: Instruction) -> u64 {
+    let v = |x: u8| yarch8.v_regs[x as usize];
+    let skip = |taken: bool| if taken { 14 } else { 10 };
+    let execute = match instruction {
+        Instruction::Cls => 24 + 3078, // clears the 256 display bytes one at a time
+        Instruction::Ret => 10,
+        Instruction::Lores | Instruction::Hires => 24,
+        Instruction::Sys { .. } => 26,
+        Instruction::Jump { .. } => 12,
+        Instruction::Call { .. } => 26,
+        Instruction::SkipEqImm { x, nn } => skip(v(x) == nn),
+        Instruction::SkipNeImm { x, nn } => skip(v(x) != nn),
+        Instruction::SkipEqReg { x, y } => skip(v(x) == v(y)) + 4,
+        Instruction::SkipNeReg { x, y } => skip(v(x) != v(y)) + 4,
+        Instruction::SetImm { .. } => 6,
+        Instruction::AddImm { .. } => 10,
+        Instruction::Mov { .. } => 12,
+        // The 8XYN instructions run a generated 1802 instruction from RAM
+        Instruction::Or { .. }
+        | Instruction::And { .. }
+        | Instruction::Xor { .. }
+        | Instruction::AddReg { .. }
+        | Instruction::Sub { .. }
+        | Instruction::Shr { .. }
+        | Instruction::SubN { .. }
+        | Instruction::Shl { .. } => 44,
+        Instruction::SetI { .. } => 12,
+        // One more cycle pair when the target lands on the next page
+        Instruction::JumpOffset { nnn, .. } => {
+            if (nnn & 0xFF) + u16::from(v(0)) > 0xFF {
+                24
+            } else {
+                22
+            }
+        }
+        Instruction::Rand { .. } => 36,
+        Instruction::Draw { x, n, .. } => {
+            // Every row is shifted right bit by bit into two display bytes
+            let shift = u64::from(v(x) % 8);
+            let rows = u64::from(n);
+            26 + rows * (46 + 20 * shift)
+        }
+        Instruction::SkipKey { x } => skip(yarch8.keys.get(v(x) as usize) == Some(&true)) + 4,
+        Instruction::SkipNotKey { x } => skip(yarch8.keys.get(v(x) as usize) != Some(&true)) + 4,
+        Instruction::GetDelay { .. } => 10,
+        Instruction::WaitKey { .. } => 18, // one keyboard scan
+        Instruction::SetDelay { .. } | Instruction::SetSound { .. } => 10,
+        Instruction::AddI { .. } => 16,
+        Instruction::Font { .. } => 16,
+        // Digits are found by repeated subtraction
+        Instruction::Bcd { x } => {
+            let value = v(x);
+            let digits = value / 100 + value / 10 % 10 + value % 10;
+            80 + 16 * u64::from(digits)
+        }
+        Instruction::Store { x }
+        | Instruction::Load { x }
+        | Instruction::SaveFlags { x }
+        | Instruction::LoadFlags { x } => 14 + 14 * (u64::from(x) + 1),
+        // Variants ran on their own interpreters, charged like the nearest VIP instruction
+        Instruction::Halt | Instruction::Nop | Instruction::NextBackground => 10,
+        Instruction::WaitDelay | Instruction::DelayWait { .. } => 10,
+        Instruction::SkipAlways | Instruction::SkipBytes { .. } => 12,
+        Instruction::JumpBack { .. } | Instruction::JumpForward { .. } => 12,
+        Instruction::SkipGtReg { x, y } => skip(v(x) > v(y)) + 4,
+        Instruction::SkipKey2 { .. } => skip(false) + 4,
+        Instruction::SkipNotKey2 { .. } => skip(true) + 4,
+        Instruction::StoreRange { x, y } | Instruction::LoadRange { x, y } => {
+            14 + 14 * u64::from((y + 1).saturating_sub(x))
+        }
+        Instruction::AddNibbles { .. } | Instruction::Color { .. } => 44,
+        Instruction::Output { .. }
+        | Instruction::Tone { .. }
+        | Instruction::InputStrobe { .. }
+        | Instruction::Input { .. }
+        | Instruction::PortInput { .. } => 10,
+        // MEGA-CHIP never ran on the VIP, nothing to go by
+        Instruction::MegaOff
+        | Instruction::MegaOn
+        | Instruction::LongIndex { .. }
+        | Instruction::LoadPalette { .. }
+        | Instruction::SpriteWidth { .. }
+        | Instruction::SpriteHeight { .. }
+        | Instruction::PlaySound { .. }
+        | Instruction::StopSound
+        | Instruction::BlendMode { .. }
+        | Instruction::CollisionColor { .. } => 10,
+        Instruction::Unknown(_) => 0,
+    };
+    FETCH_CYCLES + execute
+}
+
+// Machine cycles left until the next frame interrupt
+pub fn until_frame(machine_cycles: u64) -> u64 {
+    FRAME_CYCLES - machine_cycles % FRAME_CYCLES
+}
+
+// Clock after the interpreter spent cycles, the display takes its share of every frame begun
+pub fn advance(machine_cycles: u64, cycles: u64) -> u64 {
+    let mut end = machine_cycles + cycles;
+    let mut frame = machine_cycles / FRAME_CYCLES;
+    while end / FRAME_CYCLES > frame {
+        frame += 1;
+        end += DISPLAY_CYCLES + INTERRUPT_CYCLES;
+    }
+    end
+}
+
+// Frame interrupts between two points of the clock
+pub fn frames_between(start: u64, end: u64) -> u64 {
+    end / FRAME_CYCLES - start / FRAME_CYCLES
+}
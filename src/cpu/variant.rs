@@ -0,0 +1,164 @@
+use super::megachip;
+use crate::instruction::{decode, Instruction};
+use serde::Deserialize;
+
+/* Historical CHIP-8 dialects, each an extension of the COSMAC VIP instruction set.
+
+    CHIP-8X ran on the VIP with the VP-590 colour board and VP-595 sound board. Programs
+    start at 0x300 behind its interpreter. The screen is split into zones of 8 pixels
+    wide that each have their own foreground colour, set with BXYN, on one of four
+    background colours cycled through with 02A0. BNNN is gone to make room for that.
+    CHIP-8E adds comparisons, register ranges, relative branches and port access.
+    HIRES CHIP-8 draws on 64x64 pixels. Its ROMs carry a patch to the VIP interpreter in
+    front of the program, entered with 1260 and hooking the display routine at 0x244.
+    We do the patch's job natively, so 1260 jumps straight to the program at 0x2C0 and
+    0230 clears the larger screen. CHIP-10 draws on 128x64 pixels and changes nothing
+    else. MEGA-CHIP brings a colour screen, sampled sound and 16 MB of memory, see
+    cpu::megachip. Plain CHIP-8 is the VIP instruction set plus the SUPER-CHIP resolution
+    switch (00FE, 00FF) and RPL flags (FX75, FX85). Nothing else from SUPER-CHIP or
+    XO-CHIP is decoded (no scrolling, DXY0 draws nothing, F000 and 5XY2 are unknown
+    opcodes), so programs relying on them do not run.
+
+    Nothing is attached to the ports, inputs read 0 and outputs go nowhere.
+*/
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    #[default]
+    Chip8,
+    Chip8X,
+    Chip8E,
+    Hires,
+    Chip10,
+    MegaChip,
+}
+
+// VP-590 colours as RGB, foreground indexes are the BXYN colour numbers
+pub const CHIP8X_FOREGROUNDS: [(u8, u8, u8); 8] = [
+    (0x00, 0x00, 0x00), // black
+    (0xFF, 0x00, 0x00), // red
+    (0x00, 0x00, 0xFF), // blue
+    (0xFF, 0x00, 0xFF), // violet
+    (0x00, 0xFF, 0x00), // green
+    (0xFF, 0xFF, 0x00), // yellow
+    (0x00, 0xFF, 0xFF), // aqua
+    (0xFF, 0xFF, 0xFF), // white
+];
+pub const CHIP8X_BACKGROUNDS: [(u8, u8, u8); 4] = [
+    (0x00, 0x00, 0x80), // dark blue, after reset
+    (0x00, 0x00, 0x00), // black
+    (0x00, 0x80, 0x00), // green
+    (0x80, 0x00, 0x00), // red
+];
+pub const CHIP8X_DEFAULT_COLOR: u8 = 1; // red
+pub const COLOR_ZONE_WIDTH: usize = 8;
+pub const COLOR_ZONE_HEIGHT: usize = 4; // BXY0 colours blocks of 4 rows, BXYN single rows
+
+impl Variant {
+    pub fn from_name(name: &str) -> Option<Variant> {
+        match name {
+            "chip8" => Some(Variant::Chip8),
+            "chip8x" => Some(Variant::Chip8X),
+            "chip8e" => Some(Variant::Chip8E),
+            "hires" => Some(Variant::Hires),
+            "chip10" => Some(Variant::Chip10),
+            "megachip" => Some(Variant::MegaChip),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Variant::Chip8 => "CHIP-8",
+            Variant::Chip8X => "CHIP-8X",
+            Variant::Chip8E => "CHIP-8E",
+            Variant::Hires => "HIRES CHIP-8",
+            Variant::Chip10 => "CHIP-10",
+            Variant::MegaChip => "MEGA-CHIP",
+        }
+    }
+
+    // Display size after reset, width and height
+    pub fn resolution(&self) -> (usize, usize) {
+        match self {
+            Variant::Hires => (64, 64),
+            Variant::Chip10 => (128, 64),
+            _ => (64, 32),
+        }
+    }
+
+    // Largest display the variant switches to, the window is made to fit it
+    pub fn window_resolution(&self) -> (usize, usize) {
+        match self {
+            Variant::MegaChip => (megachip::WIDTH, megachip::HEIGHT),
+            _ => self.resolution(),
+        }
+    }
+
+    // Where programs are loaded and start
+    pub fn start_address(&self) -> u16 {
+        match self {
+            Variant::Chip8X => 0x300,
+            _ => 0x200,
+        }
+    }
+
+    // Opcodes of the variant first, everything else decodes as plain CHIP-8
+    pub fn decode(&self, op: u16) -> Instruction {
+        let x = ((op & 0x0F00) >> 8) as u8;
+        let y = ((op & 0x00F0) >> 4) as u8;
+        let n = (op & 0x000F) as u8;
+        let nn = (op & 0x00FF) as u8;
+
+        let instruction = match self {
+            Variant::Chip8 | Variant::Chip10 => None,
+            Variant::Hires => match op {
+                0x1260 => Some(Instruction::Jump { nnn: 0x2C0 }),
+                0x0230 => Some(Instruction::Cls),
+                _ => None,
+            },
+            Variant::Chip8X => match (op & 0xF000, n, nn) {
+                _ if op == 0x02A0 => Some(Instruction::NextBackground),
+                (0x5000, 0x1, _) => Some(Instruction::AddNibbles { x, y }),
+                (0xB000, _, _) => Some(Instruction::Color { x, y, n }),
+                (0xE000, _, 0xF2) => Some(Instruction::SkipKey2 { x }),
+                (0xE000, _, 0xF5) => Some(Instruction::SkipNotKey2 { x }),
+                (0xF000, _, 0xF8) => Some(Instruction::Tone { x }),
+                (0xF000, _, 0xFB) => Some(Instruction::PortInput { x }),
+                _ => None,
+            },
+            Variant::Chip8E => match (op & 0xF000, n, nn) {
+                _ if op == 0x00ED => Some(Instruction::Halt),
+                _ if op == 0x00F2 => Some(Instruction::Nop),
+                _ if op == 0x0151 => Some(Instruction::WaitDelay),
+                _ if op == 0x0188 => Some(Instruction::SkipAlways),
+                (0x5000, 0x1, _) => Some(Instruction::SkipGtReg { x, y }),
+                (0x5000, 0x2, _) => Some(Instruction::StoreRange { x, y }),
+                (0x5000, 0x3, _) => Some(Instruction::LoadRange { x, y }),
+                (0xB000, _, _) if x == 0xB => Some(Instruction::JumpBack { nn }),
+                (0xB000, _, _) if x == 0xF => Some(Instruction::JumpForward { nn }),
+                (0xF000, _, 0x03) => Some(Instruction::Output { x }),
+                (0xF000, _, 0x1B) => Some(Instruction::SkipBytes { x }),
+                (0xF000, _, 0x4F) => Some(Instruction::DelayWait { x }),
+                (0xF000, _, 0xE3) => Some(Instruction::InputStrobe { x }),
+                (0xF000, _, 0xE7) => Some(Instruction::Input { x }),
+                _ => None,
+            },
+            Variant::MegaChip => match (op & 0xFF00, n, nn) {
+                _ if op == 0x0010 => Some(Instruction::MegaOff),
+                _ if op == 0x0011 => Some(Instruction::MegaOn),
+                _ if op == 0x0700 => Some(Instruction::StopSound),
+                (0x0100, _, _) => Some(Instruction::LongIndex { nn }),
+                (0x0200, _, _) => Some(Instruction::LoadPalette { nn }),
+                (0x0300, _, _) => Some(Instruction::SpriteWidth { nn }),
+                (0x0400, _, _) => Some(Instruction::SpriteHeight { nn }),
+                (0x0600, _, _) if y == 0 => Some(Instruction::PlaySound { n }),
+                (0x0800, _, _) if y == 0 => Some(Instruction::BlendMode { n }),
+                (0x0900, _, _) => Some(Instruction::CollisionColor { nn }),
+                _ => None,
+            },
+        };
+        instruction.unwrap_or_else(|| decode(op))
+    }
+}
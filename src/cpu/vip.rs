@@ -0,0 +1,465 @@
+use serde::{Deserialize, Serialize};
+
+/* COSMAC VIP, the machine CHIP-8 was written for: an RCA CDP1802 CPU, a CDP1861 video
+    chip, a hex keypad and 4 KB of RAM, with the 512 byte monitor ROM at 8000.
+
+    Instead of running CHIP-8 instructions itself, the machine runs the original CHIP-8
+    interpreter (512 bytes, loaded at 0000 below the program at 0200) on an emulated
+    1802. Both ROM images have to be supplied by the user. Everything the interpreter
+    does comes out exactly as on the VIP, 0NNN machine code subroutines included.
+
+    After a reset the monitor ROM also answers at 0000 until the CPU first puts an
+    address above 8000 on the bus, which is how the VIP boots into the monitor. The
+    monitor checks for key C (held: monitor mode), sets up the machine and jumps to the
+    interpreter at 0000. The interpreter then relies on the monitor's interrupt routine.
+
+    Timing is counted in machine cycles (8 clocks): 2 per instruction, 3 for long
+    branches and skips, 1 per DMA byte and 1 to take an interrupt. The 1861 shows 262
+    lines of 14 machine cycles each per frame. Two lines before the display starts it
+    interrupts the CPU, then fetches 8 bytes per line by DMA through R0 for 128 lines.
+    EF1 is set during the 4 lines before the display and the last 4 lines of it.
+
+    I/O on the VIP: OUT 1 turns the display off, INP 1 turns it on, OUT 2 latches the
+    key to scan and EF3 tells whether that key is down. Q drives the tone generator.
+*/
+
+pub const ROM_SIZE: usize = 0x200;
+pub const LINE_CYCLES: u64 = 14;
+const LINES: u64 = 262;
+const INTERRUPT_LINE: u64 = 62;
+const DISPLAY_START: u64 = 64;
+const DISPLAY_END: u64 = 192;
+const DMA_BYTES: u16 = 8;
+const LDA_R5: u8 = 0x45; // how the interpreter fetches CHIP-8 instructions
+
+// CDP1802 registers
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cdp1802 {
+    pub r: [u16; 16], // scratchpad registers, R(P) is the program counter
+    pub p: u8,
+    pub x: u8,
+    pub d: u8,
+    pub df: bool,
+    pub ie: bool,
+    pub t: u8, // X and P saved by an interrupt
+    pub q: bool,
+    pub idle: bool, // IDL, waiting for DMA or an interrupt
+}
+
+// Everything that changes while running, for save states
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VipState {
+    pub cpu: Cdp1802,
+    rom_at_zero: bool,
+    display_on: bool,
+    key_latch: u8,
+    interrupted_frame: Option<u64>,
+    dma_line: Option<u64>, // lines counted since power on
+}
+
+#[derive(Clone)]
+pub struct Vip {
+    state: VipState,
+    monitor: Vec<u8>,
+    interpreter: Vec<u8>,
+}
+
+impl Vip {
+    pub fn new(monitor: &[u8], interpreter: &[u8]) -> Result<Vip, String> {
+        if monitor.len() != ROM_SIZE {
+            return Err(format!(
+                "VIP monitor ROM must be {} bytes, not {}",
+                ROM_SIZE,
+                monitor.len()
+            ));
+        }
+        if interpreter.is_empty() || interpreter.len() > ROM_SIZE {
+            return Err(format!(
+                "CHIP-8 interpreter must be 1 to {} bytes, not {}",
+                ROM_SIZE,
+                interpreter.len()
+            ));
+        }
+        let mut vip = Vip {
+            state: VipState::default(),
+            monitor: monitor.to_vec(),
+            interpreter: interpreter.to_vec(),
+        };
+        vip.reset();
+        Ok(vip)
+    }
+
+    // Reset switch: the CPU starts at 0000 with interrupts on, seeing the monitor there
+    pub fn reset(&mut self) {
+        self.state = VipState {
+            cpu: Cdp1802 {
+                ie: true,
+                ..Cdp1802::default()
+            },
+            rom_at_zero: true,
+            ..VipState::default()
+        };
+    }
+
+    // Put the interpreter in front of the program, like loading it from tape
+    pub fn load_interpreter(&self, ram: &mut [u8; 4096]) {
+        ram[..self.interpreter.len()].copy_from_slice(&self.interpreter);
+    }
+
+    pub fn get_state(&self) -> &VipState {
+        &self.state
+    }
+
+    pub fn set_state(&mut self, state: VipState) {
+        self.state = state;
+    }
+
+    pub fn get_cpu(&self) -> &Cdp1802 {
+        &self.state.cpu
+    }
+
+    pub fn is_sound_on(&self) -> bool {
+        self.state.cpu.q
+    }
+
+    // Opcode the CPU runs next, once it is not idle
+    pub fn peek(&self, ram: &[u8; 4096]) -> u8 {
+        let cpu = &self.state.cpu;
+        self.peek_at(ram, cpu.r[cpu.p as usize])
+    }
+
+    // Byte the next LDA R5 would load, the interpreter's next CHIP-8 byte
+    pub fn peek_r5(&self, ram: &[u8; 4096]) -> u8 {
+        self.peek_at(ram, self.state.cpu.r[5])
+    }
+
+    pub fn is_fetching(&self, ram: &[u8; 4096]) -> bool {
+        !self.state.cpu.idle && self.peek(ram) == LDA_R5
+    }
+
+    fn peek_at(&self, ram: &[u8; 4096], address: u16) -> u8 {
+        if address & 0x8000 != 0 || self.state.rom_at_zero {
+            self.monitor[address as usize % ROM_SIZE]
+        } else {
+            ram[address as usize % ram.len()]
+        }
+    }
+
+    fn read(&mut self, ram: &[u8; 4096], address: u16) -> u8 {
+        let value = self.peek_at(ram, address);
+        if address & 0x8000 != 0 {
+            self.state.rom_at_zero = false;
+        }
+        value
+    }
+
+    // RAM is mirrored below 8000, writes to the ROM go nowhere
+    fn write(&mut self, ram: &mut [u8; 4096], address: u16, value: u8) {
+        if address & 0x8000 != 0 {
+            self.state.rom_at_zero = false;
+        } else {
+            ram[address as usize % ram.len()] = value;
+        }
+    }
+
+    /* Run one instruction after any DMA and interrupt that are due, clock is in machine
+        cycles since power on. disp_buff gets the lines fetched by DMA, 4 per row as the
+        CHIP-8 interrupt routine repeats them.
+    */
+    pub fn step(
+        &mut self,
+        ram: &mut [u8; 4096],
+        keys: &[bool; 16],
+        disp_buff: &mut [Vec<bool>],
+        clock: &mut u64,
+    ) {
+        self.video(ram, disp_buff, clock);
+        if self.state.cpu.idle {
+            *clock += 1;
+            return;
+        }
+        *clock += self.execute(ram, keys, disp_buff, *clock);
+    }
+
+    fn video(&mut self, ram: &[u8; 4096], disp_buff: &mut [Vec<bool>], clock: &mut u64) {
+        if !self.state.display_on {
+            return;
+        }
+        let line = *clock / LINE_CYCLES;
+        let frame = line / LINES;
+        let frame_line = line % LINES;
+
+        if (INTERRUPT_LINE..DISPLAY_START).contains(&frame_line)
+            && self.state.cpu.ie
+            && self.state.interrupted_frame != Some(frame)
+        {
+            let cpu = &mut self.state.cpu;
+            cpu.t = cpu.x << 4 | cpu.p;
+            cpu.p = 1;
+            cpu.x = 2;
+            cpu.ie = false;
+            cpu.idle = false;
+            self.state.interrupted_frame = Some(frame);
+            *clock += 1;
+        }
+
+        if (DISPLAY_START..DISPLAY_END).contains(&frame_line) && self.state.dma_line != Some(line) {
+            let rows = disp_buff.len() as u64;
+            let row =
+                ((frame_line - DISPLAY_START) * rows / (DISPLAY_END - DISPLAY_START)) as usize;
+            for byte in 0..DMA_BYTES {
+                let value = self.read(ram, self.state.cpu.r[0]);
+                self.state.cpu.r[0] = self.state.cpu.r[0].wrapping_add(1);
+                for bit in 0..8 {
+                    let x = (byte * 8 + bit) as usize;
+                    if let Some(pixel) = disp_buff.get_mut(row).and_then(|row| row.get_mut(x)) {
+                        *pixel = value & (0x80 >> bit) != 0;
+                    }
+                }
+            }
+            self.state.cpu.idle = false;
+            self.state.dma_line = Some(line);
+            *clock += DMA_BYTES as u64;
+        }
+    }
+
+    fn flag(&self, n: usize, keys: &[bool; 16], clock: u64) -> bool {
+        let frame_line = clock / LINE_CYCLES % LINES;
+        match n {
+            // EF1, display status from the 1861
+            1 => {
+                self.state.display_on
+                    && ((DISPLAY_START - 4..DISPLAY_START).contains(&frame_line)
+                        || (DISPLAY_END - 4..DISPLAY_END).contains(&frame_line))
+            }
+            // EF3, latched key is down
+            3 => keys[self.state.key_latch as usize],
+            _ => false, // EF2 tape in, EF4 unused
+        }
+    }
+
+    // Returns machine cycles taken
+    fn execute(
+        &mut self,
+        ram: &mut [u8; 4096],
+        keys: &[bool; 16],
+        disp_buff: &mut [Vec<bool>],
+        clock: u64,
+    ) -> u64 {
+        let p = self.state.cpu.p as usize;
+        let opcode = self.read(ram, self.state.cpu.r[p]);
+        self.state.cpu.r[p] = self.state.cpu.r[p].wrapping_add(1);
+        let n = (opcode & 0xF) as usize;
+        let x = self.state.cpu.x as usize;
+
+        match opcode >> 4 {
+            // IDL / LDN
+            0x0 if n == 0 => self.state.cpu.idle = true,
+            0x0 => self.state.cpu.d = self.read(ram, self.state.cpu.r[n]),
+            // INC, DEC
+            0x1 => self.state.cpu.r[n] = self.state.cpu.r[n].wrapping_add(1),
+            0x2 => self.state.cpu.r[n] = self.state.cpu.r[n].wrapping_sub(1),
+            // Short branches, the upper half branches when the condition is false
+            0x3 => {
+                let condition = match n & 7 {
+                    0 => true,
+                    1 => self.state.cpu.q,
+                    2 => self.state.cpu.d == 0,
+                    3 => self.state.cpu.df,
+                    ef => self.flag(ef - 3, keys, clock),
+                };
+                let target = self.read(ram, self.state.cpu.r[p]);
+                if condition != (n & 8 != 0) {
+                    self.state.cpu.r[p] = self.state.cpu.r[p] & 0xFF00 | target as u16;
+                } else {
+                    self.state.cpu.r[p] = self.state.cpu.r[p].wrapping_add(1);
+                }
+            }
+            // LDA, STR
+            0x4 => {
+                self.state.cpu.d = self.read(ram, self.state.cpu.r[n]);
+                self.state.cpu.r[n] = self.state.cpu.r[n].wrapping_add(1);
+            }
+            0x5 => self.write(ram, self.state.cpu.r[n], self.state.cpu.d),
+            // IRX, OUT, INP
+            0x6 => match n {
+                0 => self.state.cpu.r[x] = self.state.cpu.r[x].wrapping_add(1),
+                1..=7 => {
+                    let value = self.read(ram, self.state.cpu.r[x]);
+                    self.state.cpu.r[x] = self.state.cpu.r[x].wrapping_add(1);
+                    self.output(n, value, disp_buff);
+                }
+                8 => {}
+                _ => {
+                    if n == 9 {
+                        self.state.display_on = true;
+                    }
+                    // Nothing drives the bus, it reads as 0
+                    self.write(ram, self.state.cpu.r[x], 0);
+                    self.state.cpu.d = 0;
+                }
+            },
+            0x7 => match n {
+                // RET, DIS
+                0 | 1 => {
+                    let value = self.read(ram, self.state.cpu.r[x]);
+                    let cpu = &mut self.state.cpu;
+                    cpu.r[x] = cpu.r[x].wrapping_add(1);
+                    cpu.x = value >> 4;
+                    cpu.p = value & 0xF;
+                    cpu.ie = n == 0;
+                }
+                // LDXA, STXD
+                2 => {
+                    self.state.cpu.d = self.read(ram, self.state.cpu.r[x]);
+                    self.state.cpu.r[x] = self.state.cpu.r[x].wrapping_add(1);
+                }
+                3 => {
+                    self.write(ram, self.state.cpu.r[x], self.state.cpu.d);
+                    self.state.cpu.r[x] = self.state.cpu.r[x].wrapping_sub(1);
+                }
+                // SHRC, SHLC
+                6 => {
+                    let cpu = &mut self.state.cpu;
+                    let carry = cpu.d & 1 != 0;
+                    cpu.d = cpu.d >> 1 | (cpu.df as u8) << 7;
+                    cpu.df = carry;
+                }
+                0xE => {
+                    let cpu = &mut self.state.cpu;
+                    let carry = cpu.d & 0x80 != 0;
+                    cpu.d = cpu.d << 1 | cpu.df as u8;
+                    cpu.df = carry;
+                }
+                // SAV, MARK
+                8 => self.write(ram, self.state.cpu.r[x], self.state.cpu.t),
+                9 => {
+                    let cpu = &mut self.state.cpu;
+                    cpu.t = cpu.x << 4 | cpu.p;
+                    let (address, value) = (cpu.r[2], cpu.t);
+                    cpu.x = cpu.p;
+                    cpu.r[2] = cpu.r[2].wrapping_sub(1);
+                    self.write(ram, address, value);
+                }
+                // REQ, SEQ
+                0xA => self.state.cpu.q = false,
+                0xB => self.state.cpu.q = true,
+                // ADC, SDB, SMB and their immediate forms
+                _ => {
+                    let operand = self.operand(ram, n);
+                    let cpu = &mut self.state.cpu;
+                    match n & 3 {
+                        0 => cpu.add(cpu.d, operand, cpu.df),
+                        1 => cpu.add(operand, !cpu.d, cpu.df),
+                        _ => cpu.add(cpu.d, !operand, cpu.df),
+                    }
+                }
+            },
+            // GLO, GHI, PLO, PHI
+            0x8 => self.state.cpu.d = self.state.cpu.r[n] as u8,
+            0x9 => self.state.cpu.d = (self.state.cpu.r[n] >> 8) as u8,
+            0xA => {
+                let cpu = &mut self.state.cpu;
+                cpu.r[n] = cpu.r[n] & 0xFF00 | cpu.d as u16;
+            }
+            0xB => {
+                let cpu = &mut self.state.cpu;
+                cpu.r[n] = cpu.r[n] & 0x00FF | (cpu.d as u16) << 8;
+            }
+            // Long branches and skips, one cycle longer than everything else
+            0xC => {
+                let cpu = &self.state.cpu;
+                let condition = match n & 3 {
+                    0 => n != 0xC || cpu.ie, // LBR, LSKP, LSIE (and NOP below)
+                    1 => cpu.q,
+                    2 => cpu.d == 0,
+                    _ => cpu.df,
+                };
+                let pc = cpu.r[p];
+                match n {
+                    0x4 => {}
+                    // LBR LBQ LBZ LBDF, LBNQ LBNZ LBNF
+                    0x0..=0x3 | 0x9..=0xB => {
+                        if condition == (n < 8) {
+                            let high = self.read(ram, pc);
+                            let low = self.read(ram, pc.wrapping_add(1));
+                            self.state.cpu.r[p] = u16::from_be_bytes([high, low]);
+                        } else {
+                            self.state.cpu.r[p] = pc.wrapping_add(2);
+                        }
+                    }
+                    // LSNQ LSNZ LSNF LSKP skip when false, LSIE LSQ LSZ LSDF when true
+                    _ => {
+                        if condition == (n >= 0xC || n == 0x8) {
+                            self.state.cpu.r[p] = pc.wrapping_add(2);
+                        }
+                    }
+                }
+                return 3;
+            }
+            // SEP, SEX
+            0xD => self.state.cpu.p = n as u8,
+            0xE => self.state.cpu.x = n as u8,
+            // ALU with M(R(X)) or, from F8 on, an immediate byte
+            _ => match n {
+                0x0 => self.state.cpu.d = self.read(ram, self.state.cpu.r[x]),
+                0x6 => {
+                    let cpu = &mut self.state.cpu;
+                    cpu.df = cpu.d & 1 != 0;
+                    cpu.d >>= 1;
+                }
+                0xE => {
+                    let cpu = &mut self.state.cpu;
+                    cpu.df = cpu.d & 0x80 != 0;
+                    cpu.d <<= 1;
+                }
+                _ => {
+                    let operand = self.operand(ram, n);
+                    let cpu = &mut self.state.cpu;
+                    match n & 7 {
+                        0 => cpu.d = operand, // LDI
+                        1 => cpu.d |= operand,
+                        2 => cpu.d &= operand,
+                        3 => cpu.d ^= operand,
+                        4 => cpu.add(cpu.d, operand, false),
+                        5 => cpu.add(operand, !cpu.d, true),
+                        _ => cpu.add(cpu.d, !operand, true),
+                    }
+                }
+            },
+        }
+        2
+    }
+
+    // M(R(X)) for the lower half of the ALU opcodes, the byte after the opcode for the upper
+    fn operand(&mut self, ram: &[u8; 4096], n: usize) -> u8 {
+        let (p, x) = (self.state.cpu.p as usize, self.state.cpu.x as usize);
+        if n & 8 != 0 {
+            let pc = self.state.cpu.r[p];
+            self.state.cpu.r[p] = pc.wrapping_add(1);
+            self.read(ram, pc)
+        } else {
+            self.read(ram, self.state.cpu.r[x])
+        }
+    }
+
+    fn output(&mut self, port: usize, value: u8, disp_buff: &mut [Vec<bool>]) {
+        match port {
+            1 => {
+                self.state.display_on = false;
+                disp_buff.iter_mut().for_each(|row| row.fill(false));
+            }
+            2 => self.state.key_latch = value & 0xF,
+            _ => {}
+        }
+    }
+}
+
+impl Cdp1802 {
+    // Subtractions add the complement, DF ends up as carry, so 1 means no borrow
+    fn add(&mut self, a: u8, b: u8, carry: bool) {
+        let sum = a as u16 + b as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+}
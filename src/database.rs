@@ -0,0 +1,231 @@
+use crate::analyzer::{self, Confidence};
+use crate::config::{Palette, Profile};
+use crate::cpu::Quirks;
+use crate::platform::Platform;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/* Offline ROM database, same JSON layout as the community chip-8-database:
+    programs.json     - list of programs, each with its known ROM images keyed by SHA-1
+    sha1-hashes.json  - SHA-1 -> index into programs.json
+*/
+const PROGRAMS_JSON: &str = include_str!("../database/programs.json");
+const HASHES_JSON: &str = include_str!("../database/sha1-hashes.json");
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    authors: Vec<String>,
+    release: Option<String>,
+    roms: HashMap<String, Rom>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rom {
+    #[serde(default)]
+    platforms: Vec<String>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, QuirkOverrides>,
+    tickrate: Option<u32>,
+    keys: Option<HashMap<String, u8>>,
+    colors: Option<Colors>,
+}
+
+// Quirk names used by the database, unset ones keep platform behaviour
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuirkOverrides {
+    shift: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    logic: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+struct Database {
+    programs: Vec<Program>,
+    hashes: HashMap<String, usize>,
+}
+
+fn bundled() -> &'static Database {
+    static DATABASE: OnceLock<Database> = OnceLock::new();
+    DATABASE.get_or_init(|| Database {
+        programs: serde_json::from_str(PROGRAMS_JSON).expect("Bundled programs.json error!"),
+        hashes: serde_json::from_str(HASHES_JSON).expect("Bundled sha1-hashes.json error!"),
+    })
+}
+
+// What we know about a loaded ROM, either from the database or guessed
+#[derive(Clone, Debug)]
+pub struct RomInfo {
+    pub sha1: String,
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    pub release: Option<String>,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub tickrate: u32,
+    pub keymap: Option<HashMap<String, String>>,
+    pub palette: Option<Palette>,
+    pub known: bool,            // found in database
+    pub confidence: Confidence, // how sure we are about the platform
+}
+
+impl RomInfo {
+    // Settings the database has for this ROM, as a config profile layer
+    pub fn profile(&self) -> Profile {
+        Profile {
+            cpu_freq: self.known.then_some(self.tickrate * 60),
+            quirks: self.known.then_some(self.quirks),
+            keymap: self.keymap.clone(),
+            palette: self.palette.clone(),
+            ..Profile::default()
+        }
+    }
+
+    /* Quirks of the platform guessed for an unknown ROM. A guess is weaker than the config
+        defaults, and its speed is left alone so unknown ROMs keep the default one.
+     */
+    pub fn guessed_profile(&self) -> Profile {
+        Profile {
+            quirks: (!self.known).then_some(self.quirks),
+            ..Profile::default()
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let mut description = self
+            .title
+            .clone()
+            .unwrap_or_else(|| "Unknown ROM".to_string());
+        if !self.authors.is_empty() {
+            description.push_str(&format!(" by {}", self.authors.join(", ")));
+        }
+        if let Some(release) = &self.release {
+            description.push_str(&format!(" ({})", release));
+        }
+        description
+    }
+}
+
+pub fn sha1_hex(data: &[u8]) -> String {
+    Sha1::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn lookup(rom: &[u8]) -> RomInfo {
+    let sha1 = sha1_hex(rom);
+    let database = bundled();
+    let found = database
+        .hashes
+        .get(&sha1)
+        .and_then(|&idx| database.programs.get(idx))
+        .and_then(|program| program.roms.get(&sha1).map(|entry| (program, entry)));
+
+    let (program, entry) = match found {
+        Some(found) => found,
+        None => return guess(sha1, rom),
+    };
+
+    // First platform we can emulate wins
+    let platform = entry
+        .platforms
+        .iter()
+        .find_map(|id| Platform::from_id(id))
+        .unwrap_or_else(|| analyzer::analyze(rom).platform);
+
+    let mut quirks = platform.quirks();
+    if let Some(overrides) = entry.quirky_platforms.get(platform.id()) {
+        apply_overrides(&mut quirks, overrides);
+    }
+
+    RomInfo {
+        sha1,
+        title: Some(program.title.clone()),
+        authors: program.authors.clone(),
+        release: program.release.clone(),
+        platform,
+        quirks,
+        tickrate: entry.tickrate.unwrap_or_else(|| platform.tickrate()),
+        keymap: entry.keys.as_ref().map(keymap_from_keys),
+        palette: entry.colors.as_ref().and_then(palette_from_colors),
+        known: true,
+        confidence: Confidence::High,
+    }
+}
+
+fn guess(sha1: String, rom: &[u8]) -> RomInfo {
+    let analysis = analyzer::analyze(rom);
+    RomInfo {
+        sha1,
+        title: None,
+        authors: Vec::new(),
+        release: None,
+        platform: analysis.platform,
+        quirks: analysis.quirks,
+        tickrate: analysis.platform.tickrate(),
+        keymap: None,
+        palette: None,
+        known: false,
+        confidence: analysis.confidence,
+    }
+}
+
+fn apply_overrides(quirks: &mut Quirks, overrides: &QuirkOverrides) {
+    // Database quirks describe the newer behaviour, ours the older one for shift and load/store
+    if let Some(shift) = overrides.shift {
+        quirks.shift_vy = !shift;
+    }
+    if let Some(leave_i) = overrides.memory_leave_i_unchanged {
+        quirks.load_store_inc_i = !leave_i;
+    }
+    if let Some(wrap) = overrides.wrap {
+        quirks.wrap_sprites = wrap;
+    }
+    if let Some(jump) = overrides.jump {
+        quirks.jump_vx = jump;
+    }
+    if let Some(logic) = overrides.logic {
+        quirks.vf_reset = logic;
+    }
+}
+
+// Database names game actions, we bind them to keys most players would expect
+fn keymap_from_keys(keys: &HashMap<String, u8>) -> HashMap<String, String> {
+    keys.iter()
+        .filter_map(|(action, &key)| {
+            let key_name = match action.as_str() {
+                "up" => "Up",
+                "down" => "Down",
+                "left" => "Left",
+                "right" => "Right",
+                "a" => "Space",
+                "b" => "Left Shift",
+                _ => return None,
+            };
+            Some((format!("{:X}", key), key_name.to_string()))
+        })
+        .collect()
+}
+
+fn palette_from_colors(colors: &Colors) -> Option<Palette> {
+    match colors.pixels.as_slice() {
+        [background, foreground, ..] => Some(Palette {
+            background: background.clone(),
+            foreground: foreground.clone(),
+        }),
+        _ => None,
+    }
+}
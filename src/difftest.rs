@@ -0,0 +1,305 @@
+use crate::cpu::{Quirks, YARCH8};
+use crate::reference::Reference;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+/* Differential testing: run YARCH8 and the reference model in lockstep and compare
+    registers, stack, timers, RAM and display after every instruction.
+
+    Both get the same ROM, quirks, seed (so CXNN draws the same numbers) and key presses.
+    When both refuse an instruction (both fault) the run simply ends there, only one of
+    them refusing counts as a divergence.
+*/
+
+const TIMER_FREQ: u32 = 60;
+const MAX_ROM_SIZE: usize = 0x1000 - 0x200;
+
+// How a run ended when no divergence was found
+#[derive(Debug)]
+pub struct Agreement {
+    pub cycles: u64,           // instructions both ran
+    pub fault: Option<String>, // reason both stopped early, if they did
+}
+
+#[derive(Debug)]
+pub struct Divergence {
+    pub cycle: u64, // instructions run before the one that diverged
+    pub pc: usize,
+    pub opcode: Option<u16>,
+    pub differences: Vec<String>,
+    pub yarch8: String,
+    pub reference: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let opcode = self
+            .opcode
+            .map_or("----".to_string(), |op| format!("{:04X}", op));
+        writeln!(
+            f,
+            "Diverged at instruction {} (PC {:#05X}, opcode {})",
+            self.cycle, self.pc, opcode
+        )?;
+        for difference in self.differences.iter() {
+            writeln!(f, "  {}", difference)?;
+        }
+        writeln!(f, "YARCH8:    {}", self.yarch8)?;
+        write!(f, "Reference: {}", self.reference)
+    }
+}
+
+pub fn run(
+    rom: &[u8],
+    quirks: Quirks,
+    cpu_freq: u32,
+    seed: u64,
+    cycles: u64,
+) -> Result<Agreement, Divergence> {
+    let mut reference = Reference::new(rom, quirks, cpu_freq, TIMER_FREQ, seed);
+    if rom.len() > MAX_ROM_SIZE {
+        return Err(Divergence {
+            cycle: 0,
+            pc: reference.pc,
+            opcode: None,
+            differences: vec![format!(
+                "ROM is {} bytes, at most {} fit",
+                rom.len(),
+                MAX_ROM_SIZE
+            )],
+            yarch8: "not started".to_string(),
+            reference: "not started".to_string(),
+        });
+    }
+
+    let mut yarch8 = YARCH8::new(TIMER_FREQ, cpu_freq);
+    yarch8.load_bytes(rom);
+    yarch8.set_quirks(quirks);
+    yarch8.seed(seed);
+    yarch8.start();
+
+    // Keys go down and up at random points, same for both machines
+    let mut key_rng = StdRng::seed_from_u64(seed ^ 0x6B65_7973);
+    let mut next_key_change = 0;
+
+    for cycle in 0..cycles {
+        if cycle == next_key_change {
+            let key = key_rng.gen_range(0..16u8);
+            let pressed = key_rng.gen_bool(0.5);
+            if pressed {
+                yarch8.key_press(key);
+            } else {
+                yarch8.key_released(key);
+            }
+            reference.keys[key as usize] = pressed;
+            next_key_change += key_rng.gen_range(1..2000);
+        }
+
+        let pc = reference.pc;
+        let opcode = reference.opcode();
+        // YARCH8 should never panic, but if it does that is a divergence too
+        let ours =
+            panic::catch_unwind(AssertUnwindSafe(|| yarch8.step())).map(|_| yarch8.get_fault());
+        let theirs = reference.step();
+
+        let differences = match (ours, theirs) {
+            (Ok(None), Ok(())) => differences(&yarch8, &reference),
+            (Ok(Some(fault)), Err(_)) => {
+                return Ok(Agreement {
+                    cycles: cycle,
+                    fault: Some(fault.to_string()),
+                })
+            }
+            (Ok(Some(fault)), Ok(())) => vec![format!("YARCH8 stopped: {}", fault)],
+            (Ok(None), Err(fault)) => vec![format!("Reference faulted: {}", fault)],
+            (Err(payload), _) => vec![format!("YARCH8 panicked: {}", panic_message(&payload))],
+        };
+        if !differences.is_empty() {
+            return Err(Divergence {
+                cycle,
+                pc,
+                opcode,
+                differences,
+                yarch8: summary(
+                    yarch8.get_pc() as usize,
+                    yarch8.get_i() as usize,
+                    &(0..16).map(|idx| yarch8.get_v_reg(idx)).collect::<Vec<_>>(),
+                    yarch8.get_sp(),
+                    yarch8.get_delay_timer(),
+                    yarch8.get_sound_timer(),
+                ),
+                reference: summary(
+                    reference.pc,
+                    reference.i,
+                    &reference.v,
+                    reference.stack.len(),
+                    reference.delay_timer,
+                    reference.sound_timer,
+                ),
+            });
+        }
+    }
+    Ok(Agreement {
+        cycles,
+        fault: None,
+    })
+}
+
+fn differences(yarch8: &YARCH8, reference: &Reference) -> Vec<String> {
+    let mut differences = Vec::new();
+    if yarch8.get_pc() as usize != reference.pc {
+        differences.push(format!(
+            "PC {:#05X} vs {:#05X}",
+            yarch8.get_pc(),
+            reference.pc
+        ));
+    }
+    if yarch8.get_i() as usize != reference.i {
+        differences.push(format!("I {:#05X} vs {:#05X}", yarch8.get_i(), reference.i));
+    }
+    for idx in 0..16 {
+        if yarch8.get_v_reg(idx) != reference.v[idx] {
+            differences.push(format!(
+                "V{:X} {:#04X} vs {:#04X}",
+                idx,
+                yarch8.get_v_reg(idx),
+                reference.v[idx]
+            ));
+        }
+    }
+    let stack: Vec<usize> = yarch8.get_stack()[..yarch8.get_sp()]
+        .iter()
+        .map(|&address| address as usize)
+        .collect();
+    if stack != reference.stack {
+        differences.push(format!("Stack {:03X?} vs {:03X?}", stack, reference.stack));
+    }
+    if yarch8.get_delay_timer() != reference.delay_timer {
+        differences.push(format!(
+            "Delay timer {} vs {}",
+            yarch8.get_delay_timer(),
+            reference.delay_timer
+        ));
+    }
+    if yarch8.get_sound_timer() != reference.sound_timer {
+        differences.push(format!(
+            "Sound timer {} vs {}",
+            yarch8.get_sound_timer(),
+            reference.sound_timer
+        ));
+    }
+    let ram = yarch8.get_ram();
+    if ram[..] != reference.ram[..] {
+        let changed: Vec<String> = (0..ram.len())
+            .filter(|&address| ram[address] != reference.ram[address])
+            .take(4)
+            .map(|address| {
+                format!(
+                    "RAM[{:#05X}] {:#04X} vs {:#04X}",
+                    address, ram[address], reference.ram[address]
+                )
+            })
+            .collect();
+        differences.extend(changed);
+    }
+    if yarch8.get_rpl_flags() != &reference.rpl_flags {
+        differences.push(format!(
+            "RPL flags {:02X?} vs {:02X?}",
+            yarch8.get_rpl_flags(),
+            reference.rpl_flags
+        ));
+    }
+    if yarch8.get_disp_buff() != &reference.display[..] {
+        differences.push("Display differs".to_string());
+    }
+    differences
+}
+
+fn summary(pc: usize, i: usize, v: &[u8], sp: usize, delay: u8, sound: u8) -> String {
+    format!(
+        "PC {:#05X} I {:#05X} V {:02X?} SP {} DT {} ST {}",
+        pc, i, v, sp, delay, sound
+    )
+}
+
+fn panic_message(payload: &Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+// Random quirk combination, so every code path gets covered over many runs
+pub fn random_quirks(seed: u64) -> Quirks {
+    let mut rng = StdRng::seed_from_u64(seed);
+    Quirks {
+        shift_vy: rng.gen(),
+        load_store_inc_i: rng.gen(),
+        jump_vx: rng.gen(),
+        vf_reset: rng.gen(),
+        wrap_sprites: rng.gen(),
+        wait_key_release: rng.gen(),
+    }
+}
+
+/* Random program of valid instructions. Jumps and calls stay inside the program and
+    I mostly points at the font, the program or scratch memory above it, so runs get
+    somewhere before hitting something undefined.
+*/
+pub fn random_rom(seed: u64, instructions: usize) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let end = 0x200 + 2 * instructions as u16;
+    let mut rom = Vec::with_capacity(2 * instructions);
+
+    for _ in 0..instructions {
+        let x: u16 = rng.gen_range(0..16);
+        let y: u16 = rng.gen_range(0..16);
+        let nn: u16 = rng.gen_range(0..0x100);
+        let target = rng.gen_range(0x200..end) & !1;
+        let op = match rng.gen_range(0..32) {
+            0 => 0x00E0,
+            1 => [0x00FE, 0x00FF][rng.gen_range(0..2)],
+            2 => 0x00EE,
+            3 => 0x1000 | target,
+            4 => 0x2000 | target,
+            5 => 0x3000 | x << 8 | nn,
+            6 => 0x4000 | x << 8 | nn,
+            7 => 0x5000 | x << 8 | y << 4,
+            8 | 9 => 0x6000 | x << 8 | nn,
+            10 | 11 => 0x7000 | x << 8 | nn,
+            12..=15 => {
+                let n = [0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0xE][rng.gen_range(0..9)];
+                0x8000 | x << 8 | y << 4 | n
+            }
+            16 => 0x9000 | x << 8 | y << 4,
+            17 | 18 => {
+                let address = match rng.gen_range(0..3) {
+                    0 => rng.gen_range(0x50..0xA0),
+                    1 => rng.gen_range(0x200..end),
+                    _ => rng.gen_range(0xE00..0xF00),
+                };
+                0xA000 | address
+            }
+            19 => 0xB000 | (target - 0x10).max(0x200),
+            20 => 0xC000 | x << 8 | nn,
+            21 | 22 => 0xD000 | x << 8 | y << 4 | rng.gen_range(0..16),
+            23 => [0xE09E, 0xE0A1][rng.gen_range(0..2)] | x << 8,
+            24 => 0xF007 | x << 8,
+            25 => 0xF00A | x << 8,
+            26 => 0xF015 | x << 8,
+            27 => 0xF018 | x << 8,
+            28 => 0xF01E | x << 8,
+            29 => 0xF029 | x << 8,
+            30 => 0xF033 | x << 8,
+            31 if rng.gen_range(0..4) == 0 => [0xF075, 0xF085][rng.gen_range(0..2)] | x << 8,
+            _ => [0xF055, 0xF065][rng.gen_range(0..2)] | x << 8,
+        };
+        rom.extend_from_slice(&op.to_be_bytes());
+    }
+    rom
+}
@@ -0,0 +1,265 @@
+use crate::coverage::Coverage;
+use crate::cpu::jit::{Backend, Jit};
+use crate::cpu::YARCH8;
+
+/* Gym style environment for training agents, headless and deterministic.
+
+    reset(rom, seed) loads a ROM into a fresh machine and returns the first observation.
+    step(keys) holds the given keys for frame_skip frames and returns
+    (observation, reward, done). The observation is the display buffer. Episodes also
+    end when the machine faults.
+
+    Timers run on emulated cycles and CXNN uses the seeded generator, so the same
+    ROM, seed and key sequence always give the same results.
+*/
+
+pub type Observation = Vec<Vec<bool>>;
+
+// Turns machine state into rewards, usually by reading score bytes out of RAM
+pub trait Reward {
+    // Called after every reset, before the first step
+    fn reset(&mut self, _yarch8: &YARCH8) {}
+
+    // Reward earned since last call
+    fn reward(&mut self, yarch8: &YARCH8) -> f32;
+
+    // Whether the episode is over, e.g. no lives left
+    fn done(&mut self, _yarch8: &YARCH8) -> bool {
+        false
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ScoreFormat {
+    Bcd,       // one decimal digit per byte, most significant first, as FX33 writes it
+    BigEndian, // plain binary number
+}
+
+const RAM_SIZE: usize = 4096;
+
+// Reward is the increase of a score kept in RAM, optionally done when lives run out
+pub struct ScoreReward {
+    address: usize,
+    length: usize,
+    format: ScoreFormat,
+    lives_address: Option<usize>,
+    last_score: Option<u64>,
+}
+
+impl ScoreFormat {
+    // Longest score that still fits the u64 it is read into
+    fn max_length(&self) -> usize {
+        match self {
+            ScoreFormat::Bcd => 19,
+            ScoreFormat::BigEndian => 8,
+        }
+    }
+}
+
+impl ScoreReward {
+    // Score and lives have to lie in RAM, checked here so stepping can not fail later
+    pub fn new(address: usize, length: usize, format: ScoreFormat) -> Result<Self, String> {
+        if length == 0 || length > format.max_length() {
+            return Err(format!(
+                "Score length must be 1 to {} bytes",
+                format.max_length()
+            ));
+        }
+        if address.checked_add(length).is_none_or(|end| end > RAM_SIZE) {
+            return Err(format!("Score at {:#X} runs past the end of RAM", address));
+        }
+        Ok(ScoreReward {
+            address,
+            length,
+            format,
+            lives_address: None,
+            last_score: None,
+        })
+    }
+
+    pub fn with_lives(mut self, lives_address: usize) -> Result<Self, String> {
+        if lives_address >= RAM_SIZE {
+            return Err(format!(
+                "Lives at {:#X} are past the end of RAM",
+                lives_address
+            ));
+        }
+        self.lives_address = Some(lives_address);
+        Ok(self)
+    }
+
+    // None while the bytes are no BCD number, e.g. before the game first wrote its score
+    fn score(&self, yarch8: &YARCH8) -> Option<u64> {
+        let bytes = &yarch8.get_ram()[self.address..self.address + self.length];
+        match self.format {
+            ScoreFormat::Bcd => bytes.iter().try_fold(0, |score, &digit| {
+                (digit <= 9).then(|| score * 10 + u64::from(digit))
+            }),
+            ScoreFormat::BigEndian => Some(
+                bytes
+                    .iter()
+                    .fold(0, |score, &byte| (score << 8) | u64::from(byte)),
+            ),
+        }
+    }
+}
+
+impl Reward for ScoreReward {
+    fn reset(&mut self, yarch8: &YARCH8) {
+        self.last_score = self.score(yarch8);
+    }
+
+    // Nothing is earned across a score that could not be read
+    fn reward(&mut self, yarch8: &YARCH8) -> f32 {
+        let score = self.score(yarch8);
+        let reward = match (score, self.last_score) {
+            (Some(score), Some(last_score)) => score as f32 - last_score as f32,
+            _ => 0.0,
+        };
+        self.last_score = score;
+        reward
+    }
+
+    fn done(&mut self, yarch8: &YARCH8) -> bool {
+        self.lives_address
+            .is_some_and(|address| yarch8.get_ram()[address] == 0)
+    }
+}
+
+// For when only observations matter
+pub struct NoReward;
+
+impl Reward for NoReward {
+    fn reward(&mut self, _yarch8: &YARCH8) -> f32 {
+        0.0
+    }
+}
+
+pub struct Env {
+    yarch8: YARCH8,
+    reward: Box<dyn Reward>,
+    frame_skip: u32,
+    cycles_per_frame: u32,
+    max_frames: Option<u64>,
+    frames: u64,
+    backend: Backend,
+    jit: Jit,
+    coverage: Option<Coverage>,
+}
+
+impl Env {
+    pub fn new(reward: Box<dyn Reward>, frame_skip: u32) -> Self {
+        Env {
+            yarch8: YARCH8::new(60, 60 * 12),
+            reward,
+            frame_skip: frame_skip.max(1),
+            cycles_per_frame: 12,
+            max_frames: None,
+            frames: 0,
+            backend: Backend::Interpreter,
+            jit: Jit::new(),
+            coverage: None,
+        }
+    }
+
+    // End episodes after this many frames even if the reward never says done
+    pub fn set_max_frames(&mut self, max_frames: Option<u64>) {
+        self.max_frames = max_frames;
+    }
+
+    // Lockstep panics with a description of the first difference between backends
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    /* Track code coverage over all following steps, across resets. Turning it on again
+        starts over. The JIT does not report what it runs, so the interpreter is used
+        while coverage is on.
+    */
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.coverage = enabled.then(Coverage::new);
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn reset(&mut self, rom: &[u8], seed: u64) -> Observation {
+        self.yarch8.reset();
+        // Flags saved by an earlier episode would make runs depend on each other
+        self.yarch8.set_rpl_flags([0; 16]);
+        let rom_info = self.yarch8.load_bytes(rom);
+        self.yarch8.set_quirks(rom_info.quirks);
+        self.yarch8.seed(seed);
+
+        // One frame is the platform's tickrate worth of instructions at 60 Hz
+        self.cycles_per_frame = rom_info.tickrate.max(1);
+        self.yarch8.set_timer_freq(60);
+        self.yarch8.set_cpu_freq(self.cycles_per_frame * 60);
+
+        self.yarch8.start();
+        self.frames = 0;
+        self.reward.reset(&self.yarch8);
+        self.observation()
+    }
+
+    // Hold given keys (0x0-0xF) for frame_skip frames, every other key is released
+    pub fn step(&mut self, action_keys: &[u8]) -> (Observation, f32, bool) {
+        for key in 0..16u8 {
+            if action_keys.contains(&key) {
+                self.yarch8.key_press(key);
+            } else {
+                self.yarch8.key_released(key);
+            }
+        }
+
+        let mut reward = 0.0;
+        let mut done = false;
+        for _ in 0..self.frame_skip {
+            match self.backend {
+                _ if self.coverage.is_some() => {
+                    let coverage = self.coverage.as_mut().unwrap();
+                    for _ in 0..self.cycles_per_frame {
+                        coverage.record(&self.yarch8);
+                        self.yarch8.step();
+                    }
+                }
+                Backend::Interpreter => {
+                    for _ in 0..self.cycles_per_frame {
+                        self.yarch8.step();
+                    }
+                }
+                Backend::Jit => self.jit.run(&mut self.yarch8, self.cycles_per_frame),
+                Backend::Lockstep => {
+                    if let Err(e) = self
+                        .jit
+                        .run_lockstep(&mut self.yarch8, self.cycles_per_frame)
+                    {
+                        panic!("{}", e);
+                    }
+                }
+            }
+            self.frames += 1;
+            reward += self.reward.reward(&self.yarch8);
+            done = self.reward.done(&self.yarch8)
+                || self.yarch8.get_fault().is_some()
+                || self.max_frames.is_some_and(|max| self.frames >= max);
+            if done {
+                break;
+            }
+        }
+        (self.observation(), reward, done)
+    }
+
+    pub fn observation(&self) -> Observation {
+        self.yarch8.get_disp_buff().to_vec()
+    }
+
+    pub fn machine(&self) -> &YARCH8 {
+        &self.yarch8
+    }
+
+    pub fn machine_mut(&mut self) -> &mut YARCH8 {
+        &mut self.yarch8
+    }
+}
@@ -0,0 +1,438 @@
+use crate::cpu::{Fault, YARCH8};
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/* GDB Remote Serial Protocol stub.
+
+    Registers are numbered V0-VF (0-15), I (16), PC (17), SP (18), DT (19), ST (20).
+    16 bit registers are sent big endian, same as CHIP-8 instructions in memory.
+    The register layout is also served as a target description (qXfer:features:read)
+    so clients do not need to know about CHIP-8 beforehand.
+
+    The stub never blocks: the main loop polls it every cycle and only runs the CPU
+    while the client asked to continue or step.
+*/
+
+const REG_I: usize = 16;
+const REG_PC: usize = 17;
+const REG_SP: usize = 18;
+const REG_DT: usize = 19;
+const REG_ST: usize = 20;
+const NUM_REGS: usize = 21;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Halted,
+    Running,
+    Stepping,
+}
+
+pub struct GdbStub {
+    listener: TcpListener,
+    conn: Option<TcpStream>,
+    buffer: Vec<u8>,
+    breakpoints: BTreeSet<u16>,
+    state: State,
+}
+
+impl GdbStub {
+    // Only listen on localhost, anyone attached can read and write all of memory
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbStub {
+            listener,
+            conn: None,
+            buffer: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            // Wait for the debugger before running anything
+            state: State::Halted,
+        })
+    }
+
+    // Port actually listened on, for when 0 let the system pick one
+    pub fn local_port(&self) -> io::Result<u16> {
+        Ok(self.listener.local_addr()?.port())
+    }
+
+    // Whether CPU may execute next instruction
+    pub fn is_running(&self) -> bool {
+        self.state != State::Halted
+    }
+
+    // Accept client and handle whatever it sent since last poll
+    pub fn poll(&mut self, yarch8: &mut YARCH8) {
+        if self.conn.is_none() {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    // A client we can not poll would block the main loop, so it is dropped
+                    if let Err(e) = stream
+                        .set_nonblocking(true)
+                        .and_then(|()| stream.set_nodelay(true))
+                    {
+                        println!("GDB client from {} dropped: {}", addr, e);
+                        return;
+                    }
+                    println!("GDB client attached from {}", addr);
+                    self.conn = Some(stream);
+                    self.buffer.clear();
+                    self.state = State::Halted;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                // Usually the client giving up halfway, the next one may do better
+                Err(e) => {
+                    println!("GDB stub accept failed: {}", e);
+                    return;
+                }
+            }
+        }
+
+        let mut chunk = [0u8; 4096];
+        loop {
+            let conn = match self.conn.as_mut() {
+                Some(conn) => conn,
+                None => return,
+            };
+            match conn.read(&mut chunk) {
+                Ok(0) => {
+                    self.detach();
+                    return;
+                }
+                Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.detach();
+                    return;
+                }
+            }
+        }
+        self.process_buffer(yarch8);
+    }
+
+    // Called after every executed instruction to report steps and breakpoint hits
+    pub fn after_step(&mut self, yarch8: &YARCH8) {
+        // A faulted machine can not go on, report it like a crashed process
+        if let Some(fault) = yarch8.get_fault() {
+            if self.state != State::Halted {
+                self.state = State::Halted;
+                let signal = match fault {
+                    Fault::UnknownOpcode(_) | Fault::MachineCode(_) | Fault::InvalidKey(_) => {
+                        SIGILL
+                    }
+                    Fault::Halted => SIGTRAP,
+                    _ => SIGSEGV,
+                };
+                self.send_packet(&format!("S{:02x}", signal));
+            }
+            return;
+        }
+        let hit_breakpoint = self.breakpoints.contains(&yarch8.get_pc());
+        if self.state == State::Stepping || (self.state == State::Running && hit_breakpoint) {
+            self.state = State::Halted;
+            self.send_packet(&format!("S{:02x}", SIGTRAP));
+        }
+    }
+
+    fn detach(&mut self) {
+        println!("GDB client detached");
+        self.conn = None;
+        self.buffer.clear();
+        self.breakpoints.clear();
+        // Nobody is left to resume us, so keep the game going
+        self.state = State::Running;
+    }
+
+    fn process_buffer(&mut self, yarch8: &mut YARCH8) {
+        loop {
+            match self.buffer.first() {
+                None => return,
+                // Acks, we do not retransmit so nothing to do
+                Some(b'+') | Some(b'-') => {
+                    self.buffer.remove(0);
+                }
+                // Ctrl-C from client
+                Some(0x03) => {
+                    self.buffer.remove(0);
+                    if self.state != State::Halted {
+                        self.state = State::Halted;
+                        self.send_packet(&format!("S{:02x}", SIGINT));
+                    }
+                }
+                Some(b'$') => {
+                    // Need "$<data>#<2 hex digit checksum>"
+                    let end = match self.buffer.iter().position(|&b| b == b'#') {
+                        Some(end) if end + 2 < self.buffer.len() => end,
+                        _ => return,
+                    };
+                    let data = self.buffer[1..end].to_vec();
+                    let checksum = std::str::from_utf8(&self.buffer[end + 1..end + 3])
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                    self.buffer.drain(..end + 3);
+
+                    if checksum != Some(checksum_of(&data)) {
+                        self.send_raw(b"-");
+                        continue;
+                    }
+                    self.send_raw(b"+");
+                    let packet = String::from_utf8_lossy(&data).into_owned();
+                    if let Some(reply) = self.handle_packet(&packet, yarch8) {
+                        self.send_packet(&reply);
+                    }
+                }
+                // Garbage between packets
+                Some(_) => {
+                    self.buffer.remove(0);
+                }
+            }
+        }
+    }
+
+    // Returns reply to send, None when reply comes later (continue/step)
+    fn handle_packet(&mut self, packet: &str, yarch8: &mut YARCH8) -> Option<String> {
+        // Invalid UTF-8 comes through as U+FFFD, so the command is not always one byte
+        let command_len = packet.chars().next().map_or(0, char::len_utf8);
+        let (command, args) = packet.split_at(command_len);
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => (0..NUM_REGS)
+                .map(|reg| read_register(yarch8, reg))
+                .collect(),
+            "G" => {
+                let mut rest = args;
+                for reg in 0..NUM_REGS {
+                    let width = register_size(reg) * 2;
+                    if rest.len() < width {
+                        break;
+                    }
+                    if !rest.is_char_boundary(width) {
+                        return Some("E01".to_string());
+                    }
+                    let (value, tail) = rest.split_at(width);
+                    if !write_register(yarch8, reg, value) {
+                        return Some("E01".to_string());
+                    }
+                    rest = tail;
+                }
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(reg) if reg < NUM_REGS => read_register(yarch8, reg),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let written = args.split_once('=').and_then(|(reg, value)| {
+                    let reg = usize::from_str_radix(reg, 16).ok()?;
+                    (reg < NUM_REGS && write_register(yarch8, reg, value)).then_some(())
+                });
+                match written {
+                    Some(()) => "OK".to_string(),
+                    None => "E01".to_string(),
+                }
+            }
+            "m" => match parse_range(args, yarch8.get_ram().len()) {
+                Some(range) => yarch8.get_ram()[range]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect(),
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let written = args.split_once(':').and_then(|(range, data)| {
+                    let range = parse_range(range, yarch8.get_ram().len())?;
+                    let bytes = decode_hex(data)?;
+                    if bytes.len() != range.len() {
+                        return None;
+                    }
+                    for (address, byte) in range.zip(bytes) {
+                        yarch8.write_ram(address, byte);
+                    }
+                    Some(())
+                });
+                match written {
+                    Some(()) => "OK".to_string(),
+                    None => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => match parse_breakpoint(args) {
+                Some(address) => {
+                    if command == "Z" {
+                        self.breakpoints.insert(address);
+                    } else {
+                        self.breakpoints.remove(&address);
+                    }
+                    "OK".to_string()
+                }
+                // Only software breakpoints are supported
+                None => String::new(),
+            },
+            "c" | "s" => {
+                // Optional resume address
+                if let Ok(address) = u16::from_str_radix(args, 16) {
+                    yarch8.set_pc(address);
+                }
+                self.state = if command == "c" {
+                    State::Running
+                } else {
+                    State::Stepping
+                };
+                return None;
+            }
+            "D" => {
+                self.send_packet("OK");
+                self.detach();
+                return None;
+            }
+            "k" => {
+                self.detach();
+                return None;
+            }
+            "H" => "OK".to_string(),
+            "q" => handle_query(args),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn send_packet(&mut self, data: &str) {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.send_raw(packet.as_bytes());
+    }
+
+    fn send_raw(&mut self, bytes: &[u8]) {
+        if let Some(conn) = self.conn.as_mut() {
+            // Socket is non-blocking, but replies are small enough to never fill it
+            if conn.write_all(bytes).is_err() {
+                self.detach();
+            }
+        }
+    }
+}
+
+fn handle_query(query: &str) -> String {
+    if query.starts_with("Supported") {
+        "PacketSize=4000;qXfer:features:read+".to_string()
+    } else if query == "Attached" {
+        "1".to_string()
+    } else if query == "C" {
+        "QC1".to_string()
+    } else if let Some(request) = query.strip_prefix("Xfer:features:read:target.xml:") {
+        // "offset,length" into the document, 'l' marks last chunk
+        match parse_address_length(request)
+            .and_then(|(offset, length)| Some((offset, offset.checked_add(length)?)))
+        {
+            Some((offset, end)) => {
+                let xml = target_xml();
+                let start = offset.min(xml.len());
+                let end = end.min(xml.len());
+                let marker = if end == xml.len() { 'l' } else { 'm' };
+                format!("{}{}", marker, &xml[start..end])
+            }
+            None => "E01".to_string(),
+        }
+    } else {
+        String::new()
+    }
+}
+
+fn target_xml() -> String {
+    let mut regs = String::new();
+    for reg in 0..16 {
+        regs.push_str(&format!(
+            "<reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\" regnum=\"{}\"/>",
+            reg, reg
+        ));
+    }
+    regs.push_str("<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\" regnum=\"16\"/>");
+    regs.push_str("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\" regnum=\"17\"/>");
+    regs.push_str("<reg name=\"sp\" bitsize=\"8\" type=\"uint8\" regnum=\"18\"/>");
+    regs.push_str("<reg name=\"dt\" bitsize=\"8\" type=\"uint8\" regnum=\"19\"/>");
+    regs.push_str("<reg name=\"st\" bitsize=\"8\" type=\"uint8\" regnum=\"20\"/>");
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.yarch8.chip8\">{}</feature></target>",
+        regs
+    )
+}
+
+// In bytes
+fn register_size(reg: usize) -> usize {
+    match reg {
+        REG_I | REG_PC => 2,
+        _ => 1,
+    }
+}
+
+fn read_register(yarch8: &YARCH8, reg: usize) -> String {
+    match reg {
+        0..=15 => format!("{:02x}", yarch8.get_v_reg(reg)),
+        // MEGA-CHIP's 24 bit I does not fit the 16 bit register
+        REG_I => format!("{:04x}", yarch8.get_i() as u16),
+        REG_PC => format!("{:04x}", yarch8.get_pc()),
+        REG_SP => format!("{:02x}", yarch8.get_sp()),
+        REG_DT => format!("{:02x}", yarch8.get_delay_timer()),
+        REG_ST => format!("{:02x}", yarch8.get_sound_timer()),
+        _ => unreachable!(),
+    }
+}
+
+fn write_register(yarch8: &mut YARCH8, reg: usize, hex: &str) -> bool {
+    let value = match u16::from_str_radix(hex, 16) {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+    match reg {
+        0..=15 => yarch8.set_v_reg(reg, value as u8),
+        REG_I => yarch8.set_i(value),
+        REG_PC => yarch8.set_pc(value),
+        REG_SP => yarch8.set_sp(value as usize),
+        REG_DT => yarch8.set_delay_timer(value as u8),
+        REG_ST => yarch8.set_sound_timer(value as u8),
+        _ => return false,
+    }
+    true
+}
+
+fn parse_address_length(args: &str) -> Option<(usize, usize)> {
+    let (address, length) = args.split_once(',')?;
+    Some((
+        usize::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+// "addr,length" of memory that has to lie within size bytes
+fn parse_range(args: &str, size: usize) -> Option<std::ops::Range<usize>> {
+    let (address, length) = parse_address_length(args)?;
+    let end = address.checked_add(length)?;
+    (end <= size).then_some(address..end)
+}
+
+// "0,addr,kind", type 0 is software breakpoint
+fn parse_breakpoint(args: &str) -> Option<u16> {
+    let mut fields = args.split(',');
+    if fields.next()? != "0" {
+        return None;
+    }
+    u16::from_str_radix(fields.next()?, 16).ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
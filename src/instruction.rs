@@ -0,0 +1,311 @@
+use std::fmt;
+
+/* Decoded CHIP-8 instructions.
+
+    Operands are pulled out of the raw opcode once by decode(), so the interpreter
+    can cache the result per address and skip decoding on every cycle.
+    x and y are register indexes, nn/nnn are immediates. Everything is kept small
+    so the cache stays cheap to clone along with the machine.
+    The CHIP-8E, CHIP-8X and MEGA-CHIP instructions are only decoded by cpu::variant,
+    their opcodes mean something else (or nothing) to everyone else.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Cls,                            // 00E0
+    Ret,                            // 00EE
+    Lores,                          // 00FE
+    Hires,                          // 00FF
+    Sys { nnn: u16 },               // 0NNN machine code routine
+    Jump { nnn: u16 },              // 1NNN
+    Call { nnn: u16 },              // 2NNN
+    SkipEqImm { x: u8, nn: u8 },    // 3XNN
+    SkipNeImm { x: u8, nn: u8 },    // 4XNN
+    SkipEqReg { x: u8, y: u8 },     // 5XY0
+    SetImm { x: u8, nn: u8 },       // 6XNN
+    AddImm { x: u8, nn: u8 },       // 7XNN
+    Mov { x: u8, y: u8 },           // 8XY0
+    Or { x: u8, y: u8 },            // 8XY1
+    And { x: u8, y: u8 },           // 8XY2
+    Xor { x: u8, y: u8 },           // 8XY3
+    AddReg { x: u8, y: u8 },        // 8XY4
+    Sub { x: u8, y: u8 },           // 8XY5
+    Shr { x: u8, y: u8 },           // 8XY6
+    SubN { x: u8, y: u8 },          // 8XY7
+    Shl { x: u8, y: u8 },           // 8XYE
+    SkipNeReg { x: u8, y: u8 },     // 9XY0
+    SetI { nnn: u16 },              // ANNN
+    JumpOffset { x: u8, nnn: u16 }, // BNNN
+    Rand { x: u8, nn: u8 },         // CXNN
+    Draw { x: u8, y: u8, n: u8 },   // DXYN
+    SkipKey { x: u8 },              // EX9E
+    SkipNotKey { x: u8 },           // EXA1
+    GetDelay { x: u8 },             // FX07
+    WaitKey { x: u8 },              // FX0A
+    SetDelay { x: u8 },             // FX15
+    SetSound { x: u8 },             // FX18
+    AddI { x: u8 },                 // FX1E
+    Font { x: u8 },                 // FX29
+    Bcd { x: u8 },                  // FX33
+    Store { x: u8 },                // FX55
+    Load { x: u8 },                 // FX65
+    SaveFlags { x: u8 },            // FX75
+    LoadFlags { x: u8 },            // FX85
+    // CHIP-8E
+    Halt,                        // 00ED
+    Nop,                         // 00F2
+    WaitDelay,                   // 0151
+    SkipAlways,                  // 0188
+    SkipGtReg { x: u8, y: u8 },  // 5XY1
+    StoreRange { x: u8, y: u8 }, // 5XY2
+    LoadRange { x: u8, y: u8 },  // 5XY3
+    JumpBack { nn: u8 },         // BBNN
+    JumpForward { nn: u8 },      // BFNN
+    Output { x: u8 },            // FX03
+    SkipBytes { x: u8 },         // FX1B
+    DelayWait { x: u8 },         // FX4F
+    InputStrobe { x: u8 },       // FXE3
+    Input { x: u8 },             // FXE7
+    // CHIP-8X
+    NextBackground,                // 02A0
+    AddNibbles { x: u8, y: u8 },   // 5XY1
+    Color { x: u8, y: u8, n: u8 }, // BXYN
+    SkipKey2 { x: u8 },            // EXF2
+    SkipNotKey2 { x: u8 },         // EXF5
+    Tone { x: u8 },                // FXF8
+    PortInput { x: u8 },           // FXFB
+    // MEGA-CHIP
+    MegaOff,                   // 0010
+    MegaOn,                    // 0011
+    LongIndex { nn: u8 },      // 01NN NNNN, low 16 bits of I in the next 2 bytes
+    LoadPalette { nn: u8 },    // 02NN
+    SpriteWidth { nn: u8 },    // 03NN
+    SpriteHeight { nn: u8 },   // 04NN
+    PlaySound { n: u8 },       // 060N
+    StopSound,                 // 0700
+    BlendMode { n: u8 },       // 080N
+    CollisionColor { nn: u8 }, // 09NN
+    Unknown(u16),
+}
+
+pub fn decode(op: u16) -> Instruction {
+    let x = ((op & 0x0F00) >> 8) as u8;
+    let y = ((op & 0x00F0) >> 4) as u8;
+    let n = (op & 0x000F) as u8;
+    let nn = (op & 0x00FF) as u8;
+    let nnn = op & 0x0FFF;
+
+    match op & 0xF000 {
+        0x0000 => match op {
+            0x00E0 => Instruction::Cls,
+            0x00EE => Instruction::Ret,
+            0x00FE => Instruction::Lores,
+            0x00FF => Instruction::Hires,
+            _ => Instruction::Sys { nnn },
+        },
+        0x1000 => Instruction::Jump { nnn },
+        0x2000 => Instruction::Call { nnn },
+        0x3000 => Instruction::SkipEqImm { x, nn },
+        0x4000 => Instruction::SkipNeImm { x, nn },
+        0x5000 => Instruction::SkipEqReg { x, y },
+        0x6000 => Instruction::SetImm { x, nn },
+        0x7000 => Instruction::AddImm { x, nn },
+        0x8000 => match n {
+            0x0 => Instruction::Mov { x, y },
+            0x1 => Instruction::Or { x, y },
+            0x2 => Instruction::And { x, y },
+            0x3 => Instruction::Xor { x, y },
+            0x4 => Instruction::AddReg { x, y },
+            0x5 => Instruction::Sub { x, y },
+            0x6 => Instruction::Shr { x, y },
+            0x7 => Instruction::SubN { x, y },
+            0xE => Instruction::Shl { x, y },
+            _ => Instruction::Unknown(op),
+        },
+        0x9000 => Instruction::SkipNeReg { x, y },
+        0xA000 => Instruction::SetI { nnn },
+        0xB000 => Instruction::JumpOffset { x, nnn },
+        0xC000 => Instruction::Rand { x, nn },
+        0xD000 => Instruction::Draw { x, y, n },
+        0xE000 => match nn {
+            0x9E => Instruction::SkipKey { x },
+            0xA1 => Instruction::SkipNotKey { x },
+            _ => Instruction::Unknown(op),
+        },
+        0xF000 => match nn {
+            0x07 => Instruction::GetDelay { x },
+            0x0A => Instruction::WaitKey { x },
+            0x15 => Instruction::SetDelay { x },
+            0x18 => Instruction::SetSound { x },
+            0x1E => Instruction::AddI { x },
+            0x29 => Instruction::Font { x },
+            0x33 => Instruction::Bcd { x },
+            0x55 => Instruction::Store { x },
+            0x65 => Instruction::Load { x },
+            0x75 => Instruction::SaveFlags { x },
+            0x85 => Instruction::LoadFlags { x },
+            _ => Instruction::Unknown(op),
+        },
+        _ => Instruction::Unknown(op),
+    }
+}
+
+impl Instruction {
+    // Opcode pattern the instruction was decoded from, groups instructions by kind
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            Instruction::Cls => "00E0",
+            Instruction::Ret => "00EE",
+            Instruction::Lores => "00FE",
+            Instruction::Hires => "00FF",
+            Instruction::Sys { .. } => "0NNN",
+            Instruction::Jump { .. } => "1NNN",
+            Instruction::Call { .. } => "2NNN",
+            Instruction::SkipEqImm { .. } => "3XNN",
+            Instruction::SkipNeImm { .. } => "4XNN",
+            Instruction::SkipEqReg { .. } => "5XY0",
+            Instruction::SetImm { .. } => "6XNN",
+            Instruction::AddImm { .. } => "7XNN",
+            Instruction::Mov { .. } => "8XY0",
+            Instruction::Or { .. } => "8XY1",
+            Instruction::And { .. } => "8XY2",
+            Instruction::Xor { .. } => "8XY3",
+            Instruction::AddReg { .. } => "8XY4",
+            Instruction::Sub { .. } => "8XY5",
+            Instruction::Shr { .. } => "8XY6",
+            Instruction::SubN { .. } => "8XY7",
+            Instruction::Shl { .. } => "8XYE",
+            Instruction::SkipNeReg { .. } => "9XY0",
+            Instruction::SetI { .. } => "ANNN",
+            Instruction::JumpOffset { .. } => "BNNN",
+            Instruction::Rand { .. } => "CXNN",
+            Instruction::Draw { .. } => "DXYN",
+            Instruction::SkipKey { .. } => "EX9E",
+            Instruction::SkipNotKey { .. } => "EXA1",
+            Instruction::GetDelay { .. } => "FX07",
+            Instruction::WaitKey { .. } => "FX0A",
+            Instruction::SetDelay { .. } => "FX15",
+            Instruction::SetSound { .. } => "FX18",
+            Instruction::AddI { .. } => "FX1E",
+            Instruction::Font { .. } => "FX29",
+            Instruction::Bcd { .. } => "FX33",
+            Instruction::Store { .. } => "FX55",
+            Instruction::Load { .. } => "FX65",
+            Instruction::SaveFlags { .. } => "FX75",
+            Instruction::LoadFlags { .. } => "FX85",
+            Instruction::Halt => "00ED",
+            Instruction::Nop => "00F2",
+            Instruction::WaitDelay => "0151",
+            Instruction::SkipAlways => "0188",
+            Instruction::SkipGtReg { .. } => "5XY1",
+            Instruction::StoreRange { .. } => "5XY2",
+            Instruction::LoadRange { .. } => "5XY3",
+            Instruction::JumpBack { .. } => "BBNN",
+            Instruction::JumpForward { .. } => "BFNN",
+            Instruction::Output { .. } => "FX03",
+            Instruction::SkipBytes { .. } => "FX1B",
+            Instruction::DelayWait { .. } => "FX4F",
+            Instruction::InputStrobe { .. } => "FXE3",
+            Instruction::Input { .. } => "FXE7",
+            Instruction::NextBackground => "02A0",
+            Instruction::AddNibbles { .. } => "5XY1",
+            Instruction::Color { .. } => "BXYN",
+            Instruction::SkipKey2 { .. } => "EXF2",
+            Instruction::SkipNotKey2 { .. } => "EXF5",
+            Instruction::Tone { .. } => "FXF8",
+            Instruction::PortInput { .. } => "FXFB",
+            Instruction::MegaOff => "0010",
+            Instruction::MegaOn => "0011",
+            Instruction::LongIndex { .. } => "01NN",
+            Instruction::LoadPalette { .. } => "02NN",
+            Instruction::SpriteWidth { .. } => "03NN",
+            Instruction::SpriteHeight { .. } => "04NN",
+            Instruction::PlaySound { .. } => "060N",
+            Instruction::StopSound => "0700",
+            Instruction::BlendMode { .. } => "080N",
+            Instruction::CollisionColor { .. } => "09NN",
+            Instruction::Unknown(_) => "????",
+        }
+    }
+}
+
+// Disassembly in the mnemonics of Cowgod's manual
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Lores => write!(f, "LOW"),
+            Instruction::Hires => write!(f, "HIGH"),
+            Instruction::Sys { nnn } => write!(f, "SYS {:#05X}", nnn),
+            Instruction::Jump { nnn } => write!(f, "JP {:#05X}", nnn),
+            Instruction::Call { nnn } => write!(f, "CALL {:#05X}", nnn),
+            Instruction::SkipEqImm { x, nn } => write!(f, "SE V{:X}, {:#04X}", x, nn),
+            Instruction::SkipNeImm { x, nn } => write!(f, "SNE V{:X}, {:#04X}", x, nn),
+            Instruction::SkipEqReg { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::SetImm { x, nn } => write!(f, "LD V{:X}, {:#04X}", x, nn),
+            Instruction::AddImm { x, nn } => write!(f, "ADD V{:X}, {:#04X}", x, nn),
+            Instruction::Mov { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SubN { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipNeReg { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::SetI { nnn } => write!(f, "LD I, {:#05X}", nnn),
+            Instruction::JumpOffset { nnn, .. } => write!(f, "JP V0, {:#05X}", nnn),
+            Instruction::Rand { x, nn } => write!(f, "RND V{:X}, {:#04X}", x, nn),
+            Instruction::Draw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKey { x } => write!(f, "SKP V{:X}", x),
+            Instruction::SkipNotKey { x } => write!(f, "SKNP V{:X}", x),
+            Instruction::GetDelay { x } => write!(f, "LD V{:X}, DT", x),
+            Instruction::WaitKey { x } => write!(f, "LD V{:X}, K", x),
+            Instruction::SetDelay { x } => write!(f, "LD DT, V{:X}", x),
+            Instruction::SetSound { x } => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI { x } => write!(f, "ADD I, V{:X}", x),
+            Instruction::Font { x } => write!(f, "LD F, V{:X}", x),
+            Instruction::Bcd { x } => write!(f, "LD B, V{:X}", x),
+            Instruction::Store { x } => write!(f, "LD [I], V{:X}", x),
+            Instruction::Load { x } => write!(f, "LD V{:X}, [I]", x),
+            Instruction::SaveFlags { x } => write!(f, "LD R, V{:X}", x),
+            Instruction::LoadFlags { x } => write!(f, "LD V{:X}, R", x),
+            // Cowgod has no names for the variants, these follow the same scheme
+            Instruction::Halt => write!(f, "HALT"),
+            Instruction::Nop => write!(f, "NOP"),
+            Instruction::WaitDelay => write!(f, "WAIT DT"),
+            Instruction::SkipAlways => write!(f, "SKIP"),
+            Instruction::SkipGtReg { x, y } => write!(f, "SGT V{:X}, V{:X}", x, y),
+            Instruction::StoreRange { x, y } => write!(f, "LD [I], V{:X}-V{:X}", x, y),
+            Instruction::LoadRange { x, y } => write!(f, "LD V{:X}-V{:X}, [I]", x, y),
+            Instruction::JumpBack { nn } => write!(f, "JP -{:#04X}", nn),
+            Instruction::JumpForward { nn } => write!(f, "JP +{:#04X}", nn),
+            Instruction::Output { x } => write!(f, "OUT V{:X}", x),
+            Instruction::SkipBytes { x } => write!(f, "SKIP V{:X}", x),
+            Instruction::DelayWait { x } => write!(f, "WAIT V{:X}", x),
+            Instruction::InputStrobe { x } => write!(f, "INP V{:X}, STROBE", x),
+            Instruction::Input { x } => write!(f, "INP V{:X}", x),
+            Instruction::NextBackground => write!(f, "BGC"),
+            Instruction::AddNibbles { x, y } => write!(f, "ADDN V{:X}, V{:X}", x, y),
+            Instruction::Color { x, y, n } => write!(f, "COL V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKey2 { x } => write!(f, "SKP2 V{:X}", x),
+            Instruction::SkipNotKey2 { x } => write!(f, "SKNP2 V{:X}", x),
+            Instruction::Tone { x } => write!(f, "TONE V{:X}", x),
+            Instruction::PortInput { x } => write!(f, "INP V{:X}", x),
+            // MEGA-CHIP in the names of its own assembler
+            Instruction::MegaOff => write!(f, "MEGAOFF"),
+            Instruction::MegaOn => write!(f, "MEGAON"),
+            Instruction::LongIndex { nn } => write!(f, "LDHI I, {:#04X}....", nn),
+            Instruction::LoadPalette { nn } => write!(f, "LDPAL {}", nn),
+            Instruction::SpriteWidth { nn } => write!(f, "SPRW {}", nn),
+            Instruction::SpriteHeight { nn } => write!(f, "SPRH {}", nn),
+            Instruction::PlaySound { n } => write!(f, "DIGISND {}", n),
+            Instruction::StopSound => write!(f, "STOPSND"),
+            Instruction::BlendMode { n } => write!(f, "BMODE {}", n),
+            Instruction::CollisionColor { nn } => write!(f, "CCOL {}", nn),
+            Instruction::Unknown(op) => write!(f, "DW {:#06X}", op),
+        }
+    }
+}
@@ -0,0 +1,17 @@
+pub mod analyzer;
+pub mod batch;
+pub mod cheat;
+pub mod config;
+pub mod coverage;
+pub mod cpu;
+pub mod database;
+pub mod difftest;
+pub mod env;
+pub mod gdb;
+pub mod instruction;
+pub mod loader;
+pub mod patch;
+pub mod platform;
+pub mod profiler;
+pub mod reference;
+pub mod rpc;
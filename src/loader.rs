@@ -0,0 +1,189 @@
+use std::fs;
+
+/* ROM file formats, told apart by content rather than file extension.
+
+    Raw binary      - the ROM image as is, anything not matching the formats below
+    Zip archive     - "PK\x03\x04", must hold a single ROM (or a single .ch8/.sc8/.xo8
+                      file next to readmes and such), stored or deflated
+    Hex text        - hex bytes separated by whitespace or commas, "0x" prefixes and
+                      "ADDRESS:" line prefixes allowed, e.g. "00E0 A22A" or "0x00, 0xE0"
+    Octo cartridge  - "GIF87a"/"GIF89a", only recognised so it is refused rather than run
+                      as a binary. Cartridges hold Octo source, which needs the assembler
+*/
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GIF_MAGICS: [&[u8]; 2] = [b"GIF87a", b"GIF89a"];
+const ZIP_CENTRAL_DIRECTORY: u32 = 0x02014B50;
+const ZIP_END_OF_CENTRAL_DIRECTORY: &[u8] = b"PK\x05\x06";
+const MAX_ROM_SIZE: usize = 1 << 20; // way past any RAM, stops zip bombs
+const ROM_EXTENSIONS: [&str; 5] = [".ch8", ".c8", ".sc8", ".xo8", ".hex"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Binary,
+    Zip,
+    HexText,
+    OctoCartridge,
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Binary => "binary",
+            Format::Zip => "zip archive",
+            Format::HexText => "hex text",
+            Format::OctoCartridge => "Octo cartridge",
+        }
+    }
+}
+
+pub fn detect(data: &[u8]) -> Format {
+    if data.starts_with(ZIP_MAGIC) {
+        Format::Zip
+    } else if GIF_MAGICS.iter().any(|magic| data.starts_with(magic)) {
+        Format::OctoCartridge
+    } else if parse_hex_text(data).is_some() {
+        Format::HexText
+    } else {
+        Format::Binary
+    }
+}
+
+pub fn read_rom(path: &str) -> Result<Vec<u8>, String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    decode(&data)
+}
+
+// ROM image held by the file contents, whatever format they are in
+pub fn decode(data: &[u8]) -> Result<Vec<u8>, String> {
+    match detect(data) {
+        Format::Binary => Ok(data.to_vec()),
+        Format::HexText => Ok(parse_hex_text(data).unwrap()),
+        Format::OctoCartridge => Err("Octo cartridges are not supported".to_string()),
+        // The ROM inside may itself be hex text, but not another archive
+        Format::Zip => {
+            let rom = unzip_rom(data)?;
+            match detect(&rom) {
+                Format::Zip => Err("Zip archive holds another zip archive".to_string()),
+                _ => decode(&rom),
+            }
+        }
+    }
+}
+
+fn parse_hex_text(data: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut rom = Vec::new();
+    for line in text.lines() {
+        let line = match line.split_once(':') {
+            Some((address, rest)) if is_hex(address.trim()) => rest,
+            _ => line,
+        };
+        for token in line.split(|c: char| c.is_whitespace() || c == ',') {
+            if token.is_empty() {
+                continue;
+            }
+            let digits = token
+                .strip_prefix("0x")
+                .or_else(|| token.strip_prefix("0X"))
+                .unwrap_or(token);
+            if !is_hex(digits) || digits.len() % 2 != 0 {
+                return None;
+            }
+            for idx in (0..digits.len()).step_by(2) {
+                rom.push(u8::from_str_radix(&digits[idx..idx + 2], 16).ok()?);
+            }
+        }
+    }
+    // Empty or blank files are just empty binaries
+    if rom.is_empty() {
+        None
+    } else {
+        Some(rom)
+    }
+}
+
+fn is_hex(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/* Zip archives are read through their central directory, which has the reliable sizes.
+    Entries with data descriptors, zip64 and encryption are not supported.
+*/
+fn unzip_rom(data: &[u8]) -> Result<Vec<u8>, String> {
+    let u16_at = |offset: usize| -> Result<usize, String> {
+        data.get(offset..offset + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+            .ok_or_else(|| "Zip archive is truncated".to_string())
+    };
+    let u32_at = |offset: usize| -> Result<usize, String> {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+            .ok_or_else(|| "Zip archive is truncated".to_string())
+    };
+
+    // End of central directory record sits at the end, before an optional comment
+    let end = (0..data.len().saturating_sub(21))
+        .rev()
+        .find(|&offset| data[offset..].starts_with(ZIP_END_OF_CENTRAL_DIRECTORY))
+        .ok_or("Zip archive has no central directory")?;
+    let entry_count = u16_at(end + 10)?;
+    let mut offset = u32_at(end + 16)?;
+
+    // (name, compression method, compressed size, local header offset)
+    let mut files = Vec::new();
+    for _ in 0..entry_count {
+        if u32_at(offset)? != ZIP_CENTRAL_DIRECTORY as usize {
+            return Err("Zip central directory is corrupt".to_string());
+        }
+        let method = u16_at(offset + 10)?;
+        let compressed_size = u32_at(offset + 20)?;
+        let name_length = u16_at(offset + 28)?;
+        let extra_length = u16_at(offset + 30)?;
+        let comment_length = u16_at(offset + 32)?;
+        let local_header = u32_at(offset + 42)?;
+        let name = data
+            .get(offset + 46..offset + 46 + name_length)
+            .ok_or("Zip archive is truncated")?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        if !name.ends_with('/') {
+            files.push((name, method, compressed_size, local_header));
+        }
+        offset += 46 + name_length + extra_length + comment_length;
+    }
+
+    let roms: Vec<_> = files
+        .iter()
+        .filter(|(name, ..)| {
+            let name = name.to_lowercase();
+            ROM_EXTENSIONS
+                .iter()
+                .any(|extension| name.ends_with(extension))
+        })
+        .collect();
+    let (name, method, compressed_size, local_header) = match (files.len(), roms.len()) {
+        (1, _) => &files[0],
+        (_, 1) => roms[0],
+        _ => {
+            let names: Vec<&str> = files.iter().map(|(name, ..)| name.as_str()).collect();
+            return Err(format!(
+                "Zip archive must hold a single ROM, found: {}",
+                names.join(", ")
+            ));
+        }
+    };
+
+    let data_start = local_header + 30 + u16_at(local_header + 26)? + u16_at(local_header + 28)?;
+    let compressed = data
+        .get(data_start..data_start + compressed_size)
+        .ok_or("Zip archive is truncated")?;
+    match method {
+        0 => Ok(compressed.to_vec()),
+        8 => miniz_oxide::inflate::decompress_to_vec_with_limit(compressed, MAX_ROM_SIZE)
+            .map_err(|e| format!("Could not inflate {}: {:?}", name, e)),
+        _ => Err(format!(
+            "{} uses unsupported zip compression method {}",
+            name, method
+        )),
+    }
+}
@@ -1,46 +1,207 @@
-pub mod cpu;
+pub mod audio;
 pub mod renderer;
 
-use cpu::YARCH8;
+use audio::SamplePlayer;
+
+use clap::{Parser, Subcommand};
 use renderer::Renderer;
-use sdl2::{event::Event, render};
+use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use clap::Parser;
+use sdl2::pixels::Color;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
 use std::time::{Duration, Instant};
+use yarch8::cheat::Cheats;
+use yarch8::config::{self, Config, Profile};
+use yarch8::coverage::Coverage;
+use yarch8::cpu::timing::{self, Timing};
+use yarch8::cpu::variant::{self, Variant};
+use yarch8::cpu::vip::Vip;
+use yarch8::cpu::{KeyEvent, Quirks, YARCH8};
+use yarch8::database::RomInfo;
+use yarch8::gdb::GdbStub;
+use yarch8::profiler::Profiler;
+use yarch8::rpc::RpcServer;
+use yarch8::{analyzer, database, difftest, loader, patch};
+
+const DEFAULT_SCALE: u32 = 20;
+const DEFAULT_FPS: u32 = 60;
+const DEFAULT_CPU_FREQ: u32 = 500;
+const DEFAULT_TIMER_FREQ: u32 = 60;
 
 fn main() {
     let args = Args::parse();
+    match &args.command {
+        Some(Command::Info { rom_file_path }) => {
+            print_info(rom_file_path);
+            return;
+        }
+        Some(Command::Difftest {
+            rom_file_path,
+            random,
+            seed,
+            cycles,
+        }) => {
+            run_difftest(rom_file_path.as_deref(), *random, *seed, *cycles);
+            return;
+        }
+        Some(Command::CreatePatch {
+            original,
+            modified,
+            patch_file_path,
+        }) => {
+            create_patch(original, modified, patch_file_path);
+            return;
+        }
+        None => {}
+    }
+    // Required unless a subcommand is given
+    let rom_file_path = args.rom_file_path.clone().unwrap();
+
+    // Init CPU State (where pc, sp are ?)
+    let mut yarch8 = YARCH8::new(DEFAULT_TIMER_FREQ, DEFAULT_CPU_FREQ);
+
+    // Read rom file into RAM (load program into memory)
+    let (rom_info, mut rom_image) = load_rom(&mut yarch8, &rom_file_path, args.patch.as_deref());
+    println!("Loaded {}", rom_info.describe());
+    if rom_info.known {
+        println!("Platform: {}", rom_info.platform.name());
+    } else {
+        println!(
+            "Platform: {} (guessed, {} confidence)",
+            rom_info.platform.name(),
+            rom_info.confidence
+        );
+    }
+
+    // Settings priority: command line > config ROM section > ROM database > config defaults
+    // > guessed platform > built-in defaults
+    let config = Config::load(&args.config).unwrap_or_else(|e| {
+        println!("Config file error in {}", e);
+        std::process::exit(1);
+    });
+    let profile = config
+        .profile_for(&rom_info.sha1, rom_info.profile(), rom_info.guessed_profile())
+        .overlay(args.profile());
+    let fps = profile.fps.unwrap_or(DEFAULT_FPS);
+    let cpu_freq = profile.cpu_freq.unwrap_or(DEFAULT_CPU_FREQ);
+    let timer_freq = profile.timer_freq.unwrap_or(DEFAULT_TIMER_FREQ);
+    let keymap = build_keymap(profile.keymap.as_ref()).unwrap_or_else(|e| {
+        println!("Config file error in {}: {}", args.config, e);
+        std::process::exit(1);
+    });
+    let variant = profile.variant.unwrap_or_default();
 
     // SDL2 init
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
+    let mut sample_player = SamplePlayer::new(sdl_context.audio().unwrap());
 
     // For scale up original screen size
-    let scale = args.scale;
+    let scale = profile.scale.unwrap_or(DEFAULT_SCALE);
 
+    // Window fits the display of the variant
+    let (window_w, window_h) = renderer::window_size(variant.window_resolution(), scale);
     let window = video_subsystem
-        .window("YARCH8", 64 * scale, 32 * scale)
+        .window("YARCH8", window_w, window_h)
         .position_centered()
+        .resizable()
         .build()
         .unwrap();
 
     // canvas is our screen where we draw sprite
     let canvas = window.into_canvas().build().unwrap();
-    let mut renderer = Renderer::new(canvas, scale);
+    let mut renderer = Renderer::new(canvas, args.integer_scale, args.grid);
+    // Checked when the config was parsed
+    if let Some(palette) = &profile.palette {
+        let (bg_r, bg_g, bg_b) = palette.background_rgb().unwrap();
+        let (fg_r, fg_g, fg_b) = palette.foreground_rgb().unwrap();
+        renderer.set_palette(Color::RGB(bg_r, bg_g, bg_b), Color::RGB(fg_r, fg_g, fg_b));
+    }
 
-    // Init CPU State (where pc, sp are ?)
-    let mut yarch8 = YARCH8::new(args.timer_freq, args.cpu_freq);
+    yarch8.set_timer_freq(timer_freq);
+    yarch8.set_cpu_freq(cpu_freq);
+    yarch8.set_quirks(profile.quirks.unwrap_or_default());
+    yarch8.set_timing(profile.timing.unwrap_or_default());
+    // Variants may load the ROM elsewhere or keep more of it (MEGA-CHIP memory above 4K),
+    // so it goes in again once the variant is known, the same way F5 loads it
+    yarch8.set_variant(variant);
+    if variant != Variant::Chip8 {
+        yarch8.reset();
+        rom_image = load_rom(&mut yarch8, &rom_file_path, args.patch.as_deref()).1;
+        println!("Variant: {}", variant.name());
+    }
+    if let Some(vip) = &args.vip {
+        yarch8.set_vip(Some(load_vip(&vip[0], &vip[1])));
+        println!(
+            "Running on a COSMAC VIP with the interpreter from {}",
+            vip[1]
+        );
+    }
 
-    // Read rom file into RAM (load program into memory)
-    yarch8.load(&args.rom_file_path);
+    let cheats_path = args
+        .cheats
+        .clone()
+        .unwrap_or_else(|| Cheats::path_for(&rom_file_path));
+    // Only the default file may be missing, a path given on the command line must load
+    let loaded = if args.cheats.is_some() {
+        Cheats::load(&cheats_path)
+    } else {
+        Cheats::load_if_present(&cheats_path)
+    };
+    let mut cheats = loaded.unwrap_or_else(|e| {
+        println!("Cheats not loaded, {}", e);
+        Cheats::default()
+    });
+    if !cheats.is_empty() {
+        println!("Cheats loaded from {}", cheats_path);
+    }
+    cheats.apply_patches(&mut yarch8);
+
+    // High scores and such kept by earlier sessions in RPL flags (FX75/FX85)
+    let rpl_path = Path::new(&rom_file_path)
+        .with_extension("rpl")
+        .to_string_lossy()
+        .into_owned();
+    yarch8
+        .load_rpl_flags(&rpl_path)
+        .unwrap_or_else(|e| println!("Could not read RPL flags from {}: {}", rpl_path, e));
+    let saved_rpl_flags = *yarch8.get_rpl_flags();
 
     // Start program
     yarch8.start();
     let mut render_start = Instant::now();
-    let render_req_duration = Duration::new(0, 1_000_000_000u32 / args.fps).as_nanos();
+    let render_req_duration = Duration::new(0, 1_000_000_000u32 / fps).as_nanos();
+
+    let mut gdb = args.gdb.map(|port| {
+        let stub = GdbStub::listen(port).expect("Could not start GDB stub!");
+        println!("Waiting for GDB client on 127.0.0.1:{}", port);
+        stub
+    });
+
+    let mut rpc = args.rpc.as_ref().map(|address| {
+        let server = RpcServer::listen(address).expect("Could not start JSON-RPC server!");
+        println!("JSON-RPC server listening on {}", address);
+        server
+    });
+
+    let mut profiler = args.profiler.is_some().then(Profiler::new);
+    let mut coverage = args.coverage.is_some().then(Coverage::new);
+
+    // Runtime controls
+    let mut controls = Controls {
+        rom_title: rom_info.title.clone(),
+        cpu_freq,
+        timing: yarch8.get_timing(),
+        slow_factor: args.slow_factor,
+        paused: false,
+        fast_forward: false,
+        slow_motion: false,
+        frames_to_advance: 0,
+    };
 
-    // TODO: Add loop here
     'running: loop {
         // Handle keys events
         // TODO: use scancode over keycode in the future
@@ -53,101 +214,587 @@ fn main() {
                 } => {
                     break 'running;
                 }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    repeat: false,
+                    ..
+                } => {
+                    if let (Some(profiler), Some(prefix)) = (&profiler, &args.profiler) {
+                        write_profile(profiler, &yarch8, controls.cpu_freq, prefix);
+                    }
+                    if let (Some(coverage), Some(path)) = (&coverage, &args.coverage) {
+                        write_coverage(coverage, &rom_image, yarch8.get_variant(), path);
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } => {
+                    renderer.toggle_fullscreen();
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => {
+                    renderer.toggle_grid();
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    repeat: false,
+                    ..
+                } => {
+                    // Soft reset, reload ROM from disk
+                    yarch8.reset();
+                    rom_image = load_rom(&mut yarch8, &rom_file_path, args.patch.as_deref()).1;
+                    cheats.apply_patches(&mut yarch8);
+                    yarch8.start();
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    repeat: false,
+                    ..
+                } => {
+                    controls.paused = !controls.paused;
+                }
+                // Frame advance only makes sense while paused
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    ..
+                } if controls.paused => {
+                    controls.frames_to_advance += 1;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    repeat: false,
+                    ..
+                } => {
+                    controls.slow_motion = !controls.slow_motion;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    repeat: false,
+                    ..
+                } => {
+                    controls.fast_forward = true;
+                }
+                Event::KeyUp {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => {
+                    controls.fast_forward = false;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::PageUp),
+                    ..
+                } => {
+                    controls.cpu_freq += CPU_FREQ_STEP;
+                    yarch8.set_cpu_freq(controls.cpu_freq);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::PageDown),
+                    ..
+                } => {
+                    controls.cpu_freq = controls
+                        .cpu_freq
+                        .saturating_sub(CPU_FREQ_STEP)
+                        .max(CPU_FREQ_STEP);
+                    yarch8.set_cpu_freq(controls.cpu_freq);
+                }
                 Event::KeyDown {
                     keycode: Some(keycode),
                     ..
                 } => {
-                    if let Some(key_index) = get_keys_index(keycode) {
-                        yarch8.key_press(key_index);
+                    if let Some(&key) = keymap.get(&keycode) {
+                        queue_key(&mut yarch8, key, true);
                     }
                 }
                 Event::KeyUp {
                     keycode: Some(keycode),
                     ..
                 } => {
-                    if let Some(key_index) = get_keys_index(keycode) {
-                        yarch8.key_released(key_index);
+                    if let Some(&key) = keymap.get(&keycode) {
+                        queue_key(&mut yarch8, key, false);
                     }
                 }
                 _ => {}
             }
         }
-        // Fetch
-        let ins = yarch8.fetch();
-        println!("Fetched instruction: {:#04x}", ins);
 
-        // Decode
-        // Execute
-        yarch8.decode_execute(ins);
+        // External controllers decide when CPU runs
+        let mut halted = false;
+        if let Some(gdb) = gdb.as_mut() {
+            gdb.poll(&mut yarch8);
+            halted |= !gdb.is_running();
+        }
+        if let Some(rpc) = rpc.as_mut() {
+            rpc.poll(&mut yarch8, &mut cheats);
+            halted |= rpc.is_paused();
+        }
+        if halted {
+            if render_start.elapsed().as_nanos() > render_req_duration {
+                render(&mut renderer, &yarch8);
+                render_start = Instant::now();
+            }
+            ::std::thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+
+        if controls.paused {
+            // Run exactly one frame worth of cycles for each frame advance request
+            if controls.frames_to_advance > 0 {
+                match controls.timing {
+                    Timing::Flat => {
+                        for _ in 0..(controls.cpu_freq / fps).max(1) {
+                            run_cycle(&mut yarch8, profiler.as_mut(), coverage.as_mut());
+                        }
+                    }
+                    // Up to the next frame interrupt of the VIP
+                    Timing::CosmacVip => {
+                        let frame = yarch8.get_machine_cycles() / timing::FRAME_CYCLES;
+                        while yarch8.get_machine_cycles() / timing::FRAME_CYCLES == frame
+                            && yarch8.get_fault().is_none()
+                        {
+                            run_cycle(&mut yarch8, profiler.as_mut(), coverage.as_mut());
+                        }
+                    }
+                }
+                controls.frames_to_advance -= 1;
+                cheats.apply(&mut yarch8);
+            }
+            renderer.set_title(&controls.title(yarch8.is_waiting_for_key()));
+            render(&mut renderer, &yarch8);
+            ::std::thread::sleep(Duration::from_nanos(render_req_duration as u64));
+            continue;
+        }
+
+        run_cycle(&mut yarch8, profiler.as_mut(), coverage.as_mut());
+
+        if let Some(gdb) = gdb.as_mut() {
+            gdb.after_step(&yarch8);
+        }
 
         let render_now = render_start.elapsed().as_nanos();
         if render_now > render_req_duration {
-            renderer.render_screen(yarch8.get_disp_buff());
+            // Frozen values are put back once per frame
+            cheats.apply(&mut yarch8);
+            renderer.set_title(&controls.title(yarch8.is_waiting_for_key()));
+            render(&mut renderer, &yarch8);
+            sample_player.update(yarch8.get_sound());
             render_start = Instant::now();
         }
 
         // Debug
         //yarch8.stats_peek();
 
-        if yarch8.to_decrease_delay_timer() {
-            yarch8.decrease_delay_timer();
+        // Time management, fast forward runs uncapped
+        if !controls.fast_forward {
+            yarch8.stall(controls.slowdown());
+        }
+    }
+
+    if let (Some(profiler), Some(prefix)) = (&profiler, &args.profiler) {
+        write_profile(profiler, &yarch8, controls.cpu_freq, prefix);
+    }
+    if let (Some(coverage), Some(path)) = (&coverage, &args.coverage) {
+        write_coverage(coverage, &rom_image, yarch8.get_variant(), path);
+    }
+    // Only ROMs that changed their flags get a file
+    if *yarch8.get_rpl_flags() != saved_rpl_flags {
+        if let Err(e) = yarch8.save_rpl_flags(&rpl_path) {
+            println!("Could not save RPL flags to {}: {}", rpl_path, e);
+        }
+    }
+}
+
+const CPU_FREQ_STEP: u32 = 50;
+
+// Keys change before the next instruction, even a tap shorter than a frame gets seen
+fn queue_key(yarch8: &mut YARCH8, key: u8, pressed: bool) {
+    let cycle = yarch8.get_cycles();
+    let event = KeyEvent {
+        cycle,
+        key,
+        pressed,
+    };
+    if let Err(e) = yarch8.queue_key_event(event) {
+        println!("Key {:X} dropped: {}", key, e);
+    }
+}
+
+// CHIP-8X and MEGA-CHIP bring their own colours, everything else uses the palette
+fn render(renderer: &mut Renderer, yarch8: &YARCH8) {
+    if let Some(frame) = yarch8.get_frame() {
+        renderer.render_frame(frame, yarch8.get_resolution().0);
+        return;
+    }
+    let rgb = |(r, g, b): (u8, u8, u8)| Color::RGB(r, g, b);
+    match yarch8.get_variant() {
+        Variant::Chip8X => {
+            let background = variant::CHIP8X_BACKGROUNDS[yarch8.get_background_color() as usize];
+            renderer.render_colored(yarch8.get_disp_buff(), rgb(background), |x, y| {
+                rgb(variant::CHIP8X_FOREGROUNDS[yarch8.get_pixel_color(x, y) as usize])
+            });
         }
+        _ => renderer.render_screen(yarch8.get_disp_buff()),
+    }
+}
+
+// Fetch, decode and execute one instruction then update timers
+fn run_cycle(
+    yarch8: &mut YARCH8,
+    profiler: Option<&mut Profiler>,
+    coverage: Option<&mut Coverage>,
+) {
+    if yarch8.get_fault().is_some() {
+        return;
+    }
+    if let Some(coverage) = coverage {
+        coverage.record(yarch8);
+    }
+    match profiler {
+        Some(profiler) => profiler.step(yarch8),
+        None => yarch8.step(),
+    };
+    if let Some(fault) = yarch8.get_fault() {
+        println!("Machine stopped at {:#05X}: {}", yarch8.get_pc(), fault);
+    }
+}
+
+fn write_profile(profiler: &Profiler, yarch8: &YARCH8, cpu_freq: u32, prefix: &str) {
+    match profiler.write_report(yarch8, cpu_freq, prefix) {
+        Ok(()) => println!(
+            "Wrote profile of {} cycles to {}.txt and {}.folded",
+            profiler.get_cycles(),
+            prefix,
+            prefix
+        ),
+        Err(error) => println!("Could not write profile: {}", error),
+    }
+}
 
-        if yarch8.to_decrease_sound_timer() {
-            yarch8.decrease_sound_timer();
+// Coverage is reported against the ROM image as loaded, patch included
+fn write_coverage(coverage: &Coverage, rom: &[u8], variant: Variant, path: &str) {
+    match coverage.write_report(rom, variant, path) {
+        Ok(()) => println!(
+            "Wrote coverage report to {}\n{}",
+            path,
+            coverage.summary(rom)
+        ),
+        Err(error) => println!("Could not write coverage report: {}", error),
+    }
+}
+
+// Emulation state changed by hotkeys at runtime
+struct Controls {
+    rom_title: Option<String>,
+    cpu_freq: u32,
+    timing: Timing,
+    slow_factor: u32,
+    paused: bool,
+    fast_forward: bool,
+    slow_motion: bool,
+    frames_to_advance: u32,
+}
+
+impl Controls {
+    fn slowdown(&self) -> u32 {
+        if self.slow_motion {
+            self.slow_factor
+        } else {
+            1
         }
+    }
 
-        // Time management
-        yarch8.stall();
+    fn title(&self, waiting_for_key: bool) -> String {
+        let mut title = String::from("YARCH8 - ");
+        if let Some(rom_title) = &self.rom_title {
+            title.push_str(&format!("{} - ", rom_title));
+        }
+        match self.timing {
+            Timing::Flat => title.push_str(&format!("{} Hz", self.cpu_freq)),
+            timing => title.push_str(&format!("{} timing", timing.name())),
+        }
+        if self.paused {
+            title.push_str(" [Paused]");
+        } else if self.fast_forward {
+            title.push_str(" [Fast-forward]");
+        } else if self.slow_motion {
+            title.push_str(&format!(" [Slow x{}]", self.slow_factor));
+        }
+        if waiting_for_key {
+            title.push_str(" [Waiting for key]");
+        }
+        title
     }
 }
 
 /// Yet Another Chip-8 Emulator written in Rust
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[clap(subcommand_negates_reqs = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Path to ROM file
+    #[clap(short, long, required = true)]
+    rom_file_path: Option<String>,
+
+    /// IPS or BPS patch to apply to the ROM when loading it
+    #[clap(long)]
+    patch: Option<String>,
+
+    /// Config file with global and per-ROM settings
+    #[clap(long, default_value = "yarch8.toml")]
+    config: String,
+
+    /// Scale factor for initial window size [default: 20]
     #[clap(short, long)]
-    rom_file_path: String,
+    scale: Option<u32>,
 
-    /// Scale factor for display
-    #[clap(short, long, default_value_t = 20)]
-    scale: u32,
+    /// Only scale display by whole numbers when window is resized
+    #[clap(long)]
+    integer_scale: bool,
 
-    /// Refresh Rate
-    #[clap(short, long, default_value_t = 60)]
-    fps: u32,
+    /// Draw pixel grid overlay
+    #[clap(long)]
+    grid: bool,
 
-    /// CPU frequency
-    #[clap(short, long, default_value_t = 500)]
-    cpu_freq: u32,
+    /// Refresh Rate [default: 60]
+    #[clap(short, long)]
+    fps: Option<u32>,
+
+    /// CPU frequency [default: 500]
+    #[clap(short, long)]
+    cpu_freq: Option<u32>,
+
+    /// Timer frequency [default: 60]
+    #[clap(short, long)]
+    timer_freq: Option<u32>,
+
+    /// Instruction timing, cosmac_vip charges each instruction its cost on the original
+    /// interpreter and ignores CPU and timer frequency [default: flat]
+    #[clap(long, possible_values = ["flat", "cosmac_vip"])]
+    timing: Option<String>,
 
-    /// Timer frequency
-    #[clap(short, long, default_value_t = 60)]
-    timer_freq: u32,
-}
-
-fn get_keys_index(k: Keycode) -> Option<u8> {
-    return match k {
-        Keycode::Num1 => Some(0),
-        Keycode::Num2 => Some(1),
-        Keycode::Num3 => Some(2),
-        Keycode::Num4 => Some(0xC),
-
-        Keycode::Q => Some(4),
-        Keycode::W => Some(5),
-        Keycode::E => Some(6),
-        Keycode::R => Some(0xD),
-
-        Keycode::A => Some(7),
-        Keycode::S => Some(8),
-        Keycode::D => Some(9),
-        Keycode::F => Some(0xE),
-
-        Keycode::Z => Some(0xA),
-        Keycode::X => Some(0),
-        Keycode::C => Some(0xB),
-        Keycode::V => Some(0xF),
-        _ => None,
+    /// Instruction set variant, also sets the display size [default: chip8]
+    #[clap(long, possible_values = ["chip8", "chip8x", "chip8e", "hires", "chip10", "megachip"])]
+    variant: Option<String>,
+
+    /// Start halted and wait for a GDB client on this local TCP port
+    #[clap(long)]
+    gdb: Option<u16>,
+
+    /// Serve JSON-RPC control requests on tcp:<host>:<port> or unix:<socket path>
+    #[clap(long)]
+    rpc: Option<String>,
+
+    /// Emulate a COSMAC VIP running the original interpreter, needs both ROM images
+    #[clap(long, number_of_values = 2, value_names = &["MONITOR_ROM", "INTERPRETER"])]
+    vip: Option<Vec<String>>,
+
+    /// Profile execution, writes <PREFIX>.txt and <PREFIX>.folded at exit or on F10
+    #[clap(long, value_name = "PREFIX")]
+    profiler: Option<String>,
+
+    /// Cheat file to use [default: ROM path with extension .cht]
+    #[clap(long)]
+    cheats: Option<String>,
+
+    /// Track code coverage, writes a report to this path at exit or on F10 (HTML for .html)
+    #[clap(long, value_name = "PATH")]
+    coverage: Option<String>,
+
+    /// How many times slower slow motion runs
+    #[clap(long, default_value_t = 4)]
+    slow_factor: u32,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show what is known about a ROM and guess its platform without running it
+    Info {
+        /// Path to ROM file
+        rom_file_path: String,
+    },
+    /// Run the emulator side by side with a reference model and report the first difference
+    Difftest {
+        /// ROM to run, random programs are generated if left out
+        rom_file_path: Option<String>,
+
+        /// Number of random programs to try
+        #[clap(long, default_value_t = 100)]
+        random: u64,
+
+        /// Seed for random programs, CXNN and key presses
+        #[clap(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Instructions to run per program
+        #[clap(long, default_value_t = 100_000)]
+        cycles: u64,
+    },
+    /// Create a patch turning one ROM into another, IPS if the patch file ends in .ips, else BPS
+    CreatePatch {
+        /// ROM the patch applies to
+        original: String,
+
+        /// ROM the patch turns it into
+        modified: String,
+
+        /// Patch file to write
+        patch_file_path: String,
+    },
+}
+
+impl Args {
+    // Settings explicitly given on command line
+    fn profile(&self) -> Profile {
+        Profile {
+            scale: self.scale,
+            fps: self.fps,
+            cpu_freq: self.cpu_freq,
+            timer_freq: self.timer_freq,
+            timing: self.timing.as_deref().and_then(Timing::from_name),
+            variant: self.variant.as_deref().and_then(Variant::from_name),
+            ..Profile::default()
+        }
+    }
+}
+
+// Also returns the image that went into RAM, with the patch applied
+fn load_rom(yarch8: &mut YARCH8, rom_path: &str, patch_path: Option<&str>) -> (RomInfo, Vec<u8>) {
+    let mut rom = read_rom(rom_path);
+    if let Some(patch_path) = patch_path {
+        rom = fs::read(patch_path)
+            .map_err(|e| format!("Loading patch error: {}", e))
+            .and_then(|patch| patch::apply(&rom, &patch))
+            .unwrap_or_else(|e| panic!("Patching {} with {} failed: {}", rom_path, patch_path, e));
+    }
+    (yarch8.load_bytes(&rom), rom)
+}
+
+fn load_vip(monitor_path: &str, interpreter_path: &str) -> Vip {
+    let monitor = fs::read(monitor_path)
+        .unwrap_or_else(|e| panic!("Loading VIP monitor error in {}: {}", monitor_path, e));
+    let interpreter = fs::read(interpreter_path)
+        .unwrap_or_else(|e| panic!("Loading interpreter error in {}: {}", interpreter_path, e));
+    Vip::new(&monitor, &interpreter).unwrap_or_else(|e| panic!("{}", e))
+}
+
+fn read_rom(rom_path: &str) -> Vec<u8> {
+    loader::read_rom(rom_path)
+        .unwrap_or_else(|e| panic!("Loading ROM error in {}: {}", rom_path, e))
+}
+
+fn create_patch(original_path: &str, modified_path: &str, patch_path: &str) {
+    let original = read_rom(original_path);
+    let modified = read_rom(modified_path);
+    let patch = if patch_path.to_lowercase().ends_with(".ips") {
+        patch::create_ips(&original, &modified).unwrap_or_else(|e| panic!("{}", e))
+    } else {
+        patch::create(&original, &modified)
     };
+    fs::write(patch_path, &patch).expect("Writing patch error!");
+    println!("Wrote {} ({} bytes)", patch_path, patch.len());
+}
+
+fn print_info(rom_path: &str) {
+    let format = loader::detect(&fs::read(rom_path).expect("Loading ROM error!"));
+    let rom = read_rom(rom_path);
+    let rom_info = database::lookup(&rom);
+    println!("ROM: {}", rom_info.describe());
+    println!("Format: {}", format.name());
+    println!("SHA-1: {}", rom_info.sha1);
+    println!("Size: {} bytes", rom.len());
+    if rom_info.known {
+        println!("Database platform: {}", rom_info.platform.name());
+    } else {
+        println!("Not found in database");
+    }
+    print!("{}", analyzer::analyze(&rom).report());
+}
+
+fn run_difftest(rom_path: Option<&str>, random: u64, seed: u64, cycles: u64) {
+    let runs: Vec<(String, Vec<u8>, Quirks, u32)> = match rom_path {
+        Some(rom_path) => {
+            let rom = read_rom(rom_path);
+            let rom_info = database::lookup(&rom);
+            let cpu_freq = rom_info.tickrate * 60;
+            vec![(rom_path.to_string(), rom, rom_info.quirks, cpu_freq)]
+        }
+        None => (seed..seed + random)
+            .map(|seed| {
+                let rom = difftest::random_rom(seed, 256);
+                (
+                    format!("random #{}", seed),
+                    rom,
+                    difftest::random_quirks(seed),
+                    600,
+                )
+            })
+            .collect(),
+    };
+
+    // Panics are part of the comparison, keep their messages out of the report while the
+    // runs go, and put the previous hook back afterwards
+    let previous_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let mut failed = 0;
+    for (name, rom, quirks, cpu_freq) in runs.iter() {
+        match difftest::run(rom, *quirks, *cpu_freq, seed, cycles) {
+            Ok(agreement) => match agreement.fault {
+                Some(fault) => println!(
+                    "{}: agreed for {} instructions, both stopped: {}",
+                    name, agreement.cycles, fault
+                ),
+                None => println!("{}: agreed for {} instructions", name, agreement.cycles),
+            },
+            Err(divergence) => {
+                failed += 1;
+                println!("{}: {}", name, divergence);
+            }
+        }
+    }
+    std::panic::set_hook(previous_hook);
+    println!("{} of {} runs diverged", failed, runs.len());
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+const DEFAULT_KEYMAP: [(Keycode, u8); 16] = [
+    (Keycode::Num1, 0),
+    (Keycode::Num2, 1),
+    (Keycode::Num3, 2),
+    (Keycode::Num4, 0xC),
+    (Keycode::Q, 4),
+    (Keycode::W, 5),
+    (Keycode::E, 6),
+    (Keycode::R, 0xD),
+    (Keycode::A, 7),
+    (Keycode::S, 8),
+    (Keycode::D, 9),
+    (Keycode::F, 0xE),
+    (Keycode::Z, 0xA),
+    (Keycode::X, 0),
+    (Keycode::C, 0xB),
+    (Keycode::V, 0xF),
+];
+
+// Keymap overrides replace the default binding of that CHIP-8 key
+fn build_keymap(
+    overrides: Option<&HashMap<String, String>>,
+) -> Result<HashMap<Keycode, u8>, String> {
+    let mut keymap: HashMap<Keycode, u8> = DEFAULT_KEYMAP.into_iter().collect();
+    for (key, key_name) in overrides.into_iter().flatten() {
+        let key_index = config::parse_keymap_key(key)?;
+        let keycode = Keycode::from_name(key_name)
+            .ok_or_else(|| format!("Unknown key name {} in keymap", key_name))?;
+        keymap.retain(|_, &mut k| k != key_index);
+        keymap.insert(keycode, key_index);
+    }
+    Ok(keymap)
 }
@@ -0,0 +1,312 @@
+/* ROM patches in the IPS and BPS formats, so fixed or translated versions of a game can
+    be shared without sharing the ROM itself.
+
+    IPS: "PATCH", then records of 3 byte offset, 2 byte size and the bytes to write
+    (size 0 means a run: 2 byte count and the byte to repeat), ended by "EOF" and an
+    optional 3 byte length to truncate to. There are no checksums in IPS.
+
+    BPS: "BPS1", source, target and metadata sizes as variable length numbers, then
+    actions copying from the source, the patch or the output itself, ended by the CRC32
+    of the source, the target and the patch. All three are checked, so a patch is
+    refused when applied to a different ROM than it was made for.
+
+    apply() tells the formats apart by their header. create() writes BPS and create_ips()
+    IPS, both as runs of changed bytes, which is all ROMs this small need.
+*/
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const IPS_MAX_OFFSET: usize = 0xFFFFFF;
+const IPS_MAX_RECORD: usize = 0xFFFF;
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else {
+        Err("Not an IPS or BPS patch".to_string())
+    }
+}
+
+// BPS patch turning source into target
+pub fn create(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = BPS_MAGIC.to_vec();
+    write_number(&mut patch, source.len() as u64);
+    write_number(&mut patch, target.len() as u64);
+    write_number(&mut patch, 0); // no metadata
+
+    // Alternate between runs of unchanged bytes (SourceRead) and changed ones (TargetRead)
+    let mut offset = 0;
+    while offset < target.len() {
+        let same = |idx: usize| source.get(idx) == Some(&target[idx]);
+        let is_same = same(offset);
+        let mut end = offset;
+        while end < target.len() && same(end) == is_same {
+            end += 1;
+        }
+        let action = if is_same { 0 } else { 1 };
+        write_number(&mut patch, (((end - offset - 1) as u64) << 2) | action);
+        if !is_same {
+            patch.extend_from_slice(&target[offset..end]);
+        }
+        offset = end;
+    }
+
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let patch_crc = crc32(&patch);
+    patch.extend_from_slice(&patch_crc.to_le_bytes());
+    patch
+}
+
+// IPS patch turning source into target, fails for targets past IPS's 16 MB limit
+pub fn create_ips(source: &[u8], target: &[u8]) -> Result<Vec<u8>, String> {
+    if target.len() > IPS_MAX_OFFSET {
+        return Err("Target is too large for IPS".to_string());
+    }
+    let mut patch = IPS_MAGIC.to_vec();
+    let mut offset = 0;
+    while offset < target.len() {
+        if source.get(offset) == Some(&target[offset]) {
+            offset += 1;
+            continue;
+        }
+        let mut start = offset;
+        // A record at offset 0x454F46 would read as the end marker, start one byte earlier
+        if &(start as u32).to_be_bytes()[1..] == IPS_EOF {
+            start -= 1;
+        }
+        let mut end = offset;
+        while end < target.len()
+            && end - start < IPS_MAX_RECORD
+            && source.get(end) != Some(&target[end])
+        {
+            end += 1;
+        }
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&target[start..end]);
+        offset = end;
+    }
+    patch.extend_from_slice(IPS_EOF);
+    if target.len() < source.len() {
+        patch.extend_from_slice(&(target.len() as u32).to_be_bytes()[1..]);
+    }
+    Ok(patch)
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut output = rom.to_vec();
+    let mut reader = Reader::new(&patch[IPS_MAGIC.len()..]);
+    loop {
+        let offset = reader.bytes(3)?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let offset = u32::from_be_bytes([0, offset[0], offset[1], offset[2]]) as usize;
+        let size = u16::from_be_bytes(reader.array()?) as usize;
+        let data = if size == 0 {
+            let count = u16::from_be_bytes(reader.array()?) as usize;
+            vec![reader.byte()?; count]
+        } else {
+            reader.bytes(size)?.to_vec()
+        };
+        if output.len() < offset + data.len() {
+            output.resize(offset + data.len(), 0);
+        }
+        output[offset..offset + data.len()].copy_from_slice(&data);
+    }
+    // Truncation extension
+    if reader.remaining() == 3 {
+        let length = reader.bytes(3)?;
+        output.truncate(u32::from_be_bytes([0, length[0], length[1], length[2]]) as usize);
+    } else if reader.remaining() != 0 {
+        return Err("Unexpected data after end of IPS patch".to_string());
+    }
+    Ok(output)
+}
+
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < BPS_MAGIC.len() + 12 {
+        return Err("BPS patch is truncated".to_string());
+    }
+    let (body, footer) = patch.split_at(patch.len() - 12);
+    let checksum = |idx: usize| u32::from_le_bytes(footer[idx..idx + 4].try_into().unwrap());
+    if crc32(&patch[..patch.len() - 4]) != checksum(8) {
+        return Err("BPS patch is corrupt (checksum mismatch)".to_string());
+    }
+    if crc32(source) != checksum(0) {
+        return Err(format!(
+            "Patch is for a different ROM (expected CRC32 {:08X}, got {:08X})",
+            checksum(0),
+            crc32(source)
+        ));
+    }
+
+    let mut reader = Reader::new(&body[BPS_MAGIC.len()..]);
+    let source_size = reader.number()? as usize;
+    let target_size = reader.number()? as usize;
+    let metadata_size = reader.number()? as usize;
+    reader.bytes(metadata_size)?;
+    if source_size != source.len() {
+        return Err(format!(
+            "Patch is for a ROM of {} bytes, not {}",
+            source_size,
+            source.len()
+        ));
+    }
+
+    let mut target = Vec::new();
+    let mut source_offset: u64 = 0;
+    let mut target_offset: u64 = 0;
+    while reader.remaining() > 0 {
+        let data = reader.number()?;
+        let length = usize::try_from(data >> 2)
+            .ok()
+            .and_then(|length| length.checked_add(1))
+            .ok_or("BPS patch action is too long")?;
+        if target
+            .len()
+            .checked_add(length)
+            .is_none_or(|end| end > target_size)
+        {
+            return Err("BPS patch writes past the end of the target".to_string());
+        }
+        match data & 3 {
+            // SourceRead, copy from the same offset in the source
+            0 => {
+                let bytes = source_range(source, target.len(), length)?;
+                target.extend_from_slice(bytes);
+            }
+            // TargetRead, bytes come from the patch
+            1 => target.extend_from_slice(reader.bytes(length)?),
+            // SourceCopy, copy from anywhere in the source
+            2 => {
+                source_offset = relative(source_offset, reader.number()?)?;
+                let start = usize::try_from(source_offset)
+                    .map_err(|_| "BPS patch reads past the end of the source")?;
+                let bytes = source_range(source, start, length)?;
+                target.extend_from_slice(bytes);
+                // Fits, the bytes were just read from there
+                source_offset += length as u64;
+            }
+            // TargetCopy, copy from output already written, may overlap to repeat bytes
+            _ => {
+                target_offset = relative(target_offset, reader.number()?)?;
+                for _ in 0..length {
+                    let byte = usize::try_from(target_offset)
+                        .ok()
+                        .and_then(|offset| target.get(offset))
+                        .copied()
+                        .ok_or("BPS patch copies from output not written yet")?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err("BPS patch ends before the target is complete".to_string());
+    }
+    if crc32(&target) != checksum(4) {
+        return Err("Patched ROM does not match the checksum in the patch".to_string());
+    }
+    Ok(target)
+}
+
+fn source_range(source: &[u8], start: usize, length: usize) -> Result<&[u8], String> {
+    start
+        .checked_add(length)
+        .and_then(|end| source.get(start..end))
+        .ok_or_else(|| "BPS patch reads past the end of the source".to_string())
+}
+
+// Copy offsets are stored relative to the last one, lowest bit is the sign
+fn relative(offset: u64, data: u64) -> Result<u64, String> {
+    let delta = data >> 1;
+    if data & 1 == 0 {
+        offset.checked_add(delta)
+    } else {
+        offset.checked_sub(delta)
+    }
+    .ok_or_else(|| "BPS patch copy offset out of range".to_string())
+}
+
+// BPS numbers: 7 bits per byte, last byte has the top bit set
+fn write_number(patch: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let bits = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            patch.push(0x80 | bits);
+            return;
+        }
+        patch.push(bits);
+        value -= 1;
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
+
+// Bounds checked reading, every patch byte comes from an untrusted file
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, offset: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.offset
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if length > self.remaining() {
+            return Err("Patch is truncated".to_string());
+        }
+        let bytes = &self.data[self.offset..self.offset + length];
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn number(&mut self) -> Result<u64, String> {
+        let mut value: u64 = 0;
+        let mut shift: u64 = 1;
+        loop {
+            let byte = self.byte()?;
+            value = (byte as u64 & 0x7F)
+                .checked_mul(shift)
+                .and_then(|bits| value.checked_add(bits))
+                .ok_or("Patch number too large")?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or("Patch number too large")?;
+            value = value.checked_add(shift).ok_or("Patch number too large")?;
+        }
+    }
+}
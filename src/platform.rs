@@ -0,0 +1,90 @@
+use crate::cpu::Quirks;
+
+// CHIP-8 interpreters a ROM may have been written for.
+// Ids follow the platform ids of the community chip-8-database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Platform {
+    OriginalChip8, // COSMAC VIP interpreter
+    HybridVip,     // COSMAC VIP with machine code routines
+    ModernChip8,   // Octo and most modern interpreters in CHIP-8 mode
+    Chip48,        // HP-48 CHIP-48
+    SuperChip1,    // SUPER-CHIP 1.0
+    SuperChip,     // SUPER-CHIP 1.1
+    XoChip,        // Octo XO-CHIP
+}
+
+impl Platform {
+    pub fn from_id(id: &str) -> Option<Platform> {
+        match id {
+            "originalChip8" => Some(Platform::OriginalChip8),
+            "hybridVIP" => Some(Platform::HybridVip),
+            "modernChip8" => Some(Platform::ModernChip8),
+            "chip48" => Some(Platform::Chip48),
+            "superchip1" => Some(Platform::SuperChip1),
+            "superchip" => Some(Platform::SuperChip),
+            "xochip" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Platform::OriginalChip8 => "originalChip8",
+            Platform::HybridVip => "hybridVIP",
+            Platform::ModernChip8 => "modernChip8",
+            Platform::Chip48 => "chip48",
+            Platform::SuperChip1 => "superchip1",
+            Platform::SuperChip => "superchip",
+            Platform::XoChip => "xochip",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::OriginalChip8 => "CHIP-8 (COSMAC VIP)",
+            Platform::HybridVip => "CHIP-8 (COSMAC VIP, hybrid)",
+            Platform::ModernChip8 => "CHIP-8 (modern)",
+            Platform::Chip48 => "CHIP-48",
+            Platform::SuperChip1 => "SUPER-CHIP 1.0",
+            Platform::SuperChip => "SUPER-CHIP 1.1",
+            Platform::XoChip => "XO-CHIP",
+        }
+    }
+
+    // Instructions executed per 60 Hz frame
+    pub fn tickrate(&self) -> u32 {
+        match self {
+            Platform::OriginalChip8 | Platform::HybridVip => 15,
+            Platform::ModernChip8 => 12,
+            Platform::Chip48 | Platform::SuperChip1 | Platform::SuperChip => 30,
+            Platform::XoChip => 1000,
+        }
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        match self {
+            Platform::OriginalChip8 | Platform::HybridVip => Quirks {
+                shift_vy: true,
+                load_store_inc_i: true,
+                vf_reset: true,
+                wait_key_release: true,
+                ..Quirks::default()
+            },
+            Platform::ModernChip8 => Quirks {
+                shift_vy: true,
+                load_store_inc_i: true,
+                ..Quirks::default()
+            },
+            Platform::Chip48 | Platform::SuperChip1 | Platform::SuperChip => Quirks {
+                jump_vx: true,
+                ..Quirks::default()
+            },
+            Platform::XoChip => Quirks {
+                shift_vy: true,
+                load_store_inc_i: true,
+                wrap_sprites: true,
+                ..Quirks::default()
+            },
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use crate::cpu::YARCH8;
+use crate::instruction::Instruction;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+
+/* Execution profiler, counts where the cycles of a running ROM go.
+
+    Wraps YARCH8::step() and records per instruction: the address, the opcode pattern,
+    2NNN/00EE call graph edges and cycles spent waiting in FX0A for a key. Functions
+    are named after their entry address, the code that runs before any call is "main".
+
+    The profiler keeps its own call stack of function entries. Whenever it no longer
+    matches the machine (reset, load_state, a debugger moving SP) it is rebuilt from
+    the return addresses on the machine's stack.
+
+    Two reports can be written: a flat listing with disassembly, and folded stacks
+    ("main;sub_234;sub_2A0 1234") that flamegraph.pl and inferno take as input.
+*/
+
+const ROOT: u16 = 0x200;
+const HOT_SPOTS: usize = 20;
+
+pub struct Profiler {
+    cycles: u64,
+    per_address: Vec<u64>,
+    key_wait: Vec<u64>, // FX0A cycles without a key down, per address
+    per_pattern: HashMap<&'static str, u64>,
+    calls: HashMap<(u16, u16), u64>, // (caller entry, callee entry)
+    returns: u64,
+    stack: Vec<u16>, // entries of the active functions, outermost first
+    folded: HashMap<Vec<u16>, u64>, // cycles per call stack
+    folded_wait: HashMap<Vec<u16>, u64>, // key wait cycles per call stack
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            cycles: 0,
+            per_address: vec![0; 4096],
+            key_wait: vec![0; 4096],
+            per_pattern: HashMap::new(),
+            calls: HashMap::new(),
+            returns: 0,
+            stack: vec![ROOT],
+            folded: HashMap::new(),
+            folded_wait: HashMap::new(),
+        }
+    }
+
+    // Same as yarch8.step(), with the instruction recorded
+    pub fn step(&mut self, yarch8: &mut YARCH8) -> u16 {
+        let pc = yarch8.get_pc();
+        let ram = yarch8.get_ram();
+        let instruction = match ram.get(pc as usize..pc as usize + 2) {
+            Some(bytes) => {
+                let opcode = u16::from_be_bytes([bytes[0], bytes[1]]);
+                yarch8.get_variant().decode(opcode)
+            }
+            None => return yarch8.step(),
+        };
+        let sp = yarch8.get_sp();
+        let opcode = yarch8.step();
+        // Refused instructions did not run
+        if yarch8.get_fault().is_some() {
+            return opcode;
+        }
+
+        self.sync_stack(yarch8, sp);
+        self.cycles += 1;
+        self.per_address[pc as usize] += 1;
+        *self.per_pattern.entry(instruction.pattern()).or_insert(0) += 1;
+        let waiting = matches!(instruction, Instruction::WaitKey { .. }) && yarch8.get_pc() == pc;
+        if waiting {
+            self.key_wait[pc as usize] += 1;
+            count(&mut self.folded_wait, &self.stack);
+        } else {
+            count(&mut self.folded, &self.stack);
+        }
+
+        // The cycle of a call or return belongs to the caller, the stack changes after it
+        match instruction {
+            Instruction::Call { nnn } if yarch8.get_sp() == sp + 1 => {
+                let caller = *self.stack.last().unwrap();
+                *self.calls.entry((caller, nnn)).or_insert(0) += 1;
+                self.stack.push(nnn);
+            }
+            Instruction::Ret if sp > 0 && yarch8.get_sp() == sp - 1 => {
+                self.returns += 1;
+                self.stack.pop();
+            }
+            _ => {}
+        }
+        opcode
+    }
+
+    // Rebuild the function stack when something else changed the machine's stack
+    fn sync_stack(&mut self, yarch8: &YARCH8, sp: usize) {
+        if self.stack.len() == sp + 1 {
+            return;
+        }
+        let ram = yarch8.get_ram();
+        self.stack = vec![ROOT];
+        for &return_address in yarch8.get_stack()[..sp].iter() {
+            // The call sits right before the return address, its target is the entry
+            let call = return_address.wrapping_sub(2) as usize;
+            let entry = match ram.get(call..call + 2) {
+                Some(bytes) => match yarch8
+                    .get_variant()
+                    .decode(u16::from_be_bytes([bytes[0], bytes[1]]))
+                {
+                    Instruction::Call { nnn } => nnn,
+                    _ => return_address,
+                },
+                None => return_address,
+            };
+            self.stack.push(entry);
+        }
+    }
+
+    pub fn get_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Flat listing: totals, opcode patterns, call graph, hot spots and every executed address
+    pub fn flat_report(&self, yarch8: &YARCH8, cpu_freq: u32) -> String {
+        let mut report = String::new();
+        let total = self.cycles.max(1) as f64;
+        let seconds = |cycles: u64| cycles as f64 / cpu_freq.max(1) as f64;
+        let waited: u64 = self.key_wait.iter().sum();
+        let _ = writeln!(
+            report,
+            "{} cycles ({:.2}s at {} Hz), {:.2}s waiting for keys (FX0A)",
+            self.cycles,
+            seconds(self.cycles),
+            cpu_freq,
+            seconds(waited)
+        );
+
+        let _ = writeln!(report, "\nOpcodes");
+        let mut patterns: Vec<_> = self.per_pattern.iter().collect();
+        patterns.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (pattern, &cycles) in patterns {
+            let _ = writeln!(
+                report,
+                "  {}  {:>12}  {:6.2}%",
+                pattern,
+                cycles,
+                100.0 * cycles as f64 / total
+            );
+        }
+
+        let _ = writeln!(report, "\nCalls ({} returns)", self.returns);
+        let mut calls: Vec<_> = self.calls.iter().collect();
+        calls.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (&(caller, callee), &count) in calls {
+            let _ = writeln!(
+                report,
+                "  {:<10} -> {:<10} {:>12}",
+                function_name(caller),
+                function_name(callee),
+                count
+            );
+        }
+
+        let ram = yarch8.get_ram();
+        let line = |address: usize| {
+            let opcode = u16::from_be_bytes([ram[address], ram[(address + 1) % ram.len()]]);
+            let wait = match self.key_wait[address] {
+                0 => String::new(),
+                cycles => format!("  (waiting {:.2}s)", seconds(cycles)),
+            };
+            format!(
+                "  {:#05X}  {:>12}  {:6.2}%  {:04X}  {}{}",
+                address,
+                self.per_address[address],
+                100.0 * self.per_address[address] as f64 / total,
+                opcode,
+                yarch8.get_variant().decode(opcode),
+                wait
+            )
+        };
+
+        let _ = writeln!(report, "\nHot spots");
+        let addresses: Vec<usize> = (0..self.per_address.len())
+            .filter(|&address| self.per_address[address] > 0)
+            .collect();
+        let mut hottest = addresses.clone();
+        hottest.sort_by(|&a, &b| {
+            self.per_address[b]
+                .cmp(&self.per_address[a])
+                .then(a.cmp(&b))
+        });
+        for &address in hottest.iter().take(HOT_SPOTS) {
+            let _ = writeln!(report, "{}", line(address));
+        }
+
+        let _ = writeln!(report, "\nListing");
+        for &address in addresses.iter() {
+            let _ = writeln!(report, "{}", line(address));
+        }
+        report
+    }
+
+    // One line per call stack with the cycles spent in it, sorted for stable output
+    pub fn folded_stacks(&self) -> String {
+        let mut lines: Vec<String> = self
+            .folded
+            .iter()
+            .map(|(stack, cycles)| format!("{} {}", stack_name(stack), cycles))
+            .chain(
+                self.folded_wait
+                    .iter()
+                    .map(|(stack, cycles)| format!("{};key_wait {}", stack_name(stack), cycles)),
+            )
+            .collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    // Writes <prefix>.txt with the flat listing and <prefix>.folded with the stacks
+    pub fn write_report(&self, yarch8: &YARCH8, cpu_freq: u32, prefix: &str) -> io::Result<()> {
+        fs::write(
+            format!("{}.txt", prefix),
+            self.flat_report(yarch8, cpu_freq),
+        )?;
+        fs::write(format!("{}.folded", prefix), self.folded_stacks())
+    }
+}
+
+fn count(stacks: &mut HashMap<Vec<u16>, u64>, stack: &[u16]) {
+    match stacks.get_mut(stack) {
+        Some(cycles) => *cycles += 1,
+        None => {
+            stacks.insert(stack.to_vec(), 1);
+        }
+    }
+}
+
+fn function_name(entry: u16) -> String {
+    if entry == ROOT {
+        "main".to_string()
+    } else {
+        format!("sub_{:03X}", entry)
+    }
+}
+
+fn stack_name(stack: &[u16]) -> String {
+    let names: Vec<String> = stack.iter().map(|&entry| function_name(entry)).collect();
+    names.join(";")
+}
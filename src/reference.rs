@@ -0,0 +1,343 @@
+use crate::cpu::Quirks;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/* Deliberately simple CHIP-8 machine, written straight from the specification.
+
+    It shares nothing with the real core except the quirk settings, so the two can be
+    run side by side and compared (see difftest.rs). Speed does not matter here, every
+    instruction is decoded from scratch and every rule is spelled out.
+
+    Instead of panicking on programs that do something undefined (unknown opcodes,
+    stack over/underflow, memory access past 4K, key numbers above F) step() returns
+    a fault, which the core is expected to refuse as well.
+*/
+
+const RAM_SIZE: usize = 4096;
+const FONT_ADDRESS: usize = 0x50;
+const START_ADDRESS: usize = 0x200;
+
+#[rustfmt::skip]
+const FONT: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+pub struct Reference {
+    pub pc: usize,
+    pub i: usize, // 16 bit register, so it wraps past 0xFFFF
+    pub v: [u8; 16],
+    pub stack: Vec<usize>,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub ram: Vec<u8>,
+    pub display: Vec<Vec<bool>>,
+    pub keys: [bool; 16],
+    pub rpl_flags: [u8; 16],
+    quirks: Quirks,
+    rng: StdRng,
+    cycle_nanos: u64, // length of one instruction
+    timer_nanos: u64, // length of one timer tick
+    cycles: u64,
+    delay_set_at: u64, // cycle each timer was last set or decreased
+    sound_set_at: u64,
+    waiting: bool,           // in FX0A, for the key release quirk
+    wait_key: Option<usize>, // key that went down during the wait
+    keys_before: [bool; 16], // keys at the previous FX0A, to see which went down since
+}
+
+impl Reference {
+    pub fn new(rom: &[u8], quirks: Quirks, cpu_freq: u32, timer_freq: u32, seed: u64) -> Self {
+        let mut ram = vec![0; RAM_SIZE];
+        ram[FONT_ADDRESS..FONT_ADDRESS + FONT.len()].copy_from_slice(&FONT);
+        let end = (START_ADDRESS + rom.len()).min(RAM_SIZE);
+        ram[START_ADDRESS..end].copy_from_slice(&rom[..end - START_ADDRESS]);
+        Reference {
+            pc: START_ADDRESS,
+            i: 0,
+            v: [0; 16],
+            stack: Vec::new(),
+            delay_timer: 0,
+            sound_timer: 0,
+            ram,
+            display: vec![vec![false; 64]; 32],
+            keys: [false; 16],
+            rpl_flags: [0; 16],
+            quirks,
+            rng: StdRng::seed_from_u64(seed),
+            cycle_nanos: 1_000_000_000 / cpu_freq as u64,
+            timer_nanos: 1_000_000_000 / timer_freq as u64,
+            cycles: 0,
+            delay_set_at: 0,
+            sound_set_at: 0,
+            waiting: false,
+            wait_key: None,
+            keys_before: [false; 16],
+        }
+    }
+
+    pub fn opcode(&self) -> Option<u16> {
+        let bytes = self.ram.get(self.pc..self.pc + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    // Run one instruction, then let the timers catch up with the clock
+    pub fn step(&mut self) -> Result<(), String> {
+        let op = self.opcode().ok_or("PC past end of memory")?;
+        self.pc += 2;
+        self.cycles += 1;
+        self.execute(op)?;
+
+        if self.delay_timer > 0 && self.timer_due(self.delay_set_at) {
+            self.delay_timer -= 1;
+            self.delay_set_at = self.cycles;
+        }
+        if self.sound_timer > 0 && self.timer_due(self.sound_set_at) {
+            self.sound_timer -= 1;
+            self.sound_set_at = self.cycles;
+        }
+        Ok(())
+    }
+
+    fn timer_due(&self, set_at: u64) -> bool {
+        (self.cycles - set_at) * self.cycle_nanos > self.timer_nanos
+    }
+
+    fn read(&self, address: usize) -> Result<u8, String> {
+        self.ram
+            .get(address)
+            .copied()
+            .ok_or_else(|| format!("Read past end of memory at {:#X}", address))
+    }
+
+    fn write(&mut self, address: usize, value: u8) -> Result<(), String> {
+        match self.ram.get_mut(address) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            }
+            None => Err(format!("Write past end of memory at {:#X}", address)),
+        }
+    }
+
+    fn key(&self, value: u8) -> Result<bool, String> {
+        self.keys
+            .get(value as usize)
+            .copied()
+            .ok_or_else(|| format!("No key {:#X}", value))
+    }
+
+    fn execute(&mut self, op: u16) -> Result<(), String> {
+        let x = ((op >> 8) & 0xF) as usize;
+        let y = ((op >> 4) & 0xF) as usize;
+        let n = (op & 0xF) as usize;
+        let nn = (op & 0xFF) as u8;
+        let nnn = (op & 0xFFF) as usize;
+
+        match (op >> 12, x, y, n) {
+            (0x0, 0x0, 0xE, 0x0) => self.display.iter_mut().for_each(|row| row.fill(false)),
+            (0x0, 0x0, 0xE, 0xE) => self.pc = self.stack.pop().ok_or("Return with empty stack")?,
+            (0x0, 0x0, 0xF, 0xE) => self.display = vec![vec![false; 64]; 32],
+            (0x0, 0x0, 0xF, 0xF) => self.display = vec![vec![false; 128]; 64],
+            (0x1, _, _, _) => self.pc = nnn,
+            (0x2, _, _, _) => {
+                if self.stack.len() == 16 {
+                    return Err("Call with full stack".to_string());
+                }
+                self.stack.push(self.pc);
+                self.pc = nnn;
+            }
+            (0x3, _, _, _) => self.skip_if(self.v[x] == nn),
+            (0x4, _, _, _) => self.skip_if(self.v[x] != nn),
+            (0x5, _, _, _) => self.skip_if(self.v[x] == self.v[y]),
+            (0x6, _, _, _) => self.v[x] = nn,
+            (0x7, _, _, _) => self.v[x] = self.v[x].wrapping_add(nn),
+            (0x8, _, _, 0x0) => self.v[x] = self.v[y],
+            (0x8, _, _, 0x1) => self.logic(x, self.v[x] | self.v[y]),
+            (0x8, _, _, 0x2) => self.logic(x, self.v[x] & self.v[y]),
+            (0x8, _, _, 0x3) => self.logic(x, self.v[x] ^ self.v[y]),
+            (0x8, _, _, 0x4) => {
+                let sum = self.v[x] as u16 + self.v[y] as u16;
+                self.set_with_flag(x, sum as u8, (sum > 0xFF) as u8);
+            }
+            (0x8, _, _, 0x5) => {
+                let no_borrow = self.v[x] >= self.v[y];
+                self.set_with_flag(x, self.v[x].wrapping_sub(self.v[y]), no_borrow as u8);
+            }
+            (0x8, _, _, 0x6) => {
+                let value = if self.quirks.shift_vy {
+                    self.v[y]
+                } else {
+                    self.v[x]
+                };
+                self.set_with_flag(x, value / 2, value % 2);
+            }
+            (0x8, _, _, 0x7) => {
+                let no_borrow = self.v[y] >= self.v[x];
+                self.set_with_flag(x, self.v[y].wrapping_sub(self.v[x]), no_borrow as u8);
+            }
+            (0x8, _, _, 0xE) => {
+                let value = if self.quirks.shift_vy {
+                    self.v[y]
+                } else {
+                    self.v[x]
+                };
+                self.set_with_flag(x, value.wrapping_mul(2), value / 128);
+            }
+            (0x9, _, _, _) => self.skip_if(self.v[x] != self.v[y]),
+            (0xA, _, _, _) => self.i = nnn,
+            (0xB, _, _, _) => {
+                let offset = if self.quirks.jump_vx {
+                    self.v[x]
+                } else {
+                    self.v[0]
+                };
+                self.pc = nnn + offset as usize;
+            }
+            (0xC, _, _, _) => self.v[x] = self.rng.gen::<u8>() & nn,
+            (0xD, _, _, _) => self.draw(x, y, n)?,
+            (0xE, _, 0x9, 0xE) => {
+                let pressed = self.key(self.v[x])?;
+                self.skip_if(pressed);
+            }
+            (0xE, _, 0xA, 0x1) => {
+                let pressed = self.key(self.v[x])?;
+                self.skip_if(!pressed);
+            }
+            (0xF, _, 0x0, 0x7) => self.v[x] = self.delay_timer,
+            (0xF, _, 0x0, 0xA) if self.quirks.wait_key_release => self.wait_key_release(x),
+            (0xF, _, 0x0, 0xA) => match self.keys.iter().position(|&pressed| pressed) {
+                Some(key) => self.v[x] = key as u8,
+                None => self.pc -= 2,
+            },
+            (0xF, _, 0x1, 0x5) => {
+                self.delay_timer = self.v[x];
+                self.delay_set_at = self.cycles;
+            }
+            (0xF, _, 0x1, 0x8) => {
+                self.sound_timer = self.v[x];
+                self.sound_set_at = self.cycles;
+            }
+            (0xF, _, 0x1, 0xE) => self.i = (self.i + self.v[x] as usize) & 0xFFFF,
+            (0xF, _, 0x2, 0x9) => self.i = FONT_ADDRESS + (self.v[x] & 0xF) as usize * 5,
+            (0xF, _, 0x3, 0x3) => {
+                self.write(self.i, self.v[x] / 100)?;
+                self.write(self.i + 1, self.v[x] / 10 % 10)?;
+                self.write(self.i + 2, self.v[x] % 10)?;
+            }
+            (0xF, _, 0x5, 0x5) => {
+                for reg in 0..=x {
+                    self.write(self.i + reg, self.v[reg])?;
+                }
+                if self.quirks.load_store_inc_i {
+                    self.i = (self.i + x + 1) & 0xFFFF;
+                }
+            }
+            (0xF, _, 0x6, 0x5) => {
+                for reg in 0..=x {
+                    self.v[reg] = self.read(self.i + reg)?;
+                }
+                if self.quirks.load_store_inc_i {
+                    self.i = (self.i + x + 1) & 0xFFFF;
+                }
+            }
+            (0xF, _, 0x7, 0x5) => self.rpl_flags[..=x].copy_from_slice(&self.v[..=x]),
+            (0xF, _, 0x8, 0x5) => self.v[..=x].copy_from_slice(&self.rpl_flags[..=x]),
+            _ => return Err(format!("Unsupported opcode {:04X}", op)),
+        }
+        Ok(())
+    }
+
+    // Only a key going down during the wait counts, it is stored once up again
+    fn wait_key_release(&mut self, x: usize) {
+        if self.waiting && self.wait_key.is_none() {
+            self.wait_key = (0..16).find(|&key| self.keys[key] && !self.keys_before[key]);
+        }
+        match self.wait_key {
+            Some(key) if !self.keys[key] => {
+                self.v[x] = key as u8;
+                self.waiting = false;
+                self.wait_key = None;
+                return;
+            }
+            // Tone on while the key is down
+            Some(_) if self.sound_timer == 0 => {
+                self.sound_timer = 1;
+                self.sound_set_at = self.cycles;
+            }
+            _ => {}
+        }
+        self.waiting = true;
+        self.keys_before = self.keys;
+        self.pc -= 2;
+    }
+
+    fn skip_if(&mut self, condition: bool) {
+        if condition {
+            self.pc += 2;
+        }
+    }
+
+    // VF is written last, so the flag wins when X is F
+    fn set_with_flag(&mut self, x: usize, value: u8, flag: u8) {
+        self.v[x] = value;
+        self.v[0xF] = flag;
+    }
+
+    fn logic(&mut self, x: usize, value: u8) {
+        self.v[x] = value;
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
+    }
+
+    fn draw(&mut self, x: usize, y: usize, rows: usize) -> Result<(), String> {
+        let height = self.display.len();
+        let width = self.display[0].len();
+        let left = self.v[x] as usize % width;
+        let top = self.v[y] as usize % height;
+        self.v[0xF] = 0;
+
+        for row in 0..rows {
+            let mut screen_y = top + row;
+            if screen_y >= height {
+                if !self.quirks.wrap_sprites {
+                    break;
+                }
+                screen_y %= height;
+            }
+            let sprite = self.read(self.i + row)?;
+            for column in 0..8 {
+                let mut screen_x = left + column;
+                if screen_x >= width {
+                    if !self.quirks.wrap_sprites {
+                        break;
+                    }
+                    screen_x %= width;
+                }
+                if sprite & (0x80 >> column) != 0 {
+                    let pixel = &mut self.display[screen_y][screen_x];
+                    if *pixel {
+                        self.v[0xF] = 1;
+                    }
+                    *pixel = !*pixel;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,48 +1,160 @@
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 use sdl2::render::WindowCanvas;
+use sdl2::video::FullscreenType;
+
+// Initial window size for a display resolution, always 32 scaled pixels high so larger
+// screens do not open huge windows
+pub fn window_size(resolution: (usize, usize), scale: u32) -> (u32, u32) {
+    let (width, height) = resolution;
+    let window_h = 32 * scale;
+    (width as u32 * window_h / height as u32, window_h)
+}
 
 pub struct Renderer {
     canvas: WindowCanvas,
-    scale: u32,
+    integer_scale: bool,
+    show_grid: bool,
     bg_color: Color,
     fg_color: Color,
+    grid_color: Color,
 }
 
 impl Renderer {
-    pub fn new(canvas: WindowCanvas, scale: u32) -> Self {
+    pub fn new(canvas: WindowCanvas, integer_scale: bool, show_grid: bool) -> Self {
         Renderer {
             canvas,
-            scale,
+            integer_scale,
+            show_grid,
             bg_color: Color::RGB(0, 0, 0),
             fg_color: Color::RGB(255, 255, 255),
+            grid_color: Color::RGB(40, 40, 40),
         }
     }
 
+    pub fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let next_state = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        window.set_fullscreen(next_state).unwrap();
+    }
+
+    pub fn toggle_grid(&mut self) {
+        self.show_grid = !self.show_grid;
+    }
+
+    pub fn set_palette(&mut self, bg_color: Color, fg_color: Color) {
+        self.bg_color = bg_color;
+        self.fg_color = fg_color;
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.canvas.window_mut().set_title(title).unwrap();
+    }
+
     // Use for both render and clear screen
-    pub fn render_screen(&mut self, disp_buff: &[[bool; 64]; 32]) {
-        self.canvas.set_draw_color(self.bg_color);
+    pub fn render_screen(&mut self, disp_buff: &[Vec<bool>]) {
+        let fg_color = self.fg_color;
+        self.render_colored(disp_buff, self.bg_color, |_, _| fg_color);
+    }
+
+    // Pixels that are on get the colour fg_color(x, y) picks, CHIP-8X colours them by zone
+    pub fn render_colored<F>(&mut self, disp_buff: &[Vec<bool>], bg_color: Color, fg_color: F)
+    where
+        F: Fn(usize, usize) -> Color,
+    {
+        // Logical resolution comes from the buffer so it may change between frames
+        let width = disp_buff.first().map_or(0, |row| row.len());
+        self.render_pixels(width, disp_buff.len(), bg_color, |x, y| {
+            disp_buff[y][x].then(|| fg_color(x, y))
+        });
+    }
+
+    // True colour ARGB pixels in rows of width, MEGA-CHIP draws on black
+    pub fn render_frame(&mut self, frame: &[u32], width: usize) {
+        let height = frame.len() / width.max(1);
+        self.render_pixels(width, height, Color::RGB(0, 0, 0), |x, y| {
+            let [_, r, g, b] = frame[y * width + x].to_be_bytes();
+            ((r, g, b) != (0, 0, 0)).then(|| Color::RGB(r, g, b))
+        });
+    }
+
+    // Pixels left at None show the background
+    fn render_pixels<F>(&mut self, width: usize, height: usize, bg_color: Color, pixel: F)
+    where
+        F: Fn(usize, usize) -> Option<Color>,
+    {
+        // Letterbox area around the screen is always cleared to black
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
         self.canvas.clear();
 
-        // logic to display render from boolean matrix
-        self.canvas.set_draw_color(self.fg_color);
+        let (logical_w, logical_h) = (width as u32, height as u32);
+        if logical_w == 0 || logical_h == 0 {
+            self.canvas.present();
+            return;
+        }
+
+        // Fit the screen in the window while keeping the aspect ratio
+        let (window_w, window_h) = self.canvas.output_size().unwrap();
+        let mut scale =
+            (window_w as f32 / logical_w as f32).min(window_h as f32 / logical_h as f32);
+        if self.integer_scale {
+            scale = scale.floor().max(1.0);
+        }
+        let offset_x = (window_w as f32 - logical_w as f32 * scale) / 2.0;
+        let offset_y = (window_h as f32 - logical_h as f32 * scale) / 2.0;
+
+        // Pixel edges are rounded separately so fractional scales leave no gaps
+        let edge_x = |x: u32| (offset_x + x as f32 * scale).round() as i32;
+        let edge_y = |y: u32| (offset_y + y as f32 * scale).round() as i32;
+
+        self.canvas.set_draw_color(bg_color);
+        self.canvas
+            .fill_rect(Rect::new(
+                edge_x(0),
+                edge_y(0),
+                (edge_x(logical_w) - edge_x(0)) as u32,
+                (edge_y(logical_h) - edge_y(0)) as u32,
+            ))
+            .unwrap();
+
         // Render row by row...
-        for (y, row) in disp_buff.iter().enumerate() {
-            for (x, pixel) in row.iter().enumerate() {
-                // Draw a pixel if it is true
-                if *pixel {
+        for y in 0..height {
+            let (top, bottom) = (edge_y(y as u32), edge_y(y as u32 + 1));
+            for x in 0..width {
+                // Draw a pixel if it has a colour
+                if let Some(color) = pixel(x, y) {
+                    let (left, right) = (edge_x(x as u32), edge_x(x as u32 + 1));
+                    self.canvas.set_draw_color(color);
                     self.canvas
                         .fill_rect(Rect::new(
-                            (x * self.scale as usize) as i32,
-                            (y * self.scale as usize) as i32,
-                            self.scale,
-                            self.scale,
+                            left,
+                            top,
+                            (right - left) as u32,
+                            (bottom - top) as u32,
                         ))
                         .unwrap();
                 }
             }
         }
 
+        // Grid lines only make sense when a pixel is a few screen pixels wide
+        if self.show_grid && scale >= 3.0 {
+            self.canvas.set_draw_color(self.grid_color);
+            for x in 1..logical_w {
+                self.canvas
+                    .draw_line((edge_x(x), edge_y(0)), (edge_x(x), edge_y(logical_h)))
+                    .unwrap();
+            }
+            for y in 1..logical_h {
+                self.canvas
+                    .draw_line((edge_x(0), edge_y(y)), (edge_x(logical_w), edge_y(y)))
+                    .unwrap();
+            }
+        }
+
         self.canvas.present();
     }
 }
@@ -0,0 +1,509 @@
+use crate::cheat::{Cheats, Comparison, Search};
+use crate::cpu::{KeyEvent, Snapshot, YARCH8};
+use crate::{loader, patch};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/* JSON-RPC 2.0 control server, one request per line.
+
+    Listens on "tcp:127.0.0.1:<port>" or "unix:<socket path>". TCP is loopback only, the
+    methods read and write files (load_rom, save_state, load_state). Methods:
+        load_rom { path, patch? }          -> ROM info, machine is reset, IPS/BPS patch applied
+        step { cycles }                    -> number of cycles run, 1000000 at most
+        pause, resume                      -> stop or restart the main loop's own execution
+        press_key { key, cycle? }          -> key goes down now, or once that many instructions
+        release_key { key, cycle? }           ran (cycles in get_registers) when cycle is given
+        get_registers                      -> { v, i, pc, sp, stack, delay_timer, sound_timer,
+                                                waiting_for_key (stuck in FX0A), cycles,
+                                                fault (null unless the machine stopped) }
+        read_memory { address, length }    -> array of bytes
+        get_framebuffer                    -> { width, height, pixels: rows of 0/1 }
+        save_state { path? }               -> snapshot, also written to path if given
+        load_state { snapshot | path }
+        search_start                       -> number of candidates, every address to begin with
+        search { compare, value? }         -> { count, candidates: first 64 addresses },
+                                              compare is equal (needs value), changed,
+                                              unchanged, decreased or increased
+        freeze { address, value }, unfreeze { address }
+        get_cheats                         -> { frozen, patches } as [address, value] pairs
+*/
+
+const MAX_LISTED_CANDIDATES: usize = 64;
+const MAX_STEP_CYCLES: u64 = 1_000_000; // a request must not stall the main loop for long
+const COMPARE_ERROR: &str =
+    "compare must be equal (with a value), changed, unchanged, decreased or increased";
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Stream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    // Responses like save states can be bigger than the socket buffer, so block until sent
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.set_nonblocking(false)?;
+        self.write_all(bytes)?;
+        self.set_nonblocking(true)
+    }
+}
+
+struct Client {
+    stream: Stream,
+    buffer: Vec<u8>,
+}
+
+pub struct RpcServer {
+    listener: Listener,
+    clients: Vec<Client>,
+    paused: bool,
+    search: Option<Search>,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+fn invalid_params(message: &str) -> RpcError {
+    RpcError {
+        code: INVALID_PARAMS,
+        message: message.to_string(),
+    }
+}
+
+fn server_error(message: String) -> RpcError {
+    RpcError {
+        code: SERVER_ERROR,
+        message,
+    }
+}
+
+impl RpcServer {
+    pub fn listen(address: &str) -> io::Result<Self> {
+        let listener = if let Some(path) = address.strip_prefix("unix:") {
+            Self::listen_unix(path)?
+        } else {
+            let address = address.strip_prefix("tcp:").unwrap_or(address);
+            let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+            if addresses.iter().any(|address| !address.ip().is_loopback()) {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "JSON-RPC only listens on loopback addresses",
+                ));
+            }
+            let listener = TcpListener::bind(&addresses[..])?;
+            listener.set_nonblocking(true)?;
+            Listener::Tcp(listener)
+        };
+        Ok(RpcServer {
+            listener,
+            clients: Vec::new(),
+            paused: false,
+            search: None,
+        })
+    }
+
+    #[cfg(unix)]
+    fn listen_unix(path: &str) -> io::Result<Listener> {
+        // Left over socket file from an earlier run would make bind fail
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix(listener))
+    }
+
+    #[cfg(not(unix))]
+    fn listen_unix(_path: &str) -> io::Result<Listener> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "Unix sockets are not supported on this platform",
+        ))
+    }
+
+    // Port actually listened on, for when 0 let the system pick one
+    pub fn local_port(&self) -> io::Result<u16> {
+        match &self.listener {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?.port()),
+            #[cfg(unix)]
+            Listener::Unix(_) => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "Unix sockets have no port",
+            )),
+        }
+    }
+
+    // Whether a client asked the main loop to stop running the CPU
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Accept new clients and answer every complete request received so far
+    pub fn poll(&mut self, yarch8: &mut YARCH8, cheats: &mut Cheats) {
+        self.accept_clients();
+
+        let mut idx = 0;
+        while idx < self.clients.len() {
+            let mut chunk = [0u8; 4096];
+            let mut open = true;
+            loop {
+                match self.clients[idx].stream.read(&mut chunk) {
+                    Ok(0) => {
+                        open = false;
+                        break;
+                    }
+                    Ok(len) => self.clients[idx].buffer.extend_from_slice(&chunk[..len]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(_) => {
+                        open = false;
+                        break;
+                    }
+                }
+            }
+
+            while let Some(end) = self.clients[idx].buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.clients[idx].buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line).trim().to_string();
+                if line.is_empty() {
+                    continue;
+                }
+                if let Some(response) = self.handle_line(&line, yarch8, cheats) {
+                    let mut reply = response.to_string();
+                    reply.push('\n');
+                    if self.clients[idx].stream.send(reply.as_bytes()).is_err() {
+                        open = false;
+                        break;
+                    }
+                }
+            }
+
+            if open {
+                idx += 1;
+            } else {
+                self.clients.remove(idx);
+            }
+        }
+    }
+
+    fn accept_clients(&mut self) {
+        loop {
+            let accepted = match &self.listener {
+                Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+                #[cfg(unix)]
+                Listener::Unix(listener) => {
+                    listener.accept().map(|(stream, _)| Stream::Unix(stream))
+                }
+            }
+            .and_then(|stream| {
+                stream.set_nonblocking(true)?;
+                Ok(stream)
+            });
+            match accepted {
+                Ok(stream) => self.clients.push(Client {
+                    stream,
+                    buffer: Vec::new(),
+                }),
+                Err(_) => return,
+            }
+        }
+    }
+
+    // Notifications (no id) get no response
+    fn handle_line(
+        &mut self,
+        line: &str,
+        yarch8: &mut YARCH8,
+        cheats: &mut Cheats,
+    ) -> Option<Value> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return Some(error_response(Value::Null, PARSE_ERROR, &e.to_string())),
+        };
+        let id = request.get("id").cloned();
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            None => {
+                let id = id.unwrap_or(Value::Null);
+                return Some(error_response(id, INVALID_REQUEST, "Missing method"));
+            }
+        };
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+        let result = self.call(method, &params, yarch8, cheats);
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => error_response(id, e.code, &e.message),
+        })
+    }
+
+    fn call(
+        &mut self,
+        method: &str,
+        params: &Value,
+        yarch8: &mut YARCH8,
+        cheats: &mut Cheats,
+    ) -> Result<Value, RpcError> {
+        match method {
+            "load_rom" => {
+                let path = str_param(params, "path")?;
+                let mut rom = loader::read_rom(path)
+                    .map_err(|e| server_error(format!("Loading ROM {} failed: {}", path, e)))?;
+                if let Some(patch_path) = params.get("patch").and_then(Value::as_str) {
+                    rom = fs::read(patch_path)
+                        .map_err(|e| e.to_string())
+                        .and_then(|patch| patch::apply(&rom, &patch))
+                        .map_err(|e| server_error(format!("Patching ROM failed: {}", e)))?;
+                }
+                // The running game is only replaced once the new one could be read
+                yarch8.reset();
+                let rom_info = yarch8.load_bytes(&rom);
+                yarch8.set_quirks(rom_info.quirks);
+                yarch8.start();
+                Ok(json!({
+                    "sha1": rom_info.sha1,
+                    "title": rom_info.title,
+                    "authors": rom_info.authors,
+                    "platform": rom_info.platform.id(),
+                }))
+            }
+            "step" => {
+                let cycles = params.get("cycles").map_or(Some(1), Value::as_u64);
+                let cycles = cycles
+                    .filter(|&cycles| cycles <= MAX_STEP_CYCLES)
+                    .ok_or_else(|| invalid_params("cycles must be a number up to 1000000"))?;
+                for _ in 0..cycles {
+                    yarch8.step();
+                }
+                Ok(json!(cycles))
+            }
+            "pause" => {
+                self.paused = true;
+                Ok(Value::Null)
+            }
+            "resume" => {
+                self.paused = false;
+                Ok(Value::Null)
+            }
+            "press_key" | "release_key" => {
+                let key = key_param(params)?;
+                let pressed = method == "press_key";
+                match params.get("cycle") {
+                    None => {
+                        if pressed {
+                            yarch8.key_press(key)
+                        } else {
+                            yarch8.key_released(key)
+                        }
+                    }
+                    Some(cycle) => {
+                        let cycle = cycle
+                            .as_u64()
+                            .ok_or_else(|| invalid_params("cycle must be a number"))?;
+                        yarch8
+                            .queue_key_event(KeyEvent {
+                                cycle,
+                                key,
+                                pressed,
+                            })
+                            .map_err(|e| invalid_params(&e))?;
+                    }
+                }
+                Ok(Value::Null)
+            }
+            "get_registers" => {
+                let v: Vec<u8> = (0..16).map(|idx| yarch8.get_v_reg(idx)).collect();
+                Ok(json!({
+                    "v": v,
+                    "i": yarch8.get_i(),
+                    "pc": yarch8.get_pc(),
+                    "sp": yarch8.get_sp(),
+                    "stack": yarch8.get_stack(),
+                    "delay_timer": yarch8.get_delay_timer(),
+                    "sound_timer": yarch8.get_sound_timer(),
+                    "waiting_for_key": yarch8.is_waiting_for_key(),
+                    "cycles": yarch8.get_cycles(),
+                    "fault": yarch8.get_fault().map(|fault| fault.to_string()),
+                }))
+            }
+            "read_memory" => {
+                let address = usize_param(params, "address")?;
+                let length = usize_param(params, "length")?;
+                let ram = yarch8.get_ram();
+                if address
+                    .checked_add(length)
+                    .is_none_or(|end| end > ram.len())
+                {
+                    return Err(invalid_params("Memory range out of bounds"));
+                }
+                Ok(json!(ram[address..address + length]))
+            }
+            "get_framebuffer" => {
+                let (width, height) = yarch8.get_resolution();
+                let pixels: Vec<Vec<u8>> = yarch8
+                    .get_disp_buff()
+                    .iter()
+                    .map(|row| row.iter().map(|&pixel| pixel as u8).collect())
+                    .collect();
+                Ok(json!({ "width": width, "height": height, "pixels": pixels }))
+            }
+            "save_state" => {
+                let snapshot = serde_json::to_value(yarch8.save_state()).unwrap();
+                if let Some(path) = params.get("path").and_then(Value::as_str) {
+                    fs::write(path, snapshot.to_string())
+                        .map_err(|e| server_error(format!("Saving state failed: {}", e)))?;
+                }
+                Ok(snapshot)
+            }
+            "load_state" => {
+                let snapshot = match params.get("path").and_then(Value::as_str) {
+                    Some(path) => {
+                        let text = fs::read_to_string(path)
+                            .map_err(|e| server_error(format!("Loading state failed: {}", e)))?;
+                        serde_json::from_str::<Snapshot>(&text)
+                    }
+                    None => serde_json::from_value::<Snapshot>(
+                        params.get("snapshot").cloned().unwrap_or(Value::Null),
+                    ),
+                };
+                let snapshot = snapshot.map_err(|e| invalid_params(&e.to_string()))?;
+                yarch8
+                    .load_state(&snapshot)
+                    .map_err(|e| invalid_params(&e))?;
+                Ok(Value::Null)
+            }
+            "search_start" => {
+                let search = Search::new(yarch8);
+                let count = search.get_candidates().len();
+                self.search = Some(search);
+                Ok(json!(count))
+            }
+            "search" => {
+                let search = self
+                    .search
+                    .as_mut()
+                    .ok_or_else(|| server_error("No search started".to_string()))?;
+                let value = match params.get("value") {
+                    Some(value) => Some(byte_param(value, "value")?),
+                    None => None,
+                };
+                let comparison = Comparison::from_name(str_param(params, "compare")?, value)
+                    .ok_or_else(|| invalid_params(COMPARE_ERROR))?;
+                let count = search.filter(yarch8, comparison);
+                let candidates = &search.get_candidates()[..count.min(MAX_LISTED_CANDIDATES)];
+                Ok(json!({ "count": count, "candidates": candidates }))
+            }
+            "freeze" => {
+                let address = address_param(params)?;
+                let value = byte_param(params.get("value").unwrap_or(&Value::Null), "value")?;
+                cheats.freeze(address, value);
+                Ok(Value::Null)
+            }
+            "unfreeze" => Ok(json!(cheats.unfreeze(address_param(params)?))),
+            "get_cheats" => {
+                let frozen: Vec<(u16, u8)> = cheats
+                    .get_frozen()
+                    .iter()
+                    .map(|(&address, &value)| (address, value))
+                    .collect();
+                Ok(json!({ "frozen": frozen, "patches": cheats.get_patches() }))
+            }
+            _ => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("Unknown method {}", method),
+            }),
+        }
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn str_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid_params(&format!("{} must be a string", name)))
+}
+
+fn usize_param(params: &Value, name: &str) -> Result<usize, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_u64)
+        .map(|value| value as usize)
+        .ok_or_else(|| invalid_params(&format!("{} must be a number", name)))
+}
+
+fn address_param(params: &Value) -> Result<u16, RpcError> {
+    match params.get("address").and_then(Value::as_u64) {
+        Some(address) if address < 0x1000 => Ok(address as u16),
+        _ => Err(invalid_params("address must be a number from 0 to 0xFFF")),
+    }
+}
+
+fn byte_param(value: &Value, name: &str) -> Result<u8, RpcError> {
+    value
+        .as_u64()
+        .and_then(|value| u8::try_from(value).ok())
+        .ok_or_else(|| invalid_params(&format!("{} must be a number from 0 to 255", name)))
+}
+
+fn key_param(params: &Value) -> Result<u8, RpcError> {
+    match params.get("key").and_then(Value::as_u64) {
+        Some(key) if key < 16 => Ok(key as u8),
+        _ => Err(invalid_params("key must be a number from 0 to 15")),
+    }
+}
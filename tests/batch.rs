@@ -0,0 +1,32 @@
+use serde_json::json;
+use std::sync::Arc;
+use yarch8::batch::{run_batch, Job};
+use yarch8::cpu::{Snapshot, YARCH8};
+
+// V0 += 1, loop
+const ROM: [u8; 4] = [0x70, 0x01, 0x12, 0x00];
+
+fn job(start: Option<Snapshot>) -> Job {
+    Job {
+        rom: Arc::new(ROM.to_vec()),
+        seed: 1,
+        inputs: vec![0; 10],
+        start,
+    }
+}
+
+#[test]
+fn bad_start_state_only_fails_its_own_job() {
+    // A key that does not exist makes the snapshot invalid
+    let mut state = serde_json::to_value(YARCH8::new(60, 600).save_state()).unwrap();
+    state["key_events"] = json!([{"cycle": 0, "key": 16, "pressed": true}]);
+    let bad: Snapshot = serde_json::from_value(state).unwrap();
+
+    let outcomes = run_batch(&[job(None), job(Some(bad)), job(None)], 2);
+    assert_eq!(outcomes.len(), 3);
+    assert!(outcomes[1].is_err());
+    let first = outcomes[0].as_ref().unwrap();
+    let last = outcomes[2].as_ref().unwrap();
+    assert_eq!(first.frames, 10);
+    assert_eq!(first.state, last.state);
+}
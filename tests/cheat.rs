@@ -0,0 +1,68 @@
+use std::fs;
+use yarch8::cheat::{Cheats, Comparison, Search};
+use yarch8::cpu::YARCH8;
+
+#[test]
+fn parses_frozen_and_patched_codes() {
+    let cheats = Cheats::parse(
+        "# Infinite lives\n\
+        2F0:03      frozen\n\
+        \n\
+        0x3A2=12    patched once\n\
+        0X3A3 = ff\n",
+    )
+    .unwrap();
+    assert_eq!(
+        cheats.get_frozen().iter().collect::<Vec<_>>(),
+        [(&0x2F0, &0x03)]
+    );
+    assert_eq!(cheats.get_patches(), [(0x3A2, 0x12), (0x3A3, 0xFF)]);
+}
+
+#[test]
+fn empty_text_has_no_cheats() {
+    assert!(Cheats::parse("").unwrap().is_empty());
+    assert!(Cheats::parse("# nothing here\n   \n").unwrap().is_empty());
+}
+
+#[test]
+fn rejects_bad_codes() {
+    for text in ["2F0", "2F0:03=04", "1000:00", "2F0:100", "xyz:00", "2F0:"] {
+        assert!(Cheats::parse(text).is_err(), "{:?} parsed", text);
+    }
+    let error = Cheats::parse("200:00\n2F0:zz\n").unwrap_err();
+    assert!(error.starts_with("Line 2:"), "{}", error);
+}
+
+#[test]
+fn only_the_default_file_may_be_missing() {
+    let path = std::env::temp_dir().join("yarch8-missing-cheats.cht");
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+    assert!(Cheats::load_if_present(path).unwrap().is_empty());
+    assert!(Cheats::load(path).is_err());
+}
+
+#[test]
+fn patches_and_freezes_ram() {
+    let mut yarch8 = YARCH8::new(60, 600);
+    let cheats = Cheats::parse("300:05\n301=07\n").unwrap();
+    cheats.apply_patches(&mut yarch8);
+    cheats.apply(&mut yarch8);
+    assert_eq!(yarch8.get_ram()[0x300..0x302], [0x05, 0x07]);
+    yarch8.write_ram(0x300, 0x01);
+    yarch8.write_ram(0x301, 0x01);
+    cheats.apply(&mut yarch8);
+    assert_eq!(yarch8.get_ram()[0x300..0x302], [0x05, 0x01]);
+}
+
+#[test]
+fn search_narrows_down_to_changed_byte() {
+    let mut yarch8 = YARCH8::new(60, 600);
+    yarch8.write_ram(0x400, 3);
+    let mut search = Search::new(&yarch8);
+    yarch8.write_ram(0x400, 2);
+    search.filter(&yarch8, Comparison::Decreased);
+    assert_eq!(search.filter(&yarch8, Comparison::Equal(2)), 1);
+    assert_eq!(search.get_candidates(), [0x400]);
+}
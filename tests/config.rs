@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::PathBuf;
+use yarch8::config::Config;
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("yarch8-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn missing_file_means_defaults() {
+    let path = temp_path("missing.toml");
+    let config = Config::load(path.to_str().unwrap()).unwrap();
+    assert!(config.roms.is_empty());
+}
+
+#[test]
+fn unreadable_files_are_errors() {
+    let directory = temp_path("config-dir");
+    fs::create_dir_all(&directory).unwrap();
+    assert!(Config::load(directory.to_str().unwrap()).is_err());
+
+    let binary = temp_path("binary.toml");
+    fs::write(&binary, [0xFF, 0xFE, 0x00]).unwrap();
+    assert!(Config::load(binary.to_str().unwrap()).is_err());
+
+    let invalid = temp_path("invalid.toml");
+    fs::write(&invalid, "[defaults]\ncpu_freq = 0\n").unwrap();
+    let error = Config::load(invalid.to_str().unwrap()).unwrap_err();
+    assert!(error.contains("cpu_freq"), "{}", error);
+
+    fs::remove_dir(&directory).unwrap();
+    fs::remove_file(&binary).unwrap();
+    fs::remove_file(&invalid).unwrap();
+}
+
+#[test]
+fn rejects_bad_keymaps_and_colours() {
+    for text in [
+        "[defaults]\nkeymap = { \"10\" = \"Up\" }\n",
+        "[defaults]\nkeymap = { \"+5\" = \"Up\" }\n",
+        "[defaults]\nkeymap = { \"G\" = \"Up\" }\n",
+        "[defaults]\nkeymap = { \"5\" = \" \" }\n",
+        "[defaults]\npalette = { background = \"#00000\", foreground = \"#ffffff\" }\n",
+        "[roms.abc]\npalette = { background = \"#000000\", foreground = \"white\" }\n",
+    ] {
+        assert!(Config::parse(text).is_err(), "{}", text);
+    }
+}
+
+#[test]
+fn accepts_keymap_and_palette() {
+    let config = Config::parse(
+        "[defaults]\nkeymap = { \"5\" = \"Up\", \"a\" = \"Space\" }\n\
+        palette = { background = \"#102030\", foreground = \"#FFffFF\" }\n",
+    )
+    .unwrap();
+    let palette = config.defaults.palette.unwrap();
+    assert_eq!(palette.background_rgb(), Ok((0x10, 0x20, 0x30)));
+    assert_eq!(palette.foreground_rgb(), Ok((0xFF, 0xFF, 0xFF)));
+}
@@ -0,0 +1,26 @@
+use yarch8::coverage::{Coverage, FETCHED};
+use yarch8::cpu::variant::Variant;
+use yarch8::cpu::YARCH8;
+
+#[test]
+fn report_disassembles_with_the_variant() {
+    // CHIP-8E 5XY1 (skip if V1 > V2) in a loop
+    let rom = [0x51, 0x21, 0x12, 0x00];
+    let mut yarch8 = YARCH8::new(60, 600);
+    yarch8.set_variant(Variant::Chip8E);
+    yarch8.load_bytes(&rom);
+    yarch8.start();
+    let mut coverage = Coverage::new();
+    for _ in 0..10 {
+        coverage.record(&yarch8);
+        yarch8.step();
+    }
+    assert_ne!(coverage.get(0x200) & FETCHED, 0);
+    for report in [
+        coverage.text_report(&rom, Variant::Chip8E),
+        coverage.html_report(&rom, Variant::Chip8E),
+    ] {
+        assert!(report.contains("SGT V1, V2"), "{}", report);
+        assert!(!report.contains("DW"), "{}", report);
+    }
+}
@@ -0,0 +1,60 @@
+use yarch8::cpu::YARCH8;
+use yarch8::env::{Reward, ScoreFormat, ScoreReward};
+
+const SCORE: usize = 0x300;
+
+fn write(yarch8: &mut YARCH8, bytes: &[u8]) {
+    for (idx, &byte) in bytes.iter().enumerate() {
+        yarch8.write_ram(SCORE + idx, byte);
+    }
+}
+
+#[test]
+fn rewards_bcd_score_increase() {
+    let mut yarch8 = YARCH8::new(60, 600);
+    let mut reward = ScoreReward::new(SCORE, 3, ScoreFormat::Bcd).unwrap();
+    reward.reset(&yarch8);
+    write(&mut yarch8, &[1, 2, 3]);
+    assert_eq!(reward.reward(&yarch8), 123.0);
+    assert_eq!(reward.reward(&yarch8), 0.0);
+}
+
+#[test]
+fn rewards_big_endian_score_increase() {
+    let mut yarch8 = YARCH8::new(60, 600);
+    let mut reward = ScoreReward::new(SCORE, 2, ScoreFormat::BigEndian).unwrap();
+    reward.reset(&yarch8);
+    write(&mut yarch8, &[0x01, 0x02]);
+    assert_eq!(reward.reward(&yarch8), 258.0);
+}
+
+#[test]
+fn non_digit_bytes_give_no_reward() {
+    let mut yarch8 = YARCH8::new(60, 600);
+    let mut reward = ScoreReward::new(SCORE, 19, ScoreFormat::Bcd).unwrap();
+    reward.reset(&yarch8);
+    write(&mut yarch8, &[0xFF; 19]);
+    assert_eq!(reward.reward(&yarch8), 0.0);
+    // Nothing to compare against right after an unreadable score
+    write(&mut yarch8, &[0; 17]);
+    yarch8.write_ram(SCORE + 17, 1);
+    yarch8.write_ram(SCORE + 18, 5);
+    assert_eq!(reward.reward(&yarch8), 0.0);
+    yarch8.write_ram(SCORE + 17, 2);
+    yarch8.write_ram(SCORE + 18, 0);
+    assert_eq!(reward.reward(&yarch8), 5.0);
+    // Largest 19 digit score still fits
+    write(&mut yarch8, &[9; 19]);
+    assert!(reward.reward(&yarch8) > 0.0);
+}
+
+#[test]
+fn rejects_scores_outside_ram() {
+    assert!(ScoreReward::new(SCORE, 0, ScoreFormat::Bcd).is_err());
+    assert!(ScoreReward::new(SCORE, 20, ScoreFormat::Bcd).is_err());
+    assert!(ScoreReward::new(SCORE, 9, ScoreFormat::BigEndian).is_err());
+    assert!(ScoreReward::new(0xFFE, 3, ScoreFormat::Bcd).is_err());
+    assert!(ScoreReward::new(usize::MAX, 3, ScoreFormat::Bcd).is_err());
+    let reward = ScoreReward::new(SCORE, 3, ScoreFormat::Bcd).unwrap();
+    assert!(reward.with_lives(0x1000).is_err());
+}
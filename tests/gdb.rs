@@ -0,0 +1,173 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use yarch8::cpu::YARCH8;
+use yarch8::gdb::GdbStub;
+
+// V0 = 5, V1 = 7, V0 += V1, then loop forever
+const ROM: [u8; 8] = [0x60, 0x05, 0x61, 0x07, 0x80, 0x14, 0x12, 0x06];
+
+struct Session {
+    stub: GdbStub,
+    yarch8: YARCH8,
+    client: TcpStream,
+}
+
+impl Session {
+    fn new() -> Self {
+        let mut stub = GdbStub::listen(0).unwrap();
+        let mut yarch8 = YARCH8::new(60, 600);
+        yarch8.load_bytes(&ROM);
+        yarch8.start();
+        let client = TcpStream::connect(("127.0.0.1", stub.local_port().unwrap())).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(5)))
+            .unwrap();
+        stub.poll(&mut yarch8);
+        Session {
+            stub,
+            yarch8,
+            client,
+        }
+    }
+
+    // Raw bytes to the stub, then run like the main loop until a reply packet comes back
+    fn exchange(&mut self, raw: &[u8]) -> (Vec<u8>, String) {
+        self.client.write_all(raw).unwrap();
+        let mut received = Vec::new();
+        for _ in 0..400 {
+            self.stub.poll(&mut self.yarch8);
+            if self.stub.is_running() {
+                self.yarch8.step();
+                self.stub.after_step(&self.yarch8);
+            }
+            let mut chunk = [0u8; 1024];
+            if let Ok(len) = self.client.read(&mut chunk) {
+                received.extend_from_slice(&chunk[..len]);
+            }
+            if let Some(start) = received.iter().position(|&b| b == b'$') {
+                if let Some(end) = received[start..].iter().position(|&b| b == b'#') {
+                    let end = start + end;
+                    if received.len() >= end + 3 {
+                        let data = &received[start + 1..end];
+                        let checksum = format!("{:02x}", checksum_of(data));
+                        assert_eq!(&received[end + 1..end + 3], checksum.as_bytes());
+                        let acks = received[..start].to_vec();
+                        return (acks, String::from_utf8(data.to_vec()).unwrap());
+                    }
+                }
+            }
+        }
+        (received, String::new())
+    }
+
+    fn command(&mut self, data: &str) -> String {
+        let (acks, reply) = self.exchange(packet(data).as_bytes());
+        assert_eq!(acks, b"+", "no ack for {}", data);
+        reply
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn packet(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum_of(data.as_bytes()))
+}
+
+fn raw_packet(data: &[u8]) -> Vec<u8> {
+    let mut raw = vec![b'$'];
+    raw.extend_from_slice(data);
+    raw.extend_from_slice(format!("#{:02x}", checksum_of(data)).as_bytes());
+    raw
+}
+
+#[test]
+fn reads_registers() {
+    let mut session = Session::new();
+    assert_eq!(session.command("?"), "S05");
+    let registers = session.command("g");
+    // 16 V registers, I and PC of 2 bytes, SP, DT and ST
+    assert_eq!(registers.len(), (16 + 2 + 2 + 3) * 2);
+    assert_eq!(&registers[36..40], "0200");
+    assert_eq!(session.command("p11"), "0200");
+    assert_eq!(session.command("p15"), "E01");
+}
+
+#[test]
+fn writes_registers() {
+    let mut session = Session::new();
+    assert_eq!(session.command("P0=2a"), "OK");
+    assert_eq!(session.yarch8.get_v_reg(0), 0x2A);
+    assert_eq!(session.command("P10=0300"), "OK");
+    assert_eq!(session.yarch8.get_i(), 0x300);
+    assert_eq!(session.command("Pzz=00"), "E01");
+}
+
+#[test]
+fn i_register_agrees_with_fx1e() {
+    // I = FFF, V0 = 2, I += V0 leaves I past 4K
+    let mut yarch8 = YARCH8::new(60, 600);
+    yarch8.load_bytes(&[0xAF, 0xFF, 0x60, 0x02, 0xF0, 0x1E]);
+    yarch8.start();
+    for _ in 0..3 {
+        yarch8.step();
+    }
+    assert_eq!(yarch8.get_i(), 0x1001);
+
+    let mut session = Session::new();
+    assert_eq!(session.command("P10=1001"), "OK");
+    assert_eq!(session.yarch8.get_i(), 0x1001);
+    assert_eq!(session.command("p10"), "1001");
+}
+
+#[test]
+fn reads_and_writes_memory() {
+    let mut session = Session::new();
+    assert_eq!(session.command("m200,4"), "60056107");
+    assert_eq!(session.command("M300,3:aabbcc"), "OK");
+    assert_eq!(session.command("m300,3"), "aabbcc");
+    assert_eq!(session.command("mfff,2"), "E01");
+    assert_eq!(session.command("mffffffffffffffff,2"), "E01");
+    assert_eq!(session.command("Mffffffffffffffff,2:0000"), "E01");
+    assert_eq!(session.command("M300,2:aa"), "E01");
+}
+
+#[test]
+fn steps_and_stops_at_breakpoints() {
+    let mut session = Session::new();
+    assert_eq!(session.command("s"), "S05");
+    assert_eq!(session.yarch8.get_pc(), 0x202);
+    assert_eq!(session.command("Z0,206,2"), "OK");
+    assert_eq!(session.command("c"), "S05");
+    assert_eq!(session.yarch8.get_pc(), 0x206);
+    assert_eq!(session.yarch8.get_v_reg(0), 12);
+    assert_eq!(session.command("z0,206,2"), "OK");
+    // Hardware breakpoints are not supported
+    assert_eq!(session.command("Z1,206,2"), "");
+}
+
+#[test]
+fn rejects_bad_checksums() {
+    let mut session = Session::new();
+    let (acks, reply) = session.exchange(b"$g#00$?#3f");
+    assert_eq!(acks, b"-+");
+    assert_eq!(reply, "S05");
+}
+
+#[test]
+fn survives_malformed_packets() {
+    let mut session = Session::new();
+    let (acks, reply) = session.exchange(b"$\xff#ff");
+    assert_eq!((acks.as_slice(), reply.as_str()), (&b"+"[..], ""));
+    let (_, reply) = session.exchange(&raw_packet(b"G\xff\xff"));
+    assert_eq!(reply, "E01");
+    assert_eq!(
+        session.command("qXfer:features:read:target.xml:ffffffffffffffff,2"),
+        "E01"
+    );
+    // Garbage between packets is skipped
+    let (_, reply) = session.exchange(format!("junk{}", packet("?")).as_bytes());
+    assert_eq!(reply, "S05");
+}
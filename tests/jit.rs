@@ -0,0 +1,96 @@
+use yarch8::cpu::jit::Jit;
+use yarch8::cpu::timing::Timing;
+use yarch8::cpu::vip::{Vip, ROM_SIZE};
+use yarch8::cpu::{Quirks, YARCH8};
+use yarch8::difftest;
+
+fn machine(rom: &[u8], quirks: Quirks) -> YARCH8 {
+    let mut yarch8 = YARCH8::new(60, 600);
+    yarch8.seed(1);
+    yarch8.set_quirks(quirks);
+    yarch8.load_bytes(rom);
+    yarch8.start();
+    yarch8
+}
+
+// Same state as stepping the interpreter, whether run in one go or in odd chunks
+fn assert_matches_interpreter(rom: &[u8], quirks: Quirks, cycles: u32) -> YARCH8 {
+    let mut interpreted = machine(rom, quirks);
+    for _ in 0..cycles {
+        interpreted.step();
+    }
+
+    let mut compiled = machine(rom, quirks);
+    Jit::new().run(&mut compiled, cycles);
+    assert_eq!(compiled.save_state(), interpreted.save_state());
+
+    let mut chunked = machine(rom, quirks);
+    let mut jit = Jit::new();
+    let mut done = 0;
+    for chunk in [1, 7, 3, 64].iter().cycle() {
+        if done >= cycles {
+            break;
+        }
+        let chunk = (*chunk).min(cycles - done);
+        jit.run(&mut chunked, chunk);
+        done += chunk;
+    }
+    assert_eq!(chunked.save_state(), interpreted.save_state());
+    interpreted
+}
+
+#[test]
+fn random_roms_match_interpreter() {
+    for seed in 0..50 {
+        let rom = difftest::random_rom(seed, 256);
+        let quirks = difftest::random_quirks(seed);
+        let mut yarch8 = machine(&rom, quirks);
+        if let Err(divergence) = Jit::new().run_lockstep(&mut yarch8, 2000) {
+            panic!("seed {}: {}", seed, divergence);
+        }
+        assert_matches_interpreter(&rom, quirks, 2000);
+    }
+}
+
+#[test]
+fn self_modifying_code_matches_interpreter() {
+    // Counts V2 to 3, then FX55 turns the first instruction (V2 += 1) into V3 += 1
+    let rom = [
+        0x72, 0x01, 0x32, 0x03, 0x12, 0x00, 0xA2, 0x00, 0x60, 0x73, 0x61, 0x01, 0xF1, 0x55, 0x12,
+        0x00,
+    ];
+    let yarch8 = assert_matches_interpreter(&rom, Quirks::default(), 200);
+    assert_eq!(yarch8.get_v_reg(2), 3);
+    assert!(yarch8.get_v_reg(3) > 0);
+}
+
+#[test]
+fn faulting_rom_stops_like_interpreter() {
+    // V0 += 1, then jump to the last byte of RAM
+    let rom = [0x70, 0x01, 0x1F, 0xFF];
+    let yarch8 = assert_matches_interpreter(&rom, Quirks::default(), 10);
+    assert!(yarch8.get_fault().is_some());
+}
+
+#[test]
+fn vip_is_left_to_the_interpreter() {
+    // Monitor: LBR 8003 to leave the boot mapping, LBR 0000 into the interpreter
+    let mut monitor = vec![0; ROM_SIZE];
+    monitor[..6].copy_from_slice(&[0xC0, 0x80, 0x03, 0xC0, 0x00, 0x00]);
+    // Interpreter: BR 00, never gets to the CHIP-8 program
+    let interpreter = [0x30, 0x00];
+    for timing in [Timing::CosmacVip, Timing::Flat] {
+        let mut yarch8 = YARCH8::new(60, 600);
+        yarch8.load_bytes(&[0x70, 0x01, 0x12, 0x00]);
+        yarch8.set_vip(Some(Vip::new(&monitor, &interpreter).unwrap()));
+        yarch8.set_timing(timing);
+        yarch8.start();
+        let mut interpreted = yarch8.clone();
+        Jit::new().run_lockstep(&mut yarch8, 20).unwrap();
+        for _ in 0..20 {
+            interpreted.step();
+        }
+        assert_eq!(yarch8.save_state(), interpreted.save_state());
+        assert_eq!(yarch8.get_v_reg(0), 0);
+    }
+}
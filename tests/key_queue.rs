@@ -0,0 +1,120 @@
+use yarch8::cpu::jit::Jit;
+use yarch8::cpu::{KeyEvent, MAX_KEY_EVENTS, YARCH8};
+
+// V1 counts the instructions E09E finds key 0 down in, E09E runs on every third cycle
+const ROM: [u8; 8] = [0xE0, 0x9E, 0x12, 0x06, 0x71, 0x01, 0x12, 0x00];
+
+fn machine() -> YARCH8 {
+    let mut yarch8 = YARCH8::new(60, 600);
+    yarch8.seed(1);
+    yarch8.load_bytes(&ROM);
+    yarch8.start();
+    yarch8
+}
+
+fn event(cycle: u64, key: u8, pressed: bool) -> KeyEvent {
+    KeyEvent {
+        cycle,
+        key,
+        pressed,
+    }
+}
+
+fn run(yarch8: &mut YARCH8, cycles: usize) {
+    for _ in 0..cycles {
+        yarch8.step();
+    }
+}
+
+#[test]
+fn tap_holds_key_for_one_instruction() {
+    let mut yarch8 = machine();
+    yarch8.queue_key_event(event(9, 0, true)).unwrap();
+    yarch8.queue_key_event(event(9, 0, false)).unwrap();
+    run(&mut yarch8, 100);
+    assert_eq!(yarch8.get_v_reg(1), 1);
+    assert!(yarch8.get_key_events().is_empty());
+    assert!(!yarch8.get_keys()[0]);
+}
+
+#[test]
+fn held_key_is_seen_until_released() {
+    let mut yarch8 = machine();
+    yarch8.queue_key_event(event(9, 0, true)).unwrap();
+    yarch8.queue_key_event(event(30, 0, false)).unwrap();
+    run(&mut yarch8, 8);
+    assert!(!yarch8.get_keys()[0]);
+    assert_eq!(yarch8.cycles_until_key_event(), Some(1));
+    run(&mut yarch8, 100);
+    assert!(yarch8.get_v_reg(1) > 1);
+    assert!(!yarch8.get_keys()[0]);
+}
+
+#[test]
+fn late_events_keep_queue_in_order() {
+    let mut yarch8 = machine();
+    yarch8.queue_key_event(event(20, 0, true)).unwrap();
+    yarch8.queue_key_event(event(5, 0, false)).unwrap();
+    let cycles: Vec<u64> = yarch8
+        .get_key_events()
+        .iter()
+        .map(|event| event.cycle)
+        .collect();
+    assert_eq!(cycles, [20, 20]);
+}
+
+#[test]
+fn rejects_unknown_keys() {
+    let mut yarch8 = machine();
+    assert!(yarch8.queue_key_event(event(0, 0x10, true)).is_err());
+    assert!(yarch8.get_key_events().is_empty());
+}
+
+#[test]
+fn queue_is_capped() {
+    let mut yarch8 = machine();
+    for cycle in 0..MAX_KEY_EVENTS as u64 {
+        yarch8
+            .queue_key_event(event(cycle, 0, cycle % 2 == 0))
+            .unwrap();
+    }
+    assert!(yarch8.queue_key_event(event(1000, 0, true)).is_err());
+    assert_eq!(yarch8.get_key_events().len(), MAX_KEY_EVENTS);
+}
+
+#[test]
+fn faulted_machine_drops_events() {
+    // Jumps to the last byte of RAM, where no instruction fits
+    let mut yarch8 = YARCH8::new(60, 600);
+    yarch8.load_bytes(&[0x1F, 0xFF]);
+    yarch8.start();
+    yarch8.queue_key_event(event(50, 0, true)).unwrap();
+    run(&mut yarch8, 3);
+    assert!(yarch8.get_fault().is_some());
+    assert!(yarch8.get_key_events().is_empty());
+    yarch8.queue_key_event(event(60, 0, true)).unwrap();
+    assert!(yarch8.get_key_events().is_empty());
+}
+
+#[test]
+fn jit_applies_events_like_the_interpreter() {
+    let events = [
+        event(9, 0, true),
+        event(9, 0, false),
+        event(50, 0, true),
+        event(60, 0, false),
+    ];
+    let mut interpreted = machine();
+    let mut compiled = machine();
+    for event in events {
+        interpreted.queue_key_event(event).unwrap();
+        compiled.queue_key_event(event).unwrap();
+    }
+    run(&mut interpreted, 200);
+    // Odd chunks so events land inside blocks
+    let mut jit = Jit::new();
+    for cycles in [7, 3, 1, 100, 89] {
+        jit.run(&mut compiled, cycles);
+    }
+    assert_eq!(compiled.save_state(), interpreted.save_state());
+}
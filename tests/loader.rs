@@ -0,0 +1,129 @@
+use yarch8::loader::{self, Format};
+use yarch8::patch::crc32;
+
+const ROM: [u8; 6] = [0x00, 0xE0, 0xA2, 0x2A, 0x12, 0x04];
+
+// Zip archive of (name, data) files, deflated or stored as is
+fn zip(files: &[(&str, &[u8])], deflate: bool) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let mut directory = Vec::new();
+    for (name, data) in files {
+        let (method, stored) = if deflate {
+            (8u16, miniz_oxide::deflate::compress_to_vec(data, 6))
+        } else {
+            (0u16, data.to_vec())
+        };
+        let mut header = Vec::new();
+        header.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        header.extend_from_slice(&0u16.to_le_bytes()); // flags
+        header.extend_from_slice(&method.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes()); // time and date
+        header.extend_from_slice(&crc32(data).to_le_bytes());
+        header.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+        header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // extra length
+
+        directory.extend_from_slice(b"PK\x01\x02");
+        directory.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        directory.extend_from_slice(&header);
+        directory.extend_from_slice(&[0; 10]); // comment length, disk and attributes
+        directory.extend_from_slice(&(archive.len() as u32).to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+
+        archive.extend_from_slice(b"PK\x03\x04");
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(name.as_bytes());
+        archive.extend_from_slice(&stored);
+    }
+    let directory_offset = archive.len() as u32;
+    archive.extend_from_slice(&directory);
+    archive.extend_from_slice(b"PK\x05\x06");
+    archive.extend_from_slice(&[0; 4]); // disk numbers
+    archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    archive.extend_from_slice(&directory_offset.to_le_bytes());
+    archive.extend_from_slice(&0u16.to_le_bytes()); // comment length
+    archive
+}
+
+#[test]
+fn binary_is_loaded_as_is() {
+    assert_eq!(loader::detect(&ROM), Format::Binary);
+    assert_eq!(loader::decode(&ROM).unwrap(), ROM);
+}
+
+#[test]
+fn decodes_hex_text() {
+    for text in [
+        "00E0 A22A 1204",
+        "0x00, 0xE0, 0xA2, 0x2A, 0x12, 0x04\n",
+        "0200: 00E0 A22A\n0204: 1204\n",
+    ] {
+        assert_eq!(loader::detect(text.as_bytes()), Format::HexText, "{}", text);
+        assert_eq!(loader::decode(text.as_bytes()).unwrap(), ROM, "{}", text);
+    }
+}
+
+#[test]
+fn text_that_is_not_hex_stays_binary() {
+    for text in ["", "  \n", "00E0 A2A", "hello", "00E0 G22A"] {
+        assert_eq!(
+            loader::detect(text.as_bytes()),
+            Format::Binary,
+            "{:?}",
+            text
+        );
+    }
+}
+
+#[test]
+fn refuses_octo_cartridges() {
+    for magic in [&b"GIF87a"[..], b"GIF89a"] {
+        let mut gif = magic.to_vec();
+        gif.extend_from_slice(&[0x10, 0x00, 0x10, 0x00, 0xF0, 0x00, 0x00]);
+        assert_eq!(loader::detect(&gif), Format::OctoCartridge);
+        let error = loader::decode(&gif).unwrap_err();
+        assert!(error.contains("Octo cartridges"), "{}", error);
+    }
+}
+
+#[test]
+fn unzips_single_rom() {
+    for deflate in [false, true] {
+        let archive = zip(&[("game.bin", &ROM)], deflate);
+        assert_eq!(loader::detect(&archive), Format::Zip);
+        assert_eq!(loader::decode(&archive).unwrap(), ROM);
+    }
+}
+
+#[test]
+fn picks_rom_next_to_other_files() {
+    let archive = zip(&[("readme.txt", b"Have fun"), ("GAME.CH8", &ROM)], true);
+    assert_eq!(loader::decode(&archive).unwrap(), ROM);
+}
+
+#[test]
+fn zipped_hex_text_is_decoded() {
+    let archive = zip(&[("game.hex", b"00E0 A22A 1204")], false);
+    assert_eq!(loader::decode(&archive).unwrap(), ROM);
+}
+
+#[test]
+fn refuses_ambiguous_and_nested_archives() {
+    let archive = zip(&[("one.ch8", &ROM), ("two.ch8", &ROM)], false);
+    let error = loader::decode(&archive).unwrap_err();
+    assert!(error.contains("one.ch8, two.ch8"), "{}", error);
+
+    let nested = zip(&[("inner.zip", &zip(&[("game.ch8", &ROM)], false))], false);
+    assert!(loader::decode(&nested).is_err());
+}
+
+#[test]
+fn refuses_truncated_archives() {
+    let archive = zip(&[("game.ch8", &ROM)], true);
+    for length in [4, 30, archive.len() - 10] {
+        assert!(loader::decode(&archive[..length]).is_err(), "{}", length);
+    }
+}
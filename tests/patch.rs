@@ -0,0 +1,128 @@
+use yarch8::patch;
+
+fn source() -> Vec<u8> {
+    (0..600).map(|idx| (idx * 7 % 251) as u8).collect()
+}
+
+// Changed bytes, a longer tail and a changed first byte
+fn target() -> Vec<u8> {
+    let mut target = source();
+    target[0] = 0xFF;
+    target[100..110].fill(0xAA);
+    target.extend_from_slice(&[1, 2, 3, 4]);
+    target
+}
+
+#[test]
+fn bps_round_trip() {
+    let patch = patch::create(&source(), &target());
+    assert!(patch.starts_with(b"BPS1"));
+    assert_eq!(patch::apply(&source(), &patch).unwrap(), target());
+}
+
+#[test]
+fn ips_round_trip() {
+    let patch = patch::create_ips(&source(), &target()).unwrap();
+    assert!(patch.starts_with(b"PATCH"));
+    assert_eq!(patch::apply(&source(), &patch).unwrap(), target());
+}
+
+#[test]
+fn ips_truncates_shorter_targets() {
+    let shorter = &source()[..300];
+    let patch = patch::create_ips(&source(), shorter).unwrap();
+    assert_eq!(patch::apply(&source(), &patch).unwrap(), shorter);
+}
+
+#[test]
+fn ips_run_records_repeat_a_byte() {
+    // Offset 2, size 0, count 3, byte 0x11
+    let patch = b"PATCH\x00\x00\x02\x00\x00\x00\x03\x11EOF";
+    assert_eq!(
+        patch::apply(&[0; 4], patch).unwrap(),
+        [0, 0, 0x11, 0x11, 0x11]
+    );
+}
+
+#[test]
+fn bps_refuses_other_roms() {
+    let patch = patch::create(&source(), &target());
+    let mut other = source();
+    other[5] ^= 1;
+    let error = patch::apply(&other, &patch).unwrap_err();
+    assert!(error.contains("different ROM"), "{}", error);
+}
+
+#[test]
+fn bps_refuses_corrupt_patches() {
+    let mut patch = patch::create(&source(), &target());
+    let middle = patch.len() / 2;
+    patch[middle] ^= 1;
+    let error = patch::apply(&source(), &patch).unwrap_err();
+    assert!(error.contains("checksum mismatch"), "{}", error);
+}
+
+#[test]
+fn refuses_truncated_and_unknown_patches() {
+    let patch = patch::create_ips(&source(), &target()).unwrap();
+    assert!(patch::apply(&source(), &patch[..patch.len() - 5]).is_err());
+    assert!(patch::apply(&source(), b"BPS1").is_err());
+    assert!(patch::apply(&source(), b"NOT A PATCH").is_err());
+}
+
+#[test]
+fn crc32_matches_reference_value() {
+    assert_eq!(patch::crc32(b"123456789"), 0xCBF43926);
+}
+
+// BPS number encoding, 7 bits per byte with the top bit ending it
+fn number(patch: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let bits = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            patch.push(0x80 | bits);
+            return;
+        }
+        patch.push(bits);
+        value -= 1;
+    }
+}
+
+// BPS patch from raw actions, with the checksums it needs to get to them
+fn crafted_bps(source: &[u8], target_size: u64, actions: &[u64]) -> Vec<u8> {
+    let mut patch = b"BPS1".to_vec();
+    number(&mut patch, source.len() as u64);
+    number(&mut patch, target_size);
+    number(&mut patch, 0);
+    for &action in actions {
+        number(&mut patch, action);
+    }
+    patch.extend_from_slice(&patch::crc32(source).to_le_bytes());
+    patch.extend_from_slice(&0u32.to_le_bytes());
+    let patch_crc = patch::crc32(&patch);
+    patch.extend_from_slice(&patch_crc.to_le_bytes());
+    patch
+}
+
+#[test]
+fn bps_refuses_copies_out_of_range() {
+    let source = source();
+    let huge = u64::MAX >> 2;
+    let patches = [
+        // SourceCopy of 4 bytes from just before the end of the source
+        crafted_bps(&source, 4, &[(3 << 2) | 2, (598 << 1)]),
+        // SourceCopy from an offset far past the source
+        crafted_bps(&source, 4, &[(3 << 2) | 2, (u64::MAX >> 2) << 1]),
+        // TargetCopy before anything was written
+        crafted_bps(&source, 4, &[(3 << 2) | 3, 0]),
+        // TargetCopy from a negative offset
+        crafted_bps(&source, 4, &[(3 << 2) | 3, 1 | (5 << 1)]),
+        // Actions far longer than any target
+        crafted_bps(&source, u64::MAX, &[(huge << 2) | 2, 0]),
+        crafted_bps(&source, u64::MAX, &[1 << 2, huge << 2]),
+    ];
+    for patch in patches.iter() {
+        assert!(patch::apply(&source, patch).is_err());
+    }
+}
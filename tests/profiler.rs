@@ -0,0 +1,19 @@
+use yarch8::cpu::variant::Variant;
+use yarch8::cpu::YARCH8;
+use yarch8::profiler::Profiler;
+
+#[test]
+fn report_disassembles_with_the_variant() {
+    // CHIP-8E 5XY1 (skip if V1 > V2) in a loop
+    let mut yarch8 = YARCH8::new(60, 600);
+    yarch8.set_variant(Variant::Chip8E);
+    yarch8.load_bytes(&[0x51, 0x21, 0x12, 0x00]);
+    yarch8.start();
+    let mut profiler = Profiler::new();
+    for _ in 0..10 {
+        profiler.step(&mut yarch8);
+    }
+    let report = profiler.flat_report(&yarch8, 600);
+    assert!(report.contains("SGT V1, V2"), "{}", report);
+    assert!(!report.contains("DW"), "{}", report);
+}
@@ -0,0 +1,87 @@
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use yarch8::cheat::Cheats;
+use yarch8::cpu::YARCH8;
+use yarch8::rpc::RpcServer;
+
+// V0 += 1, loop
+const ROM: [u8; 4] = [0x70, 0x01, 0x12, 0x00];
+
+struct Session {
+    server: RpcServer,
+    yarch8: YARCH8,
+    cheats: Cheats,
+    client: BufReader<TcpStream>,
+}
+
+impl Session {
+    fn new() -> Self {
+        let mut server = RpcServer::listen("tcp:127.0.0.1:0").unwrap();
+        let mut yarch8 = YARCH8::new(60, 600);
+        yarch8.load_bytes(&ROM);
+        yarch8.start();
+        let client = TcpStream::connect(("127.0.0.1", server.local_port().unwrap())).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(5)))
+            .unwrap();
+        let mut cheats = Cheats::default();
+        server.poll(&mut yarch8, &mut cheats);
+        Session {
+            server,
+            yarch8,
+            cheats,
+            client: BufReader::new(client),
+        }
+    }
+
+    // Send a request and poll like the main loop until the reply line comes back
+    fn call(&mut self, method: &str, params: Value) -> Value {
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+        let mut line = request.to_string();
+        line.push('\n');
+        self.client.get_mut().write_all(line.as_bytes()).unwrap();
+        let mut reply = String::new();
+        for _ in 0..400 {
+            self.server.poll(&mut self.yarch8, &mut self.cheats);
+            if self.client.read_line(&mut reply).is_ok() && reply.ends_with('\n') {
+                return serde_json::from_str(&reply).unwrap();
+            }
+        }
+        panic!("No reply to {}", method);
+    }
+}
+
+#[test]
+fn steps_the_machine() {
+    let mut session = Session::new();
+    let reply = session.call("step", json!({"cycles": 5}));
+    assert_eq!(reply["result"], 5);
+    assert_eq!(session.yarch8.get_v_reg(0), 3);
+}
+
+#[test]
+fn refuses_too_many_cycles() {
+    let mut session = Session::new();
+    let reply = session.call("step", json!({"cycles": 1u64 << 40}));
+    assert!(reply["error"].is_object(), "{}", reply);
+    assert_eq!(session.yarch8.get_v_reg(0), 0);
+}
+
+#[test]
+fn failed_load_keeps_the_running_game() {
+    let mut session = Session::new();
+    session.call("step", json!({"cycles": 3}));
+    let missing = std::env::temp_dir().join("yarch8-missing-rom.ch8");
+    let reply = session.call("load_rom", json!({"path": missing.to_str().unwrap()}));
+    assert!(reply["error"].is_object(), "{}", reply);
+    assert_eq!(session.yarch8.get_v_reg(0), 2);
+    assert_eq!(session.yarch8.get_ram()[0x200..0x204], ROM);
+}
+
+#[test]
+fn only_listens_on_loopback() {
+    assert!(RpcServer::listen("tcp:0.0.0.0:0").is_err());
+    assert!(RpcServer::listen("127.0.0.1:0").is_ok());
+}